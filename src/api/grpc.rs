@@ -0,0 +1,471 @@
+use {
+    crate::oauth::{cookie_name, ext_authz, AuthRequest, AuthResponse},
+    futures::{Async, Future, Poll, Stream},
+    hyper::{body::Payload, service::service_fn, Body, Chunk, Request, Response, Server},
+    std::net::SocketAddr,
+    warp::http::{HeaderMap, HeaderValue},
+};
+
+const CHECK_PATHS: &[&str] = &[
+    "/envoy.service.auth.v3.Authorization/Check",
+    "/envoy.service.auth.v2.Authorization/Check",
+];
+
+/// gRPC status codes used in CheckResponse and the grpc-status trailer
+const OK: u64 = 0;
+const PERMISSION_DENIED: u64 = 7;
+const UNIMPLEMENTED: u64 = 12;
+const INTERNAL: u64 = 13;
+const UNAUTHENTICATED: u64 = 16;
+
+/// Envoy ext_authz gRPC service, answering the same as the HTTP variant
+pub fn server(addr: SocketAddr) -> impl Future<Item = (), Error = ()> {
+    info!("Serving ext_authz over gRPC on {}", addr);
+    Server::bind(&addr)
+        .http2_only(true)
+        .serve(|| service_fn(handle))
+        .map_err(|e| error!("ext_authz gRPC server failed -> {}", e))
+}
+
+fn handle(req: Request<Body>) -> impl Future<Item = Response<GrpcBody>, Error = hyper::Error> {
+    let known = CHECK_PATHS.contains(&req.uri().path());
+    req.into_body().concat2().and_then(move |body| {
+        super::run_blocking(move || {
+            if !known {
+                return GrpcBody::response(None, UNIMPLEMENTED);
+            }
+            let message = match unframe(&body).and_then(CheckRequest::decode) {
+                Some(check) => check.respond(),
+                None => return GrpcBody::response(None, INTERNAL),
+            };
+            GrpcBody::response(Some(frame(&message)), OK)
+        })
+    })
+}
+
+/// The attributes of the original request we need out of a CheckRequest
+#[derive(Debug, Default, PartialEq)]
+struct CheckRequest {
+    host: String,
+    path: String,
+    scheme: String,
+    headers: Vec<(String, String)>,
+    /// Address of the client that sent the original request
+    remote: Option<SocketAddr>,
+}
+
+impl CheckRequest {
+    /// CheckRequest.attributes.request.http
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let attributes = field(buf, 1)??;
+        let request = field(attributes, 4)??;
+        let http = field(request, 2)??;
+        let mut check = CheckRequest {
+            remote: match field(attributes, 1)? {
+                Some(source) => Self::peer_address(source)?,
+                None => None,
+            },
+            ..Default::default()
+        };
+        for (number, value) in fields(http)? {
+            match (number, value) {
+                (4, Value::Bytes(v)) => check.path = string(v)?,
+                (5, Value::Bytes(v)) => check.host = string(v)?,
+                (6, Value::Bytes(v)) => check.scheme = string(v)?,
+                // map<string, string> headers
+                (3, Value::Bytes(entry)) => check.headers.push(pair(entry)?),
+                // HeaderMap header_map, sent instead when Envoy encodes raw headers
+                (13, Value::Bytes(map)) => {
+                    for (n, v) in fields(map)? {
+                        if let (1, Value::Bytes(header)) = (n, v) {
+                            let (key, mut value) = pair(header)?;
+                            if value.is_empty() {
+                                value = string(field(header, 3)?.unwrap_or_default())?;
+                            }
+                            check.headers.push((key, value));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(check)
+    }
+
+    /// AttributeContext.Peer.address.socket_address, `None` for pipes and
+    /// addresses that aren't IPs
+    fn peer_address(peer: &[u8]) -> Option<Option<SocketAddr>> {
+        let socket = match field(peer, 1)? {
+            Some(address) => field(address, 1)?,
+            None => None,
+        };
+        let socket = match socket {
+            Some(socket) => socket,
+            None => return Some(None),
+        };
+        let mut address = None;
+        let mut port = 0;
+        for (number, value) in fields(socket)? {
+            match (number, value) {
+                (2, Value::Bytes(v)) => address = Some(string(v)?),
+                (3, Value::Varint(p)) => port = p as u16,
+                _ => {}
+            }
+        }
+        Some(
+            address
+                .and_then(|a| a.parse().ok())
+                .map(|ip| SocketAddr::new(ip, port)),
+        )
+    }
+
+    fn respond(self) -> Vec<u8> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in self.headers.iter().filter(|(k, _)| !k.starts_with(':')) {
+            if let (Ok(k), Ok(v)) = (
+                key.parse::<warp::http::header::HeaderName>(),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(k, v);
+            }
+        }
+        let cookie = headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|c| c.to_str().ok())
+            .flat_map(|c| c.split(';'))
+            .filter_map(|c| {
+                let mut parts = c.trim().splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name == cookie_name() => Some(value.to_string()),
+                    _ => None,
+                }
+            })
+            .next();
+        for (name, value) in &[("Host", &self.host), ("X-Forwarded-Proto", &self.scheme)] {
+            match HeaderValue::from_str(value) {
+                Ok(value) if !value.is_empty() => {
+                    headers.insert(*name, value);
+                }
+                _ => {}
+            }
+        }
+        let req = AuthRequest::checking(self.path, headers, cookie, self.remote);
+        match ext_authz(req) {
+            Ok(resp) => check_response(resp),
+            Err(e) => {
+                error!("ext_authz check failed -> {}", e);
+                check_response(AuthResponse {
+                    status: 500,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+/// CheckResponse with an OkHttpResponse carrying the identity headers, or a
+/// DeniedHttpResponse for Envoy to send to the client
+fn check_response(resp: AuthResponse) -> Vec<u8> {
+    let mut headers = resp.headers.clone();
+    if let Some(location) = &resp.location {
+        headers.push(("Location".into(), location.to_string()));
+    }
+    if let Some(auth) = &resp.www_authenticate {
+        headers.push(("WWW-Authenticate".into(), auth.to_string()));
+    }
+    let header_options: Vec<Vec<u8>> = headers
+        .iter()
+        .map(|(k, v)| {
+            let mut header = vec![];
+            put_bytes(&mut header, 1, k.as_bytes());
+            put_bytes(&mut header, 2, v.as_bytes());
+            let mut option = vec![];
+            put_bytes(&mut option, 1, &header);
+            option
+        })
+        .collect();
+
+    let code = match resp.status {
+        200 => OK,
+        401 => UNAUTHENTICATED,
+        _ => PERMISSION_DENIED,
+    };
+    let mut status = vec![];
+    put_varint_field(&mut status, 1, code);
+    let mut out = vec![];
+    put_bytes(&mut out, 1, &status);
+    if code == OK {
+        let mut ok = vec![];
+        for option in header_options.iter() {
+            put_bytes(&mut ok, 2, option);
+        }
+        put_bytes(&mut out, 3, &ok);
+    } else {
+        let mut http_status = vec![];
+        put_varint_field(&mut http_status, 1, u64::from(resp.status));
+        let mut denied = vec![];
+        put_bytes(&mut denied, 1, &http_status);
+        for option in header_options.iter() {
+            put_bytes(&mut denied, 2, option);
+        }
+        if let Some(body) = &resp.body {
+            put_bytes(&mut denied, 3, body.as_bytes());
+        }
+        put_bytes(&mut out, 2, &denied);
+    }
+    out
+}
+
+/// Body of a unary gRPC response, the message followed by the status trailer
+struct GrpcBody {
+    data: Option<Chunk>,
+    trailers: Option<HeaderMap>,
+}
+
+impl GrpcBody {
+    fn response(message: Option<Vec<u8>>, status: u64) -> Response<GrpcBody> {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(status));
+        let mut resp = Response::new(GrpcBody {
+            data: message.map(Chunk::from),
+            trailers: Some(trailers),
+        });
+        resp.headers_mut().insert(
+            "content-type",
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        resp
+    }
+}
+
+impl Payload for GrpcBody {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        Ok(Async::Ready(self.data.take()))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, hyper::Error> {
+        Ok(Async::Ready(self.trailers.take()))
+    }
+}
+
+/// Message of a gRPC frame, compressed messages aren't supported
+fn unframe(body: &[u8]) -> Option<&[u8]> {
+    if body.len() < 5 || body[0] != 0 {
+        return None;
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    body.get(5..5 + len)
+}
+
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut out = vec![0];
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+    out
+}
+
+/// Protobuf field values, fixed width values are skipped over
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Every field of a message in order, `None` if it is malformed
+fn fields(buf: &[u8]) -> Option<Vec<(u64, Value<'_>)>> {
+    let mut pos = 0;
+    let mut out = vec![];
+    while pos < buf.len() {
+        let key = varint(buf, &mut pos)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(buf, &mut pos)?),
+            1 | 5 => {
+                pos += if key & 7 == 1 { 8 } else { 4 };
+                Value::Fixed
+            }
+            2 => {
+                let len = varint(buf, &mut pos)? as usize;
+                let value = buf.get(pos..pos.checked_add(len)?)?;
+                pos += len;
+                Value::Bytes(value)
+            }
+            _ => return None,
+        };
+        out.push((key >> 3, value));
+    }
+    if pos > buf.len() {
+        return None;
+    }
+    Some(out)
+}
+
+/// The last occurrence of a length delimited field, as protobuf merges them
+fn field(buf: &[u8], number: u64) -> Option<Option<&[u8]>> {
+    Some(
+        fields(buf)?
+            .into_iter()
+            .filter_map(|(n, v)| match v {
+                Value::Bytes(v) if n == number => Some(v),
+                _ => None,
+            })
+            .next_back(),
+    )
+}
+
+fn string(buf: &[u8]) -> Option<String> {
+    String::from_utf8(buf.to_vec()).ok()
+}
+
+/// Fields 1 and 2 of a map entry or HeaderValue
+fn pair(buf: &[u8]) -> Option<(String, String)> {
+    let key = string(field(buf, 1)?.unwrap_or_default())?;
+    let value = string(field(buf, 2)?.unwrap_or_default())?;
+    Some((key, value))
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_varint_field(out: &mut Vec<u8>, number: u64, value: u64) {
+    put_varint(out, number << 3);
+    put_varint(out, value);
+}
+
+fn put_bytes(out: &mut Vec<u8>, number: u64, value: &[u8]) {
+    put_varint(out, number << 3 | 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_check_requests() {
+        let mut entry = vec![];
+        put_bytes(&mut entry, 1, b"authorization");
+        put_bytes(&mut entry, 2, b"Bearer abc");
+        let mut http = vec![];
+        put_varint_field(&mut http, 9, 42);
+        put_bytes(&mut http, 3, &entry);
+        put_bytes(&mut http, 4, b"/admin?x=1");
+        put_bytes(&mut http, 5, b"app.example.com");
+        put_bytes(&mut http, 6, b"https");
+        let mut request = vec![];
+        put_bytes(&mut request, 2, &http);
+        let mut socket = vec![];
+        put_bytes(&mut socket, 2, b"192.0.2.7");
+        put_varint_field(&mut socket, 3, 51234);
+        let mut address = vec![];
+        put_bytes(&mut address, 1, &socket);
+        let mut source = vec![];
+        put_bytes(&mut source, 1, &address);
+        let mut attributes = vec![];
+        put_bytes(&mut attributes, 1, &source);
+        put_bytes(&mut attributes, 4, &request);
+        let mut check = vec![];
+        put_bytes(&mut check, 1, &attributes);
+
+        let framed = frame(&check);
+        assert_eq!(
+            CheckRequest::decode(unframe(&framed).unwrap()),
+            Some(CheckRequest {
+                host: "app.example.com".into(),
+                path: "/admin?x=1".into(),
+                scheme: "https".into(),
+                headers: vec![("authorization".into(), "Bearer abc".into())],
+                remote: Some("192.0.2.7:51234".parse().unwrap()),
+            })
+        );
+        assert_eq!(CheckRequest::decode(&check[..check.len() - 1]), None);
+    }
+
+    /// CheckResponse.status.code and the fields of the http_response
+    fn decode_response(message: &[u8]) -> (u64, u64, Vec<(u64, Vec<u8>)>) {
+        let status = field(message, 1).unwrap().unwrap();
+        let code = match fields(status).unwrap().first() {
+            Some((1, Value::Varint(code))) => *code,
+            _ => OK,
+        };
+        let (number, http) = fields(message)
+            .unwrap()
+            .into_iter()
+            .find_map(|(n, v)| match v {
+                Value::Bytes(v) if n == 2 || n == 3 => Some((n, v)),
+                _ => None,
+            })
+            .unwrap();
+        let http = fields(http)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(n, v)| match v {
+                Value::Bytes(v) => Some((n, v.to_vec())),
+                _ => None,
+            })
+            .collect();
+        (code, number, http)
+    }
+
+    fn header(option: &[u8]) -> (String, String) {
+        pair(field(option, 1).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encodes_check_responses() {
+        let (code, kind, http) = decode_response(&check_response(AuthResponse {
+            status: 200,
+            headers: vec![("X-Auth-User".into(), "jdoe".into())],
+            ..Default::default()
+        }));
+        assert_eq!((code, kind), (OK, 3));
+        assert_eq!(http.len(), 1);
+        assert_eq!(http[0].0, 2);
+        assert_eq!(header(&http[0].1), ("X-Auth-User".into(), "jdoe".into()));
+
+        let (code, kind, http) = decode_response(&check_response(AuthResponse {
+            status: 302,
+            location: Some("https://auth.example.com/".into()),
+            body: Some("Login required".into()),
+            ..Default::default()
+        }));
+        assert_eq!((code, kind), (PERMISSION_DENIED, 2));
+        assert_eq!(http[0].0, 1);
+        let status = fields(&http[0].1).unwrap();
+        assert_eq!(status, vec![(1, Value::Varint(302))]);
+        assert_eq!(
+            header(&http[1].1),
+            ("Location".into(), "https://auth.example.com/".into())
+        );
+        assert_eq!(http[2], (3, b"Login required".to_vec()));
+
+        let (code, _, _) = decode_response(&check_response(AuthResponse {
+            status: 401,
+            ..Default::default()
+        }));
+        assert_eq!(code, UNAUTHENTICATED);
+    }
+}
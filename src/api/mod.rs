@@ -1,31 +1,93 @@
+mod grpc;
+
+pub use grpc::server as ext_authz_grpc;
+
 use {
-    crate::oauth::*,
-    std::collections::HashMap,
-    warp::{http::HeaderMap, Filter, Rejection},
+    crate::{
+        login::{AssertionResponse, RegistrationResponse},
+        oauth::*,
+        Error,
+    },
+    futures::{future, Async, Future},
+    std::{collections::HashMap, net::SocketAddr},
+    warp::{
+        body::BodyStream,
+        filters::path::{FullPath, Tail},
+        http::{HeaderMap, Method},
+        Buf, Filter, Rejection, Stream,
+    },
 };
 
-const COOKIE_NAME: &str = "SID";
+/// Answers a failed handler instead of taking the worker down with it, a
+/// request that can't be authenticated is the client's fault and anything
+/// else is ours
+fn respond(result: Result<AuthResponse, Error>) -> AuthResponse {
+    result.unwrap_or_else(|e| {
+        let status = match e {
+            Error::Authentication(_) => {
+                warn!("Request refused -> {}", e);
+                400
+            }
+            _ => {
+                error!("Request failed -> {}", e);
+                500
+            }
+        };
+        AuthResponse {
+            status,
+            ..Default::default()
+        }
+    })
+}
+
+/// Runs a handler that waits on another server, like the upstream provider
+/// or LDAP, the runtime hands its other work to a new worker thread meanwhile
+/// instead of stalling behind it
+fn blocking<F: FnOnce() -> AuthResponse>(
+    handler: F,
+) -> impl Future<Item = AuthResponse, Error = Rejection> {
+    run_blocking(handler)
+}
+
+/// `blocking` for services outside of warp
+fn run_blocking<T, E, F: FnOnce() -> T>(handler: F) -> impl Future<Item = T, Error = E> {
+    let mut handler = Some(handler);
+    future::poll_fn(move || {
+        match tokio_threadpool::blocking(|| handler.take().expect("handler ran twice")()) {
+            Ok(resp) => Ok(resp),
+            // Outside of the runtime's thread pool there's no worker to hold up
+            Err(_) => Ok(Async::Ready(handler.take().expect("handler ran twice")())),
+        }
+    })
+}
 
 pub fn get_authorize() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
     path!("authorize")
         .and(warp::path::end())
         .and(warp::header::headers_cloned())
         .and(warp::query::raw())
-        .and(warp::cookie::optional(COOKIE_NAME))
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
         .map(
-            |headers: HeaderMap, query: String, cookie: Option<String>| {
-                let req = AuthRequest::new(query, headers, None, cookie);
-                authorize(req).unwrap()
+            |headers: HeaderMap,
+             query: String,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(query, headers, None, cookie, remote);
+                respond(authorize(req))
             },
         )
         .or(path!("authorize")
             .and(warp::path::end())
             .and(warp::header::headers_cloned())
-            .and(warp::cookie::optional(COOKIE_NAME))
-            .map(|headers: HeaderMap, cookie: Option<String>| {
-                let req = AuthRequest::new(String::default(), headers, None, cookie);
-                authorize(req).unwrap()
-            }))
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |headers: HeaderMap, cookie: Option<String>, remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                    respond(authorize(req))
+                },
+            ))
         .unify()
 }
 
@@ -36,14 +98,16 @@ pub fn post_authorize() -> impl Filter<Extract = (AuthResponse,), Error = Reject
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::form())
         .and(warp::header::headers_cloned())
-        .and(warp::cookie::optional(COOKIE_NAME))
-        .map(
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .and_then(
             |query: String,
              form: HashMap<String, String>,
              headers: HeaderMap,
-             cookie: Option<String>| {
-                let req = AuthRequest::new(query, headers, Some(form), cookie);
-                authorize(req).unwrap()
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(query, headers, Some(form), cookie, remote);
+                blocking(move || respond(authorize(req)))
             },
         )
         .or(path!("authorize")
@@ -51,11 +115,16 @@ pub fn post_authorize() -> impl Filter<Extract = (AuthResponse,), Error = Reject
             .and(warp::body::content_length_limit(1024 * 32))
             .and(warp::body::form())
             .and(warp::header::headers_cloned())
-            .and(warp::cookie::optional(COOKIE_NAME))
-            .map(
-                |form: HashMap<String, String>, headers: HeaderMap, cookie: Option<String>| {
-                    let req = AuthRequest::new(String::default(), headers, Some(form), cookie);
-                    authorize(req).unwrap()
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .and_then(
+                |form: HashMap<String, String>,
+                 headers: HeaderMap,
+                 cookie: Option<String>,
+                 remote: Option<SocketAddr>| {
+                    let req =
+                        AuthRequest::new(String::default(), headers, Some(form), cookie, remote);
+                    blocking(move || respond(authorize(req)))
                 },
             ))
         .unify()
@@ -64,23 +133,45 @@ pub fn post_authorize() -> impl Filter<Extract = (AuthResponse,), Error = Reject
 pub fn get_authenticate() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
     path!("authenticate")
         .and(warp::path::end())
+        .and(warp::header::headers_cloned())
         .and(warp::query::raw())
-        .and(warp::cookie::optional(COOKIE_NAME))
-        .map(|query: String, cookie: Option<String>| {
-            let req = AuthRequest::new(query, HeaderMap::default(), None, cookie);
-            authenticate(req).unwrap()
-        })
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .and_then(
+            |headers: HeaderMap,
+             query: String,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(query, headers, None, cookie, remote);
+                blocking(move || respond(authenticate(req)))
+            },
+        )
+        .or(path!("authenticate")
+            .and(warp::path::end())
+            .and(warp::header::headers_cloned())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .and_then(
+                |headers: HeaderMap, cookie: Option<String>, remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                    blocking(move || respond(authenticate(req)))
+                },
+            ))
+        .unify()
 }
 
 pub fn get_userdetail() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
     path!("resource")
         .and(warp::path::end())
         .and(warp::header::headers_cloned())
-        .and(warp::cookie::optional(COOKIE_NAME))
-        .map(|headers: HeaderMap, cookie: Option<String>| {
-            let req = AuthRequest::new(String::default(), headers, None, cookie);
-            resource(req).unwrap()
-        })
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .map(
+            |headers: HeaderMap, cookie: Option<String>, remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                respond(resource(req))
+            },
+        )
 }
 
 pub fn post_token() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
@@ -89,11 +180,15 @@ pub fn post_token() -> impl Filter<Extract = (AuthResponse,), Error = Rejection>
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::form())
         .and(warp::header::headers_cloned())
-        .and(warp::cookie::optional(COOKIE_NAME))
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
         .map(
-            |form: HashMap<String, String>, headers: HeaderMap, cookie: Option<String>| {
-                let req = AuthRequest::new(String::default(), headers, Some(form), cookie);
-                token(req).unwrap()
+            |form: HashMap<String, String>,
+             headers: HeaderMap,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(String::default(), headers, Some(form), cookie, remote);
+                respond(token(req))
             },
         )
 }
@@ -104,11 +199,283 @@ pub fn post_refresh() -> impl Filter<Extract = (AuthResponse,), Error = Rejectio
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::form())
         .and(warp::header::headers_cloned())
-        .and(warp::cookie::optional(COOKIE_NAME))
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .map(
+            |form: HashMap<String, String>,
+             headers: HeaderMap,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(String::default(), headers, Some(form), cookie, remote);
+                respond(refresh(req))
+            },
+        )
+}
+
+pub fn get_logout() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("logout")
+        .and(warp::path::end())
+        .and(warp::header::headers_cloned())
+        .and(warp::query::raw())
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .map(
+            |headers: HeaderMap,
+             query: String,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(query, headers, None, cookie, remote);
+                respond(logout(req))
+            },
+        )
+        .or(path!("logout")
+            .and(warp::path::end())
+            .and(warp::header::headers_cloned())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |headers: HeaderMap, cookie: Option<String>, remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                    respond(logout(req))
+                },
+            ))
+        .unify()
+}
+
+pub fn post_logout() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("logout")
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::form())
+        .and(warp::header::headers_cloned())
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
         .map(
-            |form: HashMap<String, String>, headers: HeaderMap, cookie: Option<String>| {
-                let req = AuthRequest::new(String::default(), headers, Some(form), cookie);
-                refresh(req).unwrap()
+            |form: HashMap<String, String>,
+             headers: HeaderMap,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(String::default(), headers, Some(form), cookie, remote);
+                respond(logout(req))
+            },
+        )
+}
+
+pub fn get_jwks() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!(".well-known" / "jwks.json")
+        .and(warp::path::end())
+        .map(|| respond(jwks()))
+}
+
+pub fn get_upstream() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("upstream")
+        .and(warp::path::end())
+        .map(|| respond(upstream_provider()))
+        .or(path!("upstream" / "login")
+            .and(warp::path::end())
+            .and(warp::header::headers_cloned())
+            .and(warp::query::raw())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |headers: HeaderMap,
+                 query: String,
+                 cookie: Option<String>,
+                 remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(query, headers, None, cookie, remote);
+                    respond(upstream_login(req))
+                },
+            ))
+        .unify()
+        .or(path!("upstream" / "callback")
+            .and(warp::path::end())
+            .and(warp::header::headers_cloned())
+            .and(warp::query::raw())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .and_then(
+                |headers: HeaderMap,
+                 query: String,
+                 cookie: Option<String>,
+                 remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(query, headers, None, cookie, remote);
+                    blocking(move || respond(upstream_callback(req)))
+                },
+            ))
+        .unify()
+}
+
+pub fn post_totp() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("mfa" / "totp")
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::form())
+        .and(warp::header::headers_cloned())
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .map(
+            |form: HashMap<String, String>,
+             headers: HeaderMap,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(String::default(), headers, Some(form), cookie, remote);
+                respond(totp_confirm(req))
+            },
+        )
+        .or(path!("mfa" / "totp" / "enroll")
+            .and(warp::path::end())
+            .and(warp::body::content_length_limit(1024 * 32))
+            .and(warp::body::form())
+            .and(warp::header::headers_cloned())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |form: HashMap<String, String>,
+                 headers: HeaderMap,
+                 cookie: Option<String>,
+                 remote: Option<SocketAddr>| {
+                    let req =
+                        AuthRequest::new(String::default(), headers, Some(form), cookie, remote);
+                    respond(totp_enroll(req))
+                },
+            ))
+        .unify()
+}
+
+pub fn get_webauthn() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("webauthn")
+        .and(warp::path::end())
+        .map(|| respond(webauthn_provider()))
+        .or(path!("webauthn" / "register")
+            .and(warp::path::end())
+            .and(warp::header::headers_cloned())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |headers: HeaderMap, cookie: Option<String>, remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                    respond(webauthn_register_options(req))
+                },
+            ))
+        .unify()
+        .or(path!("webauthn" / "login")
+            .and(warp::path::end())
+            .and(warp::header::headers_cloned())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |headers: HeaderMap, cookie: Option<String>, remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                    respond(webauthn_login_options(req))
+                },
+            ))
+        .unify()
+}
+
+pub fn post_webauthn() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("webauthn" / "register")
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(warp::header::headers_cloned())
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .map(
+            |credential: RegistrationResponse,
+             headers: HeaderMap,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                respond(webauthn_register(req, credential))
+            },
+        )
+        .or(path!("webauthn" / "login")
+            .and(warp::path::end())
+            .and(warp::body::content_length_limit(1024 * 32))
+            .and(warp::body::json())
+            .and(warp::header::headers_cloned())
+            .and(warp::cookie::optional(cookie_name()))
+            .and(warp::addr::remote())
+            .map(
+                |assertion: AssertionResponse,
+                 headers: HeaderMap,
+                 cookie: Option<String>,
+                 remote: Option<SocketAddr>| {
+                    let req = AuthRequest::new(String::default(), headers, None, cookie, remote);
+                    respond(webauthn_login(req, assertion))
+                },
+            ))
+        .unify()
+}
+
+/// Every request to a pass-through domain, except the /authenticate callback
+/// that logs the user in on that domain
+pub fn passthrough() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::path::full())
+        .and_then(
+            |headers: HeaderMap, path: FullPath| match passthrough_upstream(&headers) {
+                Some(upstream) if path.as_str() != "/authenticate" => Ok(upstream),
+                _ => Err(warp::reject::not_found()),
+            },
+        )
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(
+            warp::query::raw()
+                .or(warp::any().map(String::default))
+                .unify(),
+        )
+        .and(warp::header::headers_cloned())
+        .and(warp::body::stream())
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .and_then(
+            |(domain, upstream): (String, String),
+             method: Method,
+             path: FullPath,
+             query: String,
+             headers: HeaderMap,
+             body: BodyStream,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let path = match query.as_str() {
+                    "" => path.as_str().to_string(),
+                    query => format!("{}?{}", path.as_str(), query),
+                };
+                let req =
+                    AuthRequest::new(String::default(), headers.clone(), None, cookie, remote);
+                let body = hyper::Body::wrap_stream(body.map(|chunk| chunk.collect::<Vec<u8>>()));
+                crate::oauth::passthrough(req, &domain, &upstream, method, &path, headers, body)
+            },
+        )
+}
+
+/// Envoy ext_authz HTTP service for any method, Envoy is configured with
+/// `path_prefix: /ext_authz` so the original path follows it
+pub fn ext_authz() -> impl Filter<Extract = (AuthResponse,), Error = Rejection> + Clone {
+    path!("ext_authz")
+        .and(warp::path::tail())
+        .and(
+            warp::query::raw()
+                .or(warp::any().map(String::default))
+                .unify(),
+        )
+        .and(warp::header::headers_cloned())
+        .and(warp::cookie::optional(cookie_name()))
+        .and(warp::addr::remote())
+        .and_then(
+            |tail: Tail,
+             query: String,
+             headers: HeaderMap,
+             cookie: Option<String>,
+             remote: Option<SocketAddr>| {
+                let uri = match query.as_str() {
+                    "" => format!("/{}", tail.as_str()),
+                    query => format!("/{}?{}", tail.as_str(), query),
+                };
+                let req = AuthRequest::checking(uri, headers, cookie, remote);
+                blocking(move || respond(crate::oauth::ext_authz(req)))
             },
         )
 }
@@ -1,10 +1,28 @@
 use clap::AppSettings::*;
 use log::Level;
-use std::error::Error;
+use std::{error::Error, net::SocketAddr, path::PathBuf};
 use structopt::StructOpt;
 
 lazy_static! {
-    pub static ref CONFIG: Config = { Config::from_args() };
+    pub static ref CONFIG: Config = {
+        // Test binaries are handed the test harness arguments, use the defaults instead and
+        // keep whatever the tests store away from the real database. Passkeys are enabled so
+        // they count as a second factor, and a single anonymous session per address makes
+        // the limit easy to reach
+        if cfg!(test) {
+            Config::from_iter(&[
+                "oauth-lite",
+                "--storage-backend",
+                "memory",
+                "--webauthn-rp-id",
+                "localhost",
+                "--session-max-per-ip",
+                "1",
+            ])
+        } else {
+            Config::from_args()
+        }
+    };
 }
 
 #[derive(Default, StructOpt, Debug, Clone)]
@@ -20,9 +38,27 @@ pub struct Config {
     #[structopt(flatten)]
     pub general: GeneralOptions,
     #[structopt(flatten)]
+    pub storage: StorageOptions,
+    #[structopt(flatten)]
+    pub keys: KeyOptions,
+    #[structopt(flatten)]
     pub oauth: OauthOptions,
     #[structopt(flatten)]
     pub login: LdapAuthenticator,
+    #[structopt(flatten)]
+    pub upstream: UpstreamOptions,
+    #[structopt(flatten)]
+    pub mfa: MfaOptions,
+    #[structopt(flatten)]
+    pub webauthn: WebauthnOptions,
+    #[structopt(flatten)]
+    pub throttle: ThrottleOptions,
+    #[structopt(flatten)]
+    pub session: SessionOptions,
+    #[structopt(flatten)]
+    pub cookie: CookieOptions,
+    #[structopt(flatten)]
+    pub forward_auth: ForwardAuthOptions,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -31,18 +67,245 @@ pub struct GeneralOptions {
     /// Logging level to use
     #[structopt(short, long, default_value = "Info")]
     pub log: Level,
+
+    /// Trust X-Forwarded-For and X-Real-IP for the client address and X-Forwarded-Host and
+    /// X-Forwarded-Proto for the host, needed for forward auth, only enable behind a proxy
+    #[structopt(long = "trust-forwarded-headers", env = "TRUST_FORWARDED_HEADERS")]
+    pub trust_forwarded_headers: bool,
 }
 
 impl Default for GeneralOptions {
     fn default() -> Self {
-        GeneralOptions { log: Level::Info }
+        GeneralOptions {
+            log: Level::Info,
+            trust_forwarded_headers: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct StorageOptions {
+    /// Where sessions, tokens and keys are kept, either 'sled', 'sqlite', 'postgres' or 'memory'
+    #[structopt(
+        long = "storage-backend",
+        default_value = "sled",
+        env = "STORAGE_BACKEND"
+    )]
+    pub backend: StorageBackend,
+
+    /// Directory of the sled database or file of the SQLite database
+    #[structopt(
+        long = "storage-path",
+        default_value = ".oauth.dat",
+        parse(from_os_str),
+        env = "STORAGE_PATH"
+    )]
+    pub db_path: PathBuf,
+
+    /// Connection URL of the PostgreSQL database, e.g. postgres://oauth:secret@db/oauth
+    #[structopt(long = "storage-url", env = "STORAGE_URL")]
+    pub db_url: Option<String>,
+
+    /// PEM certificate of the CA that signed the PostgreSQL server certificate, when the system doesn't trust it.
+    /// TLS is used as the URL's sslmode asks, e.g. ?sslmode=require
+    #[structopt(long = "storage-ca-file", parse(from_os_str), env = "STORAGE_CA_FILE")]
+    pub db_ca_file: Option<PathBuf>,
+
+    /// Connections kept open to the SQL database
+    #[structopt(
+        long = "storage-pool-size",
+        default_value = "4",
+        env = "STORAGE_POOL_SIZE"
+    )]
+    pub db_pool_size: usize,
+
+    /// Key records are encrypted with when they are saved, 32 bytes as base64. Not for the SQL backends
+    #[structopt(
+        long = "storage-encryption-key",
+        env = "STORAGE_ENCRYPTION_KEY",
+        hide_env_values = true
+    )]
+    pub encryption_key: Option<String>,
+
+    /// File holding the key records are encrypted with, instead of --storage-encryption-key
+    #[structopt(
+        long = "storage-encryption-key-file",
+        parse(from_os_str),
+        env = "STORAGE_ENCRYPTION_KEY_FILE"
+    )]
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// Key records were encrypted with before the current one, for --storage-reencrypt
+    #[structopt(
+        long = "storage-previous-encryption-key",
+        env = "STORAGE_PREVIOUS_ENCRYPTION_KEY",
+        hide_env_values = true
+    )]
+    pub previous_encryption_key: Option<String>,
+
+    /// File holding the key records were encrypted with before the current one
+    #[structopt(
+        long = "storage-previous-encryption-key-file",
+        parse(from_os_str),
+        env = "STORAGE_PREVIOUS_ENCRYPTION_KEY_FILE"
+    )]
+    pub previous_encryption_key_file: Option<PathBuf>,
+
+    /// Encrypt the records kept in the clear or with the previous key with the current key, then exit
+    #[structopt(long = "storage-reencrypt")]
+    pub reencrypt: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            backend: StorageBackend::Sled,
+            db_path: PathBuf::from(".oauth.dat"),
+            db_url: None,
+            db_ca_file: None,
+            db_pool_size: 4,
+            encryption_key: None,
+            encryption_key_file: None,
+            previous_encryption_key: None,
+            previous_encryption_key_file: None,
+            reencrypt: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct KeyOptions {
+    /// Seconds after which cookies and JWTs are signed with a new key, 0 to only rotate with --rotate-keys
+    #[structopt(
+        long = "key-rotation-interval",
+        default_value = "0",
+        env = "KEY_ROTATION_INTERVAL"
+    )]
+    pub rotation_interval: u64,
+
+    /// Seconds a replaced key still verifies signatures, at least the session lifetime to keep everyone logged in
+    #[structopt(
+        long = "key-retire-after",
+        default_value = "86400",
+        env = "KEY_RETIRE_AFTER"
+    )]
+    pub retire_after: u64,
+
+    /// Start signing with new keys, then exit. Running instances pick them up on their next sweep
+    #[structopt(long = "rotate-keys")]
+    pub rotate: bool,
+
+    /// Where supplied keys come from, 'local' for the options below or 'vault'. Keys not supplied are generated into the database
+    #[structopt(long = "key-provider", default_value = "local", env = "KEY_PROVIDER")]
+    pub provider: KeyProvider,
+
+    /// Key cookies are signed and tokens hashed with, at least 32 bytes as base64. Changing it revokes issued tokens
+    #[structopt(long = "signing-key", env = "SIGNING_KEY", hide_env_values = true)]
+    pub signing_key: Option<String>,
+
+    /// File holding the signing key, instead of --signing-key
+    #[structopt(
+        long = "signing-key-file",
+        parse(from_os_str),
+        env = "SIGNING_KEY_FILE"
+    )]
+    pub signing_key_file: Option<PathBuf>,
+
+    /// ECDSA P-256 key JWTs are signed with, PKCS#8 as PEM or base64
+    #[structopt(long = "jwt-key", env = "JWT_KEY", hide_env_values = true)]
+    pub jwt_key: Option<String>,
+
+    /// File holding the JWT key, instead of --jwt-key
+    #[structopt(long = "jwt-key-file", parse(from_os_str), env = "JWT_KEY_FILE")]
+    pub jwt_key_file: Option<PathBuf>,
+
+    /// Address of the Vault server for --key-provider vault
+    #[structopt(long = "vault-url", env = "VAULT_ADDR")]
+    pub vault_url: Option<String>,
+
+    /// Token to read the keys from Vault with
+    #[structopt(long = "vault-token", env = "VAULT_TOKEN", hide_env_values = true)]
+    pub vault_token: Option<String>,
+
+    /// Path of the Vault secret holding the 'signing' and 'jwt' keys
+    #[structopt(
+        long = "vault-secret-path",
+        default_value = "secret/data/oauth-lite",
+        env = "VAULT_SECRET_PATH"
+    )]
+    pub vault_path: String,
+}
+
+impl Default for KeyOptions {
+    fn default() -> Self {
+        KeyOptions {
+            rotation_interval: 0,
+            retire_after: 86400,
+            rotate: false,
+            provider: KeyProvider::Local,
+            signing_key: None,
+            signing_key_file: None,
+            jwt_key: None,
+            jwt_key_file: None,
+            vault_url: None,
+            vault_token: None,
+            vault_path: "secret/data/oauth-lite".into(),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum KeyProvider {
+    /// Keys given as options, environment variables or files
+    #[default]
+    Local,
+    /// Keys read from a HashiCorp Vault KV secret
+    Vault,
+}
+
+impl std::str::FromStr for KeyProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "local" => Ok(KeyProvider::Local),
+            "vault" => Ok(KeyProvider::Vault),
+            _ => Err(format!("invalid key provider `{}`", s)),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    #[default]
+    Sled,
+    Sqlite,
+    /// Shared between instances behind a load balancer, needs --storage-url
+    Postgres,
+    /// Nothing survives a restart, meant for tests and trying things out
+    Memory,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sled" => Ok(StorageBackend::Sled),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "postgres" => Ok(StorageBackend::Postgres),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("invalid storage backend `{}`", s)),
+        }
     }
 }
 
 #[derive(Default, Debug, Clone, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 pub struct OauthOptions {
-    /// Enable the pass-through URL for direct usage with a reverse proxy
+    /// Proxy requests for the pass-through domains, the login page is then found through --oauth-issuer
     #[structopt(
         short,
         long = "oauth-enable-passthrough",
@@ -50,9 +313,14 @@ pub struct OauthOptions {
     )]
     pub enable_passthrough: bool,
 
-    /// Enable the pass-through URL for direct usage with a reverse proxy
-    #[structopt(long = "oauth-passthrough-domains", env = "OAUTH_PASSTHROUGH_DOMAINS")]
-    pub pass_through_domains: Vec<String>,
+    /// Define the domains to proxy logged in users to their upstream for, e.g. app.example.com=http://127.0.0.1:8080
+    #[structopt(
+        long = "oauth-passthrough-domains",
+        parse(try_from_str = parse_key_val),
+        number_of_values = 1,
+        env = "OAUTH_PASSTHROUGH_DOMAINS"
+    )]
+    pub pass_through_domains: Vec<(String, String)>,
 
     /// Define the client IDs and their redirect URLs
     #[structopt(
@@ -63,18 +331,120 @@ pub struct OauthOptions {
         env = "OAUTH_CLIENT_IDS"
     )]
     pub client_ids: Vec<(String, String)>,
+
+    /// Externally visible base URL, put in the ID tokens as the issuer. The session cookie is only
+    /// set on its host, so it has to be the URL users reach the login page on. Starting with the
+    /// default is refused unless --cookie-insecure is on for local development
+    #[structopt(
+        long = "oauth-issuer",
+        default_value = "http://localhost:3030",
+        env = "OAUTH_ISSUER"
+    )]
+    pub issuer: String,
+
+    /// Define where clients may send users after logging out
+    #[structopt(
+        long = "oauth-post-logout-redirect-uris",
+        parse(try_from_str = parse_key_val),
+        number_of_values = 1,
+        env = "OAUTH_POST_LOGOUT_REDIRECT_URIS"
+    )]
+    pub post_logout_redirect_uris: Vec<(String, String)>,
+
+    /// Define the OIDC back-channel logout URIs that logout tokens are POSTed to
+    #[structopt(
+        long = "oauth-backchannel-logout-uris",
+        parse(try_from_str = parse_key_val),
+        number_of_values = 1,
+        env = "OAUTH_BACKCHANNEL_LOGOUT_URIS"
+    )]
+    pub backchannel_logout_uris: Vec<(String, String)>,
+
+    /// Define the OIDC front-channel logout URIs loaded in iframes on logout
+    #[structopt(
+        long = "oauth-frontchannel-logout-uris",
+        parse(try_from_str = parse_key_val),
+        number_of_values = 1,
+        env = "OAUTH_FRONTCHANNEL_LOGOUT_URIS"
+    )]
+    pub frontchannel_logout_uris: Vec<(String, String)>,
+
+    /// Times a failed back-channel logout is retried before it is recorded as failed
+    #[structopt(
+        long = "oauth-logout-retries",
+        default_value = "3",
+        env = "OAUTH_LOGOUT_RETRIES"
+    )]
+    pub logout_retries: u32,
+
+    /// Hosts users may be sent back to after logging in, a leading dot also allows subdomains.
+    /// Hosts other than the one of --oauth-issuer get their own session cookie through a handoff.
+    #[structopt(
+        long = "oauth-return-to-domains",
+        number_of_values = 1,
+        env = "OAUTH_RETURN_TO_DOMAINS"
+    )]
+    pub return_to_domains: Vec<String>,
+
+    /// Revoke the tokens issued under a session when it is logged out
+    #[structopt(
+        long = "oauth-logout-revoke-tokens",
+        env = "OAUTH_LOGOUT_REVOKE_TOKENS"
+    )]
+    pub logout_revoke_tokens: bool,
 }
 
 #[derive(Default, Debug, Clone, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 pub struct LdapAuthenticator {
-    /// Set the URL string for the LDAP server
+    /// Set the URL string for the LDAP server, may be repeated for failover
     #[structopt(
         long = "ldap-url",
         default_value = "ldap://localhost:389",
+        number_of_values = 1,
         env = "LDAP_URL"
     )]
-    pub url: String,
+    pub urls: Vec<String>,
+
+    /// How to pick between multiple LDAP servers, either 'ordered' or 'round-robin'
+    #[structopt(
+        long = "ldap-failover",
+        default_value = "ordered",
+        env = "LDAP_FAILOVER"
+    )]
+    pub failover: FailoverStrategy,
+
+    /// Number of pooled LDAP connections, each served by its own worker thread
+    #[structopt(long = "ldap-pool-size", default_value = "4", env = "LDAP_POOL_SIZE")]
+    pub pool_size: usize,
+
+    /// Seconds to wait when opening a connection to an LDAP server
+    #[structopt(
+        long = "ldap-connect-timeout",
+        default_value = "5",
+        env = "LDAP_CONNECT_TIMEOUT"
+    )]
+    pub connect_timeout: u64,
+
+    /// Seconds to wait for a single LDAP operation to complete
+    #[structopt(long = "ldap-timeout", default_value = "10", env = "LDAP_TIMEOUT")]
+    pub timeout: u64,
+
+    /// Seconds a pooled connection may sit idle before it is health checked again
+    #[structopt(
+        long = "ldap-health-check-interval",
+        default_value = "30",
+        env = "LDAP_HEALTH_CHECK_INTERVAL"
+    )]
+    pub health_check_interval: u64,
+
+    /// Seconds to skip an LDAP server after it has failed
+    #[structopt(
+        long = "ldap-retry-after",
+        default_value = "30",
+        env = "LDAP_RETRY_AFTER"
+    )]
+    pub retry_after: u64,
 
     /// Set the LDAP group/domain to search for users under    
     #[structopt(
@@ -93,6 +463,433 @@ pub struct LdapAuthenticator {
     pub attrs: Vec<String>,
 }
 
+#[derive(Default, Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct UpstreamOptions {
+    /// Name of the upstream identity provider shown on the login page, enables federation
+    #[structopt(long = "upstream-name", env = "UPSTREAM_NAME")]
+    pub name: Option<String>,
+
+    /// Authorization endpoint of the upstream provider
+    #[structopt(long = "upstream-authorize-url", env = "UPSTREAM_AUTHORIZE_URL")]
+    pub authorize_url: Option<String>,
+
+    /// Token endpoint of the upstream provider
+    #[structopt(long = "upstream-token-url", env = "UPSTREAM_TOKEN_URL")]
+    pub token_url: Option<String>,
+
+    /// Userinfo endpoint of the upstream provider, claims are read from here
+    #[structopt(long = "upstream-userinfo-url", env = "UPSTREAM_USERINFO_URL")]
+    pub userinfo_url: Option<String>,
+
+    /// Client ID registered with the upstream provider
+    #[structopt(long = "upstream-client-id", env = "UPSTREAM_CLIENT_ID")]
+    pub client_id: Option<String>,
+
+    /// Client secret registered with the upstream provider
+    #[structopt(
+        long = "upstream-client-secret",
+        env = "UPSTREAM_CLIENT_SECRET",
+        hide_env_values = true
+    )]
+    pub client_secret: Option<String>,
+
+    /// Externally visible URL of the /upstream/callback route
+    #[structopt(long = "upstream-redirect-url", env = "UPSTREAM_REDIRECT_URL")]
+    pub redirect_url: Option<String>,
+
+    /// Scopes to request from the upstream provider
+    #[structopt(
+        long = "upstream-scope",
+        default_value = "openid profile email",
+        env = "UPSTREAM_SCOPE"
+    )]
+    pub scope: String,
+
+    /// Seconds to wait for the upstream token and userinfo endpoints to answer
+    #[structopt(
+        name = "upstream-timeout",
+        long = "upstream-timeout",
+        default_value = "10",
+        env = "UPSTREAM_TIMEOUT"
+    )]
+    pub timeout: u64,
+
+    /// Claim to use as the username, e.g. 'login' for GitHub or 'username' for GitLab. Usernames become
+    /// upstream:<host>:<claim> so they can't pass for directory users
+    #[structopt(
+        long = "upstream-username-claim",
+        default_value = "preferred_username",
+        env = "UPSTREAM_USERNAME_CLAIM"
+    )]
+    pub username_claim: String,
+
+    /// List of upstream claims to store as attributes in the user session
+    #[structopt(
+        name = "upstream-attrs",
+        long = "upstream-attrs",
+        env = "UPSTREAM_ATTRIBUTES"
+    )]
+    pub attrs: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct MfaOptions {
+    /// Require a second factor for every login
+    #[structopt(long = "mfa-required", env = "MFA_REQUIRED")]
+    pub required: bool,
+
+    /// Client IDs that require a second factor to log in
+    #[structopt(long = "mfa-clients", number_of_values = 1, env = "MFA_CLIENTS")]
+    pub clients: Vec<String>,
+
+    /// LDAP groups whose members require a second factor to log in
+    #[structopt(long = "mfa-groups", number_of_values = 1, env = "MFA_GROUPS")]
+    pub groups: Vec<String>,
+
+    /// User attribute holding group memberships, must also be listed in --ldap-attrs
+    #[structopt(
+        long = "mfa-group-attr",
+        default_value = "memberOf",
+        env = "MFA_GROUP_ATTR"
+    )]
+    pub group_attr: String,
+
+    /// Issuer name shown in authenticator apps
+    #[structopt(
+        long = "totp-issuer",
+        default_value = "oauth-lite",
+        env = "TOTP_ISSUER"
+    )]
+    pub totp_issuer: String,
+}
+
+#[derive(Default, Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct WebauthnOptions {
+    /// WebAuthn relying party ID, usually the domain of the login page, enables passkeys
+    #[structopt(long = "webauthn-rp-id", env = "WEBAUTHN_RP_ID")]
+    pub rp_id: Option<String>,
+
+    /// Relying party name shown by the browser when using a passkey
+    #[structopt(
+        long = "webauthn-rp-name",
+        default_value = "oauth-lite",
+        env = "WEBAUTHN_RP_NAME"
+    )]
+    pub rp_name: String,
+
+    /// Origin of the login page, defaults to https:// followed by the relying party ID
+    #[structopt(long = "webauthn-origin", env = "WEBAUTHN_ORIGIN")]
+    pub origin: Option<String>,
+
+    /// Seconds the user details kept with passkeys are trusted for passwordless logins,
+    /// after which a password login is needed to refresh them, 0 to trust them forever
+    #[structopt(
+        long = "webauthn-max-user-age",
+        default_value = "604800",
+        env = "WEBAUTHN_MAX_USER_AGE"
+    )]
+    pub max_user_age: u64,
+}
+
+#[derive(Default, Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ThrottleOptions {
+    /// Failed logins for a username before it is locked out
+    #[structopt(
+        long = "login-max-failures",
+        default_value = "5",
+        env = "LOGIN_MAX_FAILURES"
+    )]
+    pub max_failures: u32,
+
+    /// Failed logins from a client address before it is locked out
+    #[structopt(
+        long = "login-max-failures-per-ip",
+        default_value = "20",
+        env = "LOGIN_MAX_FAILURES_PER_IP"
+    )]
+    pub max_failures_per_ip: u32,
+
+    /// Seconds to wait after the first failed login, doubled on every further failure
+    #[structopt(long = "login-backoff", default_value = "1", env = "LOGIN_BACKOFF")]
+    pub backoff: u64,
+
+    /// Longest wait in seconds between failed logins before the lockout kicks in
+    #[structopt(
+        long = "login-backoff-max",
+        default_value = "60",
+        env = "LOGIN_BACKOFF_MAX"
+    )]
+    pub backoff_max: u64,
+
+    /// Seconds a locked out username or address has to wait, failures are forgotten after this
+    #[structopt(long = "login-lockout", default_value = "900", env = "LOGIN_LOCKOUT")]
+    pub lockout: u64,
+}
+
+#[derive(Default, Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct SessionOptions {
+    /// Seconds a session stays valid after it is created, however active it is
+    #[structopt(
+        long = "session-lifetime",
+        default_value = "86400",
+        env = "SESSION_LIFETIME"
+    )]
+    pub lifetime: u64,
+
+    /// Seconds without any request after which a session expires
+    #[structopt(
+        long = "session-idle-timeout",
+        default_value = "3600",
+        env = "SESSION_IDLE_TIMEOUT"
+    )]
+    pub idle_timeout: u64,
+
+    /// Seconds between purges of expired sessions, codes and tokens
+    #[structopt(
+        long = "session-sweep-interval",
+        default_value = "300",
+        env = "SESSION_SWEEP_INTERVAL"
+    )]
+    pub sweep_interval: u64,
+
+    /// Most sessions without a logged in user kept for a single client address, more are refused. 0 for no limit
+    #[structopt(
+        long = "session-max-per-ip",
+        default_value = "100",
+        env = "SESSION_MAX_PER_IP"
+    )]
+    pub max_per_ip: usize,
+}
+
+#[derive(Default, Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct CookieOptions {
+    /// Name of the session cookie, prefixed with __Host- or __Secure- when the other settings allow
+    #[structopt(
+        name = "cookie-name",
+        long = "cookie-name",
+        default_value = "SID",
+        env = "COOKIE_NAME"
+    )]
+    pub name: String,
+
+    /// Domain to scope the session cookie to, leave unset for a host-only cookie
+    #[structopt(long = "cookie-domain", env = "COOKIE_DOMAIN")]
+    pub domain: Option<String>,
+
+    /// Path to scope the session cookie to
+    #[structopt(long = "cookie-path", default_value = "/", env = "COOKIE_PATH")]
+    pub path: String,
+
+    /// SameSite mode of the session cookie, either 'strict', 'lax' or 'none'
+    #[structopt(
+        long = "cookie-same-site",
+        default_value = "lax",
+        env = "COOKIE_SAME_SITE"
+    )]
+    pub same_site: SameSite,
+
+    /// Send the session cookie over plain HTTP too, only for development without TLS
+    #[structopt(long = "cookie-insecure", env = "COOKIE_INSECURE")]
+    pub insecure: bool,
+
+    /// Seconds the browser keeps the session cookie, defaults to the rest of the session lifetime
+    #[structopt(long = "cookie-max-age", env = "COOKIE_MAX_AGE")]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ForwardAuthOptions {
+    /// Reverse proxy /authenticate answers to, either 'nginx', 'traefik' or 'caddy'
+    #[structopt(
+        long = "forward-auth-mode",
+        default_value = "nginx",
+        env = "FORWARD_AUTH_MODE"
+    )]
+    pub mode: ForwardAuthMode,
+
+    /// Where Traefik and Caddy send users that aren't logged in, e.g. http://auth.localhost/authenticate?client_id=test&response_type=code
+    #[structopt(long = "forward-auth-login-url", env = "FORWARD_AUTH_LOGIN_URL")]
+    pub login_url: Option<String>,
+
+    /// Header carrying the username of the logged in user
+    #[structopt(
+        long = "forward-auth-user-header",
+        default_value = "X-Auth-User",
+        env = "FORWARD_AUTH_USER_HEADER"
+    )]
+    pub user_header: String,
+
+    /// Header carrying the email address of the logged in user
+    #[structopt(
+        long = "forward-auth-email-header",
+        default_value = "X-Auth-Email",
+        env = "FORWARD_AUTH_EMAIL_HEADER"
+    )]
+    pub email_header: String,
+
+    /// User attribute holding the email address, must also be listed in --ldap-attrs
+    #[structopt(
+        long = "forward-auth-email-attr",
+        default_value = "mail",
+        env = "FORWARD_AUTH_EMAIL_ATTR"
+    )]
+    pub email_attr: String,
+
+    /// Header carrying the comma separated groups of the logged in user
+    #[structopt(
+        long = "forward-auth-groups-header",
+        default_value = "X-Auth-Groups",
+        env = "FORWARD_AUTH_GROUPS_HEADER"
+    )]
+    pub groups_header: String,
+
+    /// User attribute holding the group memberships, must also be listed in --ldap-attrs
+    #[structopt(
+        long = "forward-auth-groups-attr",
+        default_value = "memberOf",
+        env = "FORWARD_AUTH_GROUPS_ATTR"
+    )]
+    pub groups_attr: String,
+
+    /// Define extra headers filled from user attributes as attribute=Header
+    #[structopt(
+        long = "forward-auth-headers",
+        parse(try_from_str = parse_key_val),
+        number_of_values = 1,
+        env = "FORWARD_AUTH_HEADERS"
+    )]
+    pub headers: Vec<(String, String)>,
+
+    /// YAML file of access rules by host and path prefix, the host is read from X-Forwarded-Host
+    #[structopt(
+        long = "forward-auth-access-rules",
+        parse(from_os_str),
+        env = "FORWARD_AUTH_ACCESS_RULES"
+    )]
+    pub access_rules: Option<PathBuf>,
+
+    /// Hosts accepting OAuth access tokens of a client as Bearer credentials, e.g. api.example.com=cli.
+    /// A leading dot also allows subdomains, repeat the host to accept the tokens of several clients.
+    #[structopt(
+        long = "forward-auth-bearer-hosts",
+        parse(try_from_str = parse_key_val),
+        number_of_values = 1,
+        env = "FORWARD_AUTH_BEARER_HOSTS"
+    )]
+    pub bearer_hosts: Vec<(String, String)>,
+
+    /// Hosts accepting usernames and passwords as Basic credentials, a leading dot also allows subdomains
+    #[structopt(
+        long = "forward-auth-basic-hosts",
+        number_of_values = 1,
+        env = "FORWARD_AUTH_BASIC_HOSTS"
+    )]
+    pub basic_hosts: Vec<String>,
+
+    /// Seconds a successful Basic login is remembered before asking the directory again
+    #[structopt(
+        long = "forward-auth-basic-cache",
+        default_value = "60",
+        env = "FORWARD_AUTH_BASIC_CACHE"
+    )]
+    pub basic_cache: u64,
+
+    /// Address to serve the Envoy ext_authz gRPC service on, it is not served when left out
+    #[structopt(long = "forward-auth-grpc-addr", env = "FORWARD_AUTH_GRPC_ADDR")]
+    pub grpc_addr: Option<SocketAddr>,
+}
+
+impl Default for ForwardAuthOptions {
+    fn default() -> Self {
+        ForwardAuthOptions {
+            mode: ForwardAuthMode::Nginx,
+            login_url: None,
+            user_header: "X-Auth-User".into(),
+            email_header: "X-Auth-Email".into(),
+            email_attr: "mail".into(),
+            groups_header: "X-Auth-Groups".into(),
+            groups_attr: "memberOf".into(),
+            headers: vec![],
+            access_rules: None,
+            bearer_hosts: vec![],
+            basic_hosts: vec![],
+            basic_cache: 60,
+            grpc_addr: None,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum ForwardAuthMode {
+    /// 403 for users that aren't logged in, nginx handles the redirect itself
+    #[default]
+    Nginx,
+    /// Traefik ForwardAuth, users that aren't logged in are redirected to the login page
+    Traefik,
+    /// Caddy forward_auth, users that aren't logged in are redirected to the login page
+    Caddy,
+}
+
+impl std::str::FromStr for ForwardAuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nginx" => Ok(ForwardAuthMode::Nginx),
+            "traefik" => Ok(ForwardAuthMode::Traefik),
+            "caddy" => Ok(ForwardAuthMode::Caddy),
+            _ => Err(format!("invalid forward auth mode `{}`", s)),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl std::str::FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(format!("invalid SameSite mode `{}`", s)),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum FailoverStrategy {
+    #[default]
+    Ordered,
+    RoundRobin,
+}
+
+impl std::str::FromStr for FailoverStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ordered" => Ok(FailoverStrategy::Ordered),
+            "round-robin" | "roundrobin" => Ok(FailoverStrategy::RoundRobin),
+            _ => Err(format!("invalid failover strategy `{}`", s)),
+        }
+    }
+}
+
 fn parse_key_val<T, U>(s: &str) -> std::result::Result<(T, U), Box<dyn Error>>
 where
     T: std::str::FromStr,
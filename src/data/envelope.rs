@@ -0,0 +1,234 @@
+use {
+    crate::{args::StorageOptions, Error, Result},
+    rand::{rngs::OsRng, RngCore},
+    ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    sha2::{Digest, Sha256},
+    std::{fs, path::Path},
+};
+
+/// Starts every sealed value, JSON never starts with a NUL
+const MAGIC: &[u8] = b"\0enc1";
+const ID_LEN: usize = 8;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Magic, key-encryption key ID, nonce and data key wrapped with the
+/// key-encryption key, then the nonce of the value and the value itself
+const HEADER_LEN: usize = MAGIC.len() + ID_LEN + NONCE_LEN + KEY_LEN + TAG_LEN + NONCE_LEN;
+
+lazy_static! {
+    static ref KEYS: Result<Keys> = Keys::load(&crate::CONFIG.storage);
+}
+
+struct Kek {
+    id: Vec<u8>,
+    key: Vec<u8>,
+}
+
+/// Key-encryption keys, values are sealed with the current one and the
+/// previous one only opens values sealed before a key change
+struct Keys {
+    current: Option<Kek>,
+    previous: Option<Kek>,
+}
+
+impl Kek {
+    fn parse(encoded: &str) -> Result<Self> {
+        let key = base64::decode(encoded.trim())
+            .ok()
+            .filter(|k| k.len() == KEY_LEN)
+            .ok_or_else(|| {
+                Error::Service("Encryption keys must be 32 bytes encoded as base64".into())
+            })?;
+        let id = Sha256::digest(&key)[..ID_LEN].to_vec();
+        Ok(Kek { id, key })
+    }
+
+    fn load(value: &Option<String>, file: &Option<impl AsRef<Path>>) -> Result<Option<Self>> {
+        match (value, file) {
+            (Some(value), _) => Ok(Some(Self::parse(value)?)),
+            (None, Some(file)) => {
+                let value = fs::read_to_string(file.as_ref()).map_err(|e| {
+                    Error::Service(format!(
+                        "Unable to read encryption key {} -> {}",
+                        file.as_ref().display(),
+                        e
+                    ))
+                })?;
+                Ok(Some(Self::parse(&value)?))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl Keys {
+    fn load(config: &StorageOptions) -> Result<Self> {
+        let keys = Keys {
+            current: Kek::load(&config.encryption_key, &config.encryption_key_file)?,
+            previous: Kek::load(
+                &config.previous_encryption_key,
+                &config.previous_encryption_key_file,
+            )?,
+        };
+        if keys.current.is_none() && keys.previous.is_some() {
+            return Err(Error::Service(
+                "A previous encryption key needs a current one to re-encrypt with".into(),
+            ));
+        }
+        Ok(keys)
+    }
+
+    fn by_id(&self, id: &[u8]) -> Option<&Kek> {
+        self.current
+            .iter()
+            .chain(self.previous.iter())
+            .find(|k| k.id == id)
+    }
+}
+
+fn keys() -> Result<&'static Keys> {
+    KEYS.as_ref().map_err(|e| e.clone())
+}
+
+/// Whether values are sealed when they are saved
+pub fn enabled() -> Result<bool> {
+    Ok(keys()?.current.is_some())
+}
+
+fn failed() -> Error {
+    Error::Service("Failed to encrypt or decrypt a stored value".into())
+}
+
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn seal_with(key: &[u8], ad: &[u8], value: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).map_err(|_| failed())?);
+    let nonce = nonce();
+    let mut in_out = value.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(ad),
+        &mut in_out,
+    )
+    .map_err(|_| failed())?;
+    Ok((nonce.to_vec(), in_out))
+}
+
+fn open_with(key: &[u8], nonce: &[u8], ad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).map_err(|_| failed())?);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| failed())?;
+    let mut in_out = sealed.to_vec();
+    let plain = key
+        .open_in_place(nonce, Aad::from(ad), &mut in_out)
+        .map_err(|_| failed())?;
+    Ok(plain.to_vec())
+}
+
+fn wrap(kek: &Kek, dek: &[u8]) -> Result<Vec<u8>> {
+    let (nonce, wrapped) = seal_with(&kek.key, MAGIC, dek)?;
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&kek.id);
+    header.extend_from_slice(&nonce);
+    header.extend_from_slice(&wrapped);
+    Ok(header)
+}
+
+/// The data key of a sealed value and the key-encryption key it's wrapped with
+fn unwrap(value: &[u8]) -> Result<(&'static Kek, Vec<u8>)> {
+    if value.len() < HEADER_LEN {
+        return Err(failed());
+    }
+    let id = &value[MAGIC.len()..MAGIC.len() + ID_LEN];
+    let kek = keys()?.by_id(id).ok_or_else(|| {
+        Error::Service(
+            "Stored value is encrypted with an unknown key, is the key configured?".into(),
+        )
+    })?;
+    let nonce = &value[MAGIC.len() + ID_LEN..MAGIC.len() + ID_LEN + NONCE_LEN];
+    let wrapped = &value[MAGIC.len() + ID_LEN + NONCE_LEN..HEADER_LEN - NONCE_LEN];
+    Ok((kek, open_with(&kek.key, nonce, MAGIC, wrapped)?))
+}
+
+pub fn is_sealed(value: &[u8]) -> bool {
+    value.starts_with(MAGIC)
+}
+
+/// Additional data tying a sealed value to where it's stored, so that it
+/// can't be passed off as another record
+fn ad(tree: &str, key: &str) -> Vec<u8> {
+    format!("{}\0{}", tree, key).into_bytes()
+}
+
+/// Seals a value under a new data key if encryption is enabled
+pub fn seal(tree: &str, key: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+    let kek = match &keys()?.current {
+        Some(kek) => kek,
+        None => return Ok(value),
+    };
+    let mut dek = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut dek);
+    let mut sealed = wrap(kek, &dek)?;
+    let (nonce, ciphertext) = seal_with(&dek, &ad(tree, key), &value)?;
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// The value a stored one was sealed from, values written while encryption
+/// was off are passed through
+pub fn open(tree: &str, key: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+    if !is_sealed(&value) {
+        return Ok(value);
+    }
+    let (_, dek) = unwrap(&value)?;
+    let nonce = &value[HEADER_LEN - NONCE_LEN..HEADER_LEN];
+    open_with(&dek, nonce, &ad(tree, key), &value[HEADER_LEN..])
+}
+
+/// A sealed value with its data key wrapped with the current key instead of
+/// the previous one, `None` when there's nothing to do. The value itself
+/// isn't touched.
+pub fn rewrap(value: &[u8]) -> Result<Option<Vec<u8>>> {
+    let current = match (&keys()?.current, is_sealed(value)) {
+        (Some(current), true) => current,
+        _ => return Ok(None),
+    };
+    let (kek, dek) = unwrap(value)?;
+    if kek.id == current.id {
+        return Ok(None);
+    }
+    let mut rewrapped = wrap(current, &dek)?;
+    rewrapped.extend_from_slice(&value[HEADER_LEN - NONCE_LEN..]);
+    Ok(Some(rewrapped))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seals_values() -> Result<()> {
+        let kek = Kek::parse(&base64::encode(&[7u8; KEY_LEN]))?;
+        let dek = [9u8; KEY_LEN];
+        let mut sealed = wrap(&kek, &dek)?;
+        let (nonce, ciphertext) = seal_with(&dek, &ad("sessions", "a"), b"{}")?;
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        assert!(is_sealed(&sealed));
+        let nonce = &sealed[HEADER_LEN - NONCE_LEN..HEADER_LEN];
+        let wrapped = &sealed[MAGIC.len() + ID_LEN + NONCE_LEN..HEADER_LEN - NONCE_LEN];
+        let wrap_nonce = &sealed[MAGIC.len() + ID_LEN..MAGIC.len() + ID_LEN + NONCE_LEN];
+        assert_eq!(open_with(&kek.key, wrap_nonce, MAGIC, wrapped)?, dek);
+        let body = &sealed[HEADER_LEN..];
+        assert_eq!(open_with(&dek, nonce, &ad("sessions", "a"), body)?, b"{}");
+        assert!(open_with(&dek, nonce, &ad("sessions", "b"), body).is_err());
+        assert!(Kek::parse("c2hvcnQ=").is_err());
+        Ok(())
+    }
+}
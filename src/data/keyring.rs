@@ -0,0 +1,389 @@
+use {
+    super::{secrets, server_value, update_server_value},
+    crate::{args::KeyOptions, Error, Result},
+    chrono::Utc,
+    parking_lot::{Mutex, RwLock},
+    rand::{rngs::OsRng, RngCore},
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::{
+        cell::Cell,
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+};
+
+/// How often a ring may be reloaded for an unknown key ID, as anyone can
+/// send those
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref RINGS: RwLock<HashMap<Ring, Arc<KeyRing>>> = RwLock::new(HashMap::new());
+    /// Supplied keys seen by this instance by ID, the store only has their IDs
+    static ref SUPPLIED: RwLock<HashMap<String, Vec<u8>>> = RwLock::new(HashMap::new());
+    static ref RELOADED: Mutex<HashMap<Ring, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// What the keys of a ring sign
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ring {
+    /// HMAC keys of cookies and handoff tokens
+    Signing,
+    /// PKCS#8 documents of the ECDSA P-256 keys of JWTs
+    Jwt,
+}
+
+impl Ring {
+    pub const ALL: &'static [Ring] = &[Ring::Signing, Ring::Jwt];
+
+    fn field(self) -> &'static str {
+        match self {
+            Ring::Signing => "signing_keys",
+            Ring::Jwt => "jwt_keys",
+        }
+    }
+
+    /// Name the key is supplied as
+    fn name(self) -> &'static str {
+        match self {
+            Ring::Signing => super::KEY_FIELD,
+            Ring::Jwt => super::JWT_KEY_FIELD,
+        }
+    }
+
+    /// The key supplied from outside the database, which then always signs
+    pub fn supplied(self) -> Result<Option<Vec<u8>>> {
+        let key = match secrets::key(self.name())? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let valid = match self {
+            Ring::Signing => key.len() >= 32,
+            Ring::Jwt => ring::signature::EcdsaKeyPair::from_pkcs8(
+                &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                &key,
+                &ring::rand::SystemRandom::new(),
+            )
+            .is_ok(),
+        };
+        if !valid {
+            return Err(Error::Service(match self {
+                Ring::Signing => "The supplied signing key is shorter than 32 bytes".into(),
+                Ring::Jwt => "The supplied JWT key isn't a PKCS#8 ECDSA P-256 key".into(),
+            }));
+        }
+        SUPPLIED.write().insert(self.key_id(&key), key.clone());
+        Ok(Some(key))
+    }
+
+    /// The single key kept before there were rings, it becomes the first one
+    fn legacy(self) -> Result<Vec<u8>> {
+        match self {
+            Ring::Signing => super::key(),
+            Ring::Jwt => match server_value(super::JWT_KEY_FIELD)? {
+                Some(key) => Ok(key),
+                None => self.generate(),
+            },
+        }
+    }
+
+    /// Key IDs of JWT keys are taken from the public key, as published in the
+    /// JWK set
+    fn key_id(self, key: &[u8]) -> String {
+        let public = match self {
+            Ring::Signing => key,
+            Ring::Jwt => &key[key.len().saturating_sub(65)..],
+        };
+        base64::encode_config(&Sha256::digest(public)[..8], base64::URL_SAFE_NO_PAD)
+    }
+
+    fn generate(self) -> Result<Vec<u8>> {
+        match self {
+            Ring::Signing => {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                Ok(key.to_vec())
+            }
+            Ring::Jwt => {
+                let pkcs8 = ring::signature::EcdsaKeyPair::generate_pkcs8(
+                    &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &ring::rand::SystemRandom::new(),
+                )
+                .map_err(|_| Error::Service("Failed to generate JWT key".into()))?;
+                Ok(pkcs8.as_ref().to_vec())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RingKey {
+    pub id: String,
+    /// Left out of the store for supplied keys, which are only kept in memory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key: Vec<u8>,
+    /// When the key started signing
+    pub created: i64,
+}
+
+impl RingKey {
+    fn new(ring: Ring, key: Vec<u8>, created: i64) -> Self {
+        let id = ring.key_id(&key);
+        RingKey { id, key, created }
+    }
+}
+
+/// Keys of a ring from oldest to newest, the newest signs and the others
+/// only verify until they are retired
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRing {
+    pub keys: Vec<RingKey>,
+}
+
+impl KeyRing {
+    pub fn current(&self) -> &RingKey {
+        &self.keys[self.keys.len() - 1]
+    }
+
+    /// Drops the keys replaced longer than `retire_after` seconds ago, as
+    /// nothing they signed should still be in use
+    fn retire(&mut self, now: i64, retire_after: i64) -> usize {
+        let replaced: Vec<i64> = self.keys.iter().skip(1).map(|k| k.created).collect();
+        let before = self.keys.len();
+        let mut replaced = replaced.into_iter();
+        self.keys.retain(|_| {
+            replaced
+                .next()
+                .filter(|at| now - at >= retire_after)
+                .is_none()
+        });
+        before - self.keys.len()
+    }
+}
+
+/// Makes a newly supplied key the one that signs, or starts an empty ring
+/// with the key kept before there were rings. Returns whether it changed
+/// the ring.
+fn init(ring: Ring, keys: &mut KeyRing) -> Result<bool> {
+    let supplied = ring.supplied()?;
+    // Supplied keys saved by earlier versions are dropped from the store
+    let mut changed = false;
+    for key in keys.keys.iter_mut() {
+        if !key.key.is_empty() && SUPPLIED.read().contains_key(&key.id) {
+            key.key.clear();
+            changed = true;
+        }
+    }
+    let key = match (supplied, keys.keys.last()) {
+        (None, Some(_)) => return Ok(changed),
+        (Some(key), Some(current)) if current.id == ring.key_id(&key) => return Ok(changed),
+        (Some(key), Some(_)) => {
+            info!("Signing with the supplied {:?} key", ring);
+            key
+        }
+        (Some(key), None) => key,
+        (None, None) => {
+            let key = ring.legacy()?;
+            keys.keys
+                .push(RingKey::new(ring, key, Utc::now().timestamp()));
+            return Ok(true);
+        }
+    };
+    let id = ring.key_id(&key);
+    keys.keys.retain(|k| k.id != id);
+    let created = Utc::now().timestamp();
+    keys.keys.push(RingKey {
+        id,
+        key: vec![],
+        created,
+    });
+    Ok(true)
+}
+
+/// Fills in the supplied keys of a stored ring, dropping those this instance
+/// never had
+fn with_supplied(ring: Ring, mut keys: KeyRing) -> Result<KeyRing> {
+    for key in keys.keys.iter_mut().filter(|k| k.key.is_empty()) {
+        if let Some(supplied) = SUPPLIED.read().get(&key.id) {
+            key.key = supplied.clone();
+        }
+    }
+    keys.keys.retain(|k| !k.key.is_empty());
+    if keys.keys.is_empty() {
+        return Err(Error::Service(format!(
+            "The {:?} key is supplied to other instances, supply it to this one too",
+            ring
+        )));
+    }
+    Ok(keys)
+}
+
+/// Changes the stored ring with `change`, which returns whether it did.
+/// Instances changing the ring at the same time don't overwrite each other,
+/// `change` is applied again to what the other one saved instead.
+fn update<F: Fn(&mut KeyRing) -> Result<bool>>(ring: Ring, change: F) -> Result<Arc<KeyRing>> {
+    let parse = |stored: Option<&[u8]>| -> Result<KeyRing> {
+        match stored {
+            Some(stored) => Ok(serde_json::from_slice(stored)?),
+            None => Ok(KeyRing { keys: vec![] }),
+        }
+    };
+    let stored = update_server_value(ring.field(), |stored| {
+        let mut keys = parse(stored)?;
+        match change(&mut keys)? {
+            true => Ok(Some(serde_json::to_vec(&keys)?)),
+            false => Ok(None),
+        }
+    })?;
+    let keys = Arc::new(with_supplied(ring, parse(stored.as_deref())?)?);
+    RINGS.write().insert(ring, keys.clone());
+    Ok(keys)
+}
+
+/// Loads a ring again, as another instance may have changed it
+fn reload(ring: Ring) -> Result<Arc<KeyRing>> {
+    update(ring, |keys| init(ring, keys))
+}
+
+/// The keys of a ring, as last loaded by this instance
+pub fn ring(ring: Ring) -> Result<Arc<KeyRing>> {
+    if let Some(keys) = RINGS.read().get(&ring) {
+        return Ok(keys.clone());
+    }
+    reload(ring)
+}
+
+/// Keys of a ring matching `p`. When there are none the ring is reloaded in
+/// case another instance rotated it, at most once every `RELOAD_INTERVAL`.
+pub fn find<P: Fn(&RingKey) -> bool>(ring: Ring, p: P) -> Result<Vec<RingKey>> {
+    let found: Vec<RingKey> = self::ring(ring)?
+        .keys
+        .iter()
+        .filter(|k| p(k))
+        .cloned()
+        .collect();
+    if !found.is_empty() || !reload_allowed(ring) {
+        return Ok(found);
+    }
+    Ok(reload(ring)?
+        .keys
+        .iter()
+        .filter(|k| p(k))
+        .cloned()
+        .collect())
+}
+
+fn reload_allowed(ring: Ring) -> bool {
+    let mut reloaded = RELOADED.lock();
+    let now = Instant::now();
+    match reloaded.get(&ring) {
+        Some(at) if now.duration_since(*at) < RELOAD_INTERVAL => false,
+        _ => {
+            reloaded.insert(ring, now);
+            true
+        }
+    }
+}
+
+/// Starts signing with a new key, returning its ID. Supplied keys are rotated
+/// where they are kept instead, `None` then.
+pub fn rotate(ring: Ring, config: &KeyOptions) -> Result<Option<String>> {
+    if ring.supplied()?.is_some() {
+        return Ok(None);
+    }
+    let now = Utc::now().timestamp();
+    let keys = update(ring, |keys| {
+        init(ring, keys)?;
+        keys.keys.push(RingKey::new(ring, ring.generate()?, now));
+        keys.retire(now, config.retire_after as i64);
+        Ok(true)
+    })?;
+    Ok(Some(keys.current().id.to_string()))
+}
+
+/// Rotates the rings whose newest key is older than the rotation interval
+/// and retires the keys that were replaced long enough ago. Supplied keys are
+/// fetched again, a changed one takes over signing.
+pub fn rotate_due(config: &KeyOptions) -> Result<()> {
+    secrets::refresh();
+    let now = Utc::now().timestamp();
+    let interval = config.rotation_interval as i64;
+    for ring in Ring::ALL {
+        let supplied = ring.supplied()?.is_some();
+        // Checked again on what another instance saved, it may have rotated
+        let rotated = Cell::new(false);
+        let retired = Cell::new(0);
+        let keys = update(*ring, |keys| {
+            let changed = init(*ring, keys)?;
+            rotated.set(!supplied && interval > 0 && now - keys.current().created >= interval);
+            if rotated.get() {
+                keys.keys.push(RingKey::new(*ring, ring.generate()?, now));
+            }
+            retired.set(keys.retire(now, config.retire_after as i64));
+            Ok(changed || rotated.get() || retired.get() > 0)
+        })?;
+        if rotated.get() {
+            info!(
+                "Rotated the {:?} keys, now signing with {}",
+                ring,
+                keys.current().id
+            );
+        } else if retired.get() > 0 {
+            info!("Retired old {:?} keys", ring);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retires_replaced_keys() {
+        let mut keys = KeyRing {
+            keys: vec![
+                RingKey::new(Ring::Signing, vec![1], 0),
+                RingKey::new(Ring::Signing, vec![2], 100),
+                RingKey::new(Ring::Signing, vec![3], 200),
+            ],
+        };
+        assert_eq!(keys.retire(150, 100), 0);
+        assert_eq!(keys.retire(250, 100), 1);
+        assert_eq!(keys.keys.len(), 2);
+        assert_eq!(keys.retire(1000, 100), 1);
+        assert_eq!(keys.current().key, vec![3]);
+        assert_eq!(keys.retire(5000, 100), 0);
+    }
+
+    #[test]
+    fn keeps_supplied_keys_out_of_the_store() -> Result<()> {
+        let supplied = vec![7u8; 32];
+        let id = Ring::Signing.key_id(&supplied);
+        SUPPLIED.write().insert(id.to_string(), supplied.to_vec());
+        let mut keys = KeyRing {
+            keys: vec![
+                RingKey::new(Ring::Signing, vec![1; 32], 0),
+                RingKey::new(Ring::Signing, supplied.to_vec(), 100),
+            ],
+        };
+        assert!(init(Ring::Signing, &mut keys)?);
+        let stored = serde_json::to_value(&keys)?;
+        assert!(stored["keys"][0].get("key").is_some());
+        assert!(stored["keys"][1].get("key").is_none());
+        assert_eq!(stored["keys"][1]["id"], id.as_str());
+
+        let keys = with_supplied(Ring::Signing, serde_json::from_value(stored)?)?;
+        assert_eq!(keys.current().key, supplied);
+        let unknown = KeyRing {
+            keys: vec![RingKey {
+                id: "unknown".into(),
+                key: vec![],
+                created: 0,
+            }],
+        };
+        assert!(with_supplied(Ring::Signing, unknown).is_err());
+        Ok(())
+    }
+}
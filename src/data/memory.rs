@@ -0,0 +1,69 @@
+use {
+    super::{Entries, Storage},
+    crate::Result,
+    parking_lot::RwLock,
+    std::collections::{BTreeMap, HashMap},
+};
+
+/// Storage that lives and dies with the process
+#[derive(Default)]
+pub struct MemoryStore {
+    trees: RwLock<HashMap<String, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl Storage for MemoryStore {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .trees
+            .read()
+            .get(tree)
+            .and_then(|t| t.get(key))
+            .cloned())
+    }
+
+    fn insert(&self, tree: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.trees
+            .write()
+            .entry(tree.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.trees.write().get_mut(tree).and_then(|t| t.remove(key)))
+    }
+
+    fn entries(&self, tree: &str) -> Result<Entries> {
+        Ok(self
+            .trees
+            .read()
+            .get(tree)
+            .map(|t| t.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    fn compare_and_swap(
+        &self,
+        tree: &str,
+        key: &str,
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        let mut trees = self.trees.write();
+        let tree = trees.entry(tree.to_string()).or_default();
+        if tree.get(key).map(|v| v.as_slice()) != old {
+            return Ok(false);
+        }
+        tree.insert(key.to_string(), new.to_vec());
+        Ok(true)
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        Ok(self.trees.read().keys().cloned().collect())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
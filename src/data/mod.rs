@@ -1,53 +1,214 @@
+mod envelope;
+pub mod keyring;
+mod memory;
+mod postgres;
+mod secrets;
+mod sled_store;
+mod sql;
+mod sqlite;
+
 use {
-    crate::*,
+    self::postgres::Postgres,
+    crate::{args::StorageBackend, *},
     hmac::crypto_mac::Mac,
     hmac::Hmac,
+    memory::MemoryStore,
     rand::{rngs::OsRng, RngCore},
     serde::{de::DeserializeOwned, Serialize},
     sha2::Sha256,
-    sled::Db,
+    sled_store::SledStore,
+    sql::SqlStore,
+    sqlite::Sqlite,
 };
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Keys and values of a tree, in key order
+pub type Entries = Vec<(String, Vec<u8>)>;
+
 lazy_static! {
-    static ref DB: Db = {
-        sled::Config::default()
-            .path(".oauth.dat")
-            .flush_every_ms(Some(1000))
-            .open()
-            .unwrap()
+    static ref STORE: Result<Box<dyn Storage>> = open();
+    static ref KEY: Vec<u8> = inner_key().unwrap();
+}
+
+/// Engine underneath `Persistable`, holding values by key in named trees
+pub trait Storage: Send + Sync {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>>;
+    fn insert(&self, tree: &str, key: &str, value: &[u8]) -> Result<()>;
+    /// Removes the key, returning what it held
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>>;
+    fn entries(&self, tree: &str) -> Result<Entries>;
+    /// Sets the key to `new` only if it still holds `old`, `None` for unset,
+    /// returning whether it did. Only for trees that don't hold records.
+    fn compare_and_swap(
+        &self,
+        tree: &str,
+        key: &str,
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool>;
+    /// Names of the trees holding values
+    fn trees(&self) -> Result<Vec<String>>;
+    fn flush(&self) -> Result<()>;
+}
+
+fn open() -> Result<Box<dyn Storage>> {
+    let config = &CONFIG.storage;
+    let store: Box<dyn Storage> = match config.backend {
+        StorageBackend::Sled => Box::new(SledStore::open(&config.db_path)?),
+        StorageBackend::Sqlite => Box::new(SqlStore::new(pool(|| {
+            Ok(Box::new(Sqlite::open(&config.db_path)?))
+        })?)?),
+        StorageBackend::Postgres => {
+            let url = config.db_url.as_ref().ok_or_else(|| {
+                Error::Service("--storage-url is needed for the postgres backend".into())
+            })?;
+            let tls = postgres::tls(config.db_ca_file.as_deref())?;
+            Box::new(SqlStore::new(pool(|| {
+                Ok(Box::new(Postgres::connect(url, tls.clone())?))
+            })?)?)
+        }
+        StorageBackend::Memory => Box::new(MemoryStore::default()),
     };
-    static ref KEY: Vec<u8> = { inner_key().unwrap() };
+    if envelope::enabled()? {
+        match config.backend {
+            StorageBackend::Sled | StorageBackend::Memory => info!("Encrypting stored records"),
+            _ => {
+                return Err(Error::Service(
+                    "Records are only encrypted in sled, rely on the encryption of the SQL database instead".into(),
+                ))
+            }
+        }
+    }
+    info!("Using {:?} storage", config.backend);
+    Ok(store)
+}
+
+/// Connections for the SQL backends, at least one
+fn pool(connect: impl Fn() -> Result<Box<dyn sql::Sql>>) -> Result<Vec<Box<dyn sql::Sql>>> {
+    (0..CONFIG.storage.db_pool_size.max(1))
+        .map(|_| connect())
+        .collect()
+}
+
+/// Wraps the data keys of the records encrypted with the previous key with
+/// the current one and encrypts those saved in the clear, returning how many
+/// there were
+pub fn reencrypt() -> Result<u64> {
+    if !envelope::enabled()? {
+        return Err(Error::Service(
+            "Re-encrypting needs --storage-encryption-key".into(),
+        ));
+    }
+    let mut count = 0;
+    for tree in store()?.trees()?.into_iter().filter(|t| is_sealed_tree(t)) {
+        for (key, value) in store()?.entries(&tree)? {
+            let value = match envelope::rewrap(&value)? {
+                Some(value) => value,
+                None if !envelope::is_sealed(&value) => envelope::seal(&tree, &key, value)?,
+                None => continue,
+            };
+            store()?.insert(&tree, &key, &value)?;
+            count += 1;
+        }
+    }
+    store()?.flush()?;
+    Ok(count)
+}
+
+/// Whether the values of a tree are sealed, index trees only hold record IDs
+/// and the migrations tree when each one ran
+fn is_sealed_tree(tree: &str) -> bool {
+    tree != MIGRATIONS_TREE && !tree.contains('.')
+}
+
+/// Value of the server tree, such as a key
+fn server_value(field: &str) -> Result<Option<Vec<u8>>> {
+    match store()?.get(SERVER_TREE, field)? {
+        Some(value) => Ok(Some(envelope::open(SERVER_TREE, field, value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Changes a value of the server tree with `f`, which returns `None` to
+/// leave it as it is. When another instance changed the value in the
+/// meantime, `f` is given the new one and tried again. Returns what the
+/// value ended up as.
+fn update_server_value<F>(field: &str, f: F) -> Result<Option<Vec<u8>>>
+where
+    F: Fn(Option<&[u8]>) -> Result<Option<Vec<u8>>>,
+{
+    loop {
+        let stored = store()?.get(SERVER_TREE, field)?;
+        let current = match &stored {
+            Some(value) => Some(envelope::open(SERVER_TREE, field, value.clone())?),
+            None => None,
+        };
+        let value = match f(current.as_deref())? {
+            Some(value) => value,
+            None => return Ok(current),
+        };
+        let sealed = envelope::seal(SERVER_TREE, field, value.clone())?;
+        if store()?.compare_and_swap(SERVER_TREE, field, stored.as_deref(), &sealed)? {
+            return Ok(Some(value));
+        }
+    }
+}
+
+/// The configured storage, opened on first use
+pub fn store() -> Result<&'static dyn Storage> {
+    STORE.as_ref().map(|s| s.as_ref()).map_err(|e| e.clone())
 }
 
 const SERVER_TREE: &str = "server";
+const MIGRATIONS_TREE: &str = "migrations";
 const KEY_FIELD: &str = "signing";
+const JWT_KEY_FIELD: &str = "jwt";
 
+/// Key secrets are hashed with. It's never rotated as the stored hashes would
+/// stop matching, signatures use the `keyring` instead.
 pub fn key() -> Result<Vec<u8>> {
     Ok(KEY.clone())
 }
 
+/// Keyed hash a secret is stored and looked up as, so that reading the
+/// database isn't enough to use it
+pub fn hash_token(token: &str) -> Result<String> {
+    let mut hm = HmacSha256::new_varkey(&key()?)?;
+    hm.input(token.as_bytes());
+    Ok(base64::encode(&hm.result().code()))
+}
+
+/// Runs a one-off change of the stored data, unless it ran before
+pub fn migrate<F: FnOnce() -> Result<()>>(name: &str, f: F) -> Result<()> {
+    if store()?.get(MIGRATIONS_TREE, name)?.is_some() {
+        return Ok(());
+    }
+    info!("Migrating stored data: {}", name);
+    f()?;
+    let done = chrono::Utc::now().to_rfc3339();
+    store()?.insert(MIGRATIONS_TREE, name, done.as_bytes())
+}
+
 pub fn clean() -> Result<()> {
-    DB.flush()?;
-    Ok(())
+    store()?.flush()
 }
 
 fn inner_key() -> Result<Vec<u8>> {
-    let tree = DB.open_tree(SERVER_TREE)?;
-    let k = match tree.get(KEY_FIELD)? {
-        Some(k) => k.to_vec(),
+    if let Some(k) = keyring::Ring::Signing.supplied()? {
+        return Ok(k);
+    }
+    let k = update_server_value(KEY_FIELD, |k| match k {
+        Some(_) => Ok(None),
         None => {
             warn!("Key not found, generating new key");
             let mut key = [0u8; 32];
             OsRng.fill_bytes(&mut key);
-            let key = key.to_vec();
-            tree.insert(KEY_FIELD, key.clone())?;
-            key
+            Ok(Some(key.to_vec()))
         }
-    };
+    })?;
 
-    Ok(k)
+    Ok(k.unwrap_or_default())
 }
 
 pub trait Persistable: DeserializeOwned + Serialize + std::fmt::Debug
@@ -56,9 +217,76 @@ where
 {
     type ID;
 
+    /// Secondary indexes kept for the records, each in a tree of its own
+    /// mapping the values to the ID of the record holding them
+    const INDEXES: &'static [&'static str] = &[];
+
     fn tree_name() -> &'static str;
     fn id(&self) -> Self::ID;
 
+    /// Values the record is found by in one of `INDEXES`
+    fn index_values(&self, _index: &str) -> Vec<String> {
+        vec![]
+    }
+
+    fn index_tree(index: &str) -> String {
+        format!("{}.{}", Self::tree_name(), index)
+    }
+
+    /// Drops the index entries of `old` the record no longer has, for it or
+    /// the record gone when `new` is `None`
+    fn unindex(old: &Self, new: Option<&Self>) -> Result<()> {
+        let id = old.id().to_string();
+        for index in Self::INDEXES {
+            let kept = new.map(|n| n.index_values(index)).unwrap_or_default();
+            for value in old.index_values(index) {
+                let tree = Self::index_tree(index);
+                if !kept.contains(&value)
+                    && store()?.get(&tree, &value)? == Some(id.clone().into_bytes())
+                {
+                    store()?.remove(&tree, &value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self) -> Result<()> {
+        let id = self.id().to_string();
+        for index in Self::INDEXES {
+            for value in self.index_values(index) {
+                store()?.insert(&Self::index_tree(index), &value, id.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The record holding `value` in an index
+    fn find_by(index: &str, value: &str) -> Result<Option<Self>>
+    where
+        Self::ID: From<String>,
+    {
+        let id = match store()?.get(&Self::index_tree(index), value)? {
+            Some(id) => String::from_utf8_lossy(&id).into_owned(),
+            None => return Ok(None),
+        };
+        // Entries of records that failed to save or delete completely are stale
+        Ok(Self::get(id.into())?.filter(|o| o.index_values(index).iter().any(|v| v == value)))
+    }
+
+    /// Indexes every record, for those saved before there were indexes.
+    /// Entries already there are kept, `find_by` skips any that are stale.
+    fn reindex() -> Result<u64> {
+        if Self::INDEXES.is_empty() {
+            return Ok(0);
+        }
+        let records = Self::find_all(|_| true)?;
+        for record in records.iter() {
+            record.index()?;
+        }
+        Ok(records.len() as u64)
+    }
+
     fn gen_id() -> String {
         let mut key = [0u8; 32];
         OsRng.fill_bytes(&mut key);
@@ -66,20 +294,21 @@ where
     }
 
     fn get(id: Self::ID) -> Result<Option<Self>> {
-        let tree = DB.open_tree(Self::tree_name())?;
         let key = id.to_string();
-        let data: Option<Self> = tree
-            .get(&key)?
-            .map(|i| serde_json::from_slice(i.as_ref()).unwrap());
+        let data: Option<Self> = match store()?.get(Self::tree_name(), &key)? {
+            Some(i) => {
+                Some(serde_json::from_slice(&envelope::open(Self::tree_name(), &key, i)?).unwrap())
+            }
+            None => None,
+        };
         debug!("Retrieved: {:?}", data);
         Ok(data)
     }
 
     fn find<F: Fn(&Self) -> bool>(q: F) -> Result<Option<Self>> {
-        let tree = DB.open_tree(Self::tree_name())?;
-        for val in tree.iter() {
-            let (_, v) = val?;
-            let obj = serde_json::from_slice(v.as_ref());
+        for (k, v) in store()?.entries(Self::tree_name())? {
+            let v = envelope::open(Self::tree_name(), &k, v)?;
+            let obj = serde_json::from_slice(&v);
             if let Ok(o) = obj {
                 if q(&o) {
                     debug!("Object found: {:?}", o);
@@ -92,11 +321,10 @@ where
     }
 
     fn find_all<F: Fn(&Self) -> bool>(q: F) -> Result<Vec<Self>> {
-        let tree = DB.open_tree(Self::tree_name())?;
         let mut result = vec![];
-        for val in tree.iter() {
-            let (_, v) = val?;
-            let obj = serde_json::from_slice(v.as_ref());
+        for (k, v) in store()?.entries(Self::tree_name())? {
+            let v = envelope::open(Self::tree_name(), &k, v)?;
+            let obj = serde_json::from_slice(&v);
             if let Ok(o) = obj {
                 if q(&o) {
                     result.push(o);
@@ -107,67 +335,153 @@ where
     }
 
     fn save(&self) -> Result<()> {
-        let tree = DB.open_tree(Self::tree_name())?;
         let id = self.id();
         let key = id.to_string();
-        let val = serde_json::to_vec(self)?;
-        tree.insert(key, val)?;
-        tree.flush()?;
+        let val = envelope::seal(Self::tree_name(), &key, serde_json::to_vec(self)?)?;
+        let old = match store()?.get(Self::tree_name(), &key)? {
+            Some(v) if !Self::INDEXES.is_empty() => {
+                serde_json::from_slice::<Self>(&envelope::open(Self::tree_name(), &key, v)?).ok()
+            }
+            _ => None,
+        };
+        self.index()?;
+        store()?.insert(Self::tree_name(), &key, &val)?;
+        if let Some(old) = old {
+            Self::unindex(&old, Some(self))?;
+        }
         debug!("Saved: {:?}", self);
         Ok(())
     }
 
     fn delete(&self) -> Result<Option<Self>> {
-        let tree = DB.open_tree(Self::tree_name())?;
         let id = self.id();
         let key = id.to_string();
-        let res = tree
-            .remove(key)?
-            .map(|i| serde_json::from_slice(i.as_ref()).unwrap());
-        tree.flush()?;
+        let res: Option<Self> = match store()?.remove(Self::tree_name(), &key)? {
+            Some(i) => {
+                Some(serde_json::from_slice(&envelope::open(Self::tree_name(), &key, i)?).unwrap())
+            }
+            None => None,
+        };
+        if let Some(old) = &res {
+            Self::unindex(old, None)?;
+        }
         debug!("Deleted: {:?}", res);
         Ok(res)
     }
 
     fn delete_all<F: Fn(&Self) -> bool>(q: F) -> Result<u64> {
-        let tree = DB.open_tree(Self::tree_name())?;
         let mut count = 0;
-        for val in tree.iter() {
-            let (k, v) = val?;
-            let obj = serde_json::from_slice(v.as_ref());
+        for (k, v) in store()?.entries(Self::tree_name())? {
+            // Failing on values that don't decrypt rather than taking them for garbage
+            let v = envelope::open(Self::tree_name(), &k, v)?;
+            let obj = serde_json::from_slice(&v);
             if let Ok(o) = obj {
                 if q(&o) {
-                    tree.remove(k)?;
+                    store()?.remove(Self::tree_name(), &k)?;
+                    Self::unindex(&o, None)?;
                     count += 1;
                 }
             } else {
-                tree.remove(k)?;
+                store()?.remove(Self::tree_name(), &k)?;
                 count += 1;
             }
         }
         Ok(count)
     }
 
+    /// What signatures cover, the whole record unless fields change while
+    /// the signed token is still in use
+    fn signed_data(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Signature of the record with the newest signing key, prefixed with its ID
     fn sign(&self) -> Result<String> {
-        let data = serde_json::to_vec(self)?;
-        let key = key()?;
-        let mut hm = HmacSha256::new_varkey(&key)?;
+        let data = self.signed_data()?;
+        let keys = keyring::ring(keyring::Ring::Signing)?;
+        let current = keys.current();
+        let mut hm = HmacSha256::new_varkey(&current.key)?;
         hm.input(&data);
         let res = hm.result().code();
-        Ok(base64::encode(&res))
+        Ok(format!("{}:{}", current.id, base64::encode(&res)))
     }
 
     fn signed_token(&self) -> Result<String> {
         Ok(format!("{}.{}", self.id(), self.sign()?))
     }
 
+    /// Checks a signature with the key it names, signatures from before there
+    /// were key IDs are checked with every key that isn't retired
     fn verify(&self, code: &str) -> Result<()> {
-        let data = serde_json::to_vec(self)?;
-        let key = key()?;
-        let mut hm = HmacSha256::new_varkey(&key)?;
-        hm.input(&data);
+        let data = self.signed_data()?;
+        let (id, code) = match code.find(':') {
+            Some(i) => (Some(&code[..i]), &code[i + 1..]),
+            None => (None, code),
+        };
         let code = base64::decode(&code).unwrap_or_default();
-        hm.verify(&code)?;
+        let keys = keyring::find(keyring::Ring::Signing, |k| {
+            id.is_none() || id == Some(k.id.as_str())
+        })?;
+        if keys.is_empty() {
+            return Err(Error::Authentication("Signed with an unknown key".into()));
+        }
+        let mut result = Ok(());
+        for key in keys {
+            let mut hm = HmacSha256::new_varkey(&key.key)?;
+            hm.input(&data);
+            result = hm.verify(&code);
+            if result.is_ok() {
+                break;
+            }
+        }
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(store: &dyn Storage) -> Result<()> {
+        store.insert("things", "b", b"2")?;
+        store.insert("things", "a", b"1")?;
+        store.insert("things", "a", b"3")?;
+        store.insert("others", "a", b"4")?;
+        assert_eq!(store.get("things", "a")?, Some(b"3".to_vec()));
+        assert_eq!(
+            store.entries("things")?,
+            vec![("a".into(), b"3".to_vec()), ("b".into(), b"2".to_vec())]
+        );
+        assert_eq!(store.remove("things", "a")?, Some(b"3".to_vec()));
+        assert_eq!(store.remove("things", "a")?, None);
+        assert_eq!(store.get("others", "a")?, Some(b"4".to_vec()));
+        assert!(store.entries("nothing")?.is_empty());
+
+        assert!(store.compare_and_swap("server", "k", None, b"1")?);
+        assert!(!store.compare_and_swap("server", "k", None, b"2")?);
+        assert!(!store.compare_and_swap("server", "k", Some(b"2"), b"3")?);
+        assert!(store.compare_and_swap("server", "k", Some(b"1"), b"3")?);
+        assert!(store.compare_and_swap("others", "b", None, b"5")?);
+        assert_eq!(store.get("server", "k")?, Some(b"3".to_vec()));
+        assert_eq!(store.get("others", "b")?, Some(b"5".to_vec()));
+        store.flush()
+    }
+
+    #[test]
+    fn backends_store_by_tree() -> Result<()> {
+        round_trip(&MemoryStore::default())?;
+        round_trip(&SqlStore::new(vec![Box::new(Sqlite::in_memory()?)])?)?;
+        let dir = std::env::temp_dir().join(format!("oauth-lite-test-{}", std::process::id()));
+        round_trip(&SledStore::open(&dir)?)?;
+        std::fs::remove_dir_all(dir)?;
         Ok(())
     }
+
+    #[test]
+    fn seals_records_but_not_indexes() {
+        assert!(is_sealed_tree("sessions"));
+        assert!(is_sealed_tree(SERVER_TREE));
+        assert!(!is_sealed_tree("sessions.sid"));
+        assert!(!is_sealed_tree(MIGRATIONS_TREE));
+    }
 }
@@ -0,0 +1,123 @@
+use {
+    super::sql::{Sql, SqlValue},
+    crate::{Error, Result},
+    native_tls::{Certificate, TlsConnector},
+    postgres::{
+        types::{ToSql, Type},
+        Client, Row, Statement,
+    },
+    postgres_native_tls::MakeTlsConnector,
+    std::{fs, path::Path},
+};
+
+/// PostgreSQL database shared by several instances, reconnected when the
+/// connection drops
+pub struct Postgres {
+    url: String,
+    tls: MakeTlsConnector,
+    client: Client,
+}
+
+/// Connector for the TLS the sslmode of the URL asks for, also trusting the
+/// CA in `ca_file`
+pub fn tls(ca_file: Option<&Path>) -> Result<MakeTlsConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(file) = ca_file {
+        let pem = fs::read(file).map_err(|e| {
+            Error::Service(format!(
+                "Unable to read the database CA {} -> {}",
+                file.display(),
+                e
+            ))
+        })?;
+        builder.add_root_certificate(Certificate::from_pem(&pem)?);
+    }
+    Ok(MakeTlsConnector::new(builder.build()?))
+}
+
+impl Postgres {
+    pub fn connect(url: &str, tls: MakeTlsConnector) -> Result<Self> {
+        Ok(Postgres {
+            url: url.to_string(),
+            client: Client::connect(url, tls.clone())?,
+            tls,
+        })
+    }
+
+    fn client(&mut self) -> Result<&mut Client> {
+        if self.client.is_closed() {
+            warn!("Lost the database connection, reconnecting");
+            self.client = Client::connect(&self.url, self.tls.clone())?;
+        }
+        Ok(&mut self.client)
+    }
+}
+
+/// Parameters with NULLs of the type the statement expects, as the types
+/// have to match exactly
+fn params(stmt: &Statement, params: &[SqlValue]) -> Vec<Box<dyn ToSql + Sync>> {
+    params
+        .iter()
+        .zip(stmt.params())
+        .map(|(value, ty)| -> Box<dyn ToSql + Sync> {
+            match value {
+                SqlValue::Null if *ty == Type::INT8 => Box::new(None::<i64>),
+                SqlValue::Null if *ty == Type::BYTEA => Box::new(None::<Vec<u8>>),
+                SqlValue::Null => Box::new(None::<String>),
+                SqlValue::Int(i) => Box::new(*i),
+                SqlValue::Text(s) => Box::new(s.to_string()),
+                SqlValue::Bytes(b) => Box::new(b.to_vec()),
+            }
+        })
+        .collect()
+}
+
+fn values(row: &Row) -> Vec<SqlValue> {
+    row.columns()
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            let value = match *column.type_() {
+                Type::INT8 => row.get::<_, Option<i64>>(i).map(SqlValue::Int),
+                Type::BYTEA => row.get::<_, Option<Vec<u8>>>(i).map(SqlValue::Bytes),
+                _ => row.get::<_, Option<String>>(i).map(SqlValue::Text),
+            };
+            value.unwrap_or(SqlValue::Null)
+        })
+        .collect()
+}
+
+impl Sql for Postgres {
+    fn execute_batch(&mut self, sql: &str) -> Result<()> {
+        self.client()?.batch_execute(sql)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> Result<u64> {
+        let client = self.client()?;
+        let stmt = client.prepare(sql)?;
+        let params = self::params(&stmt, params);
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+        Ok(client.execute(&stmt, &params)?)
+    }
+
+    fn query(&mut self, sql: &str, params: &[SqlValue]) -> Result<Vec<Vec<SqlValue>>> {
+        let client = self.client()?;
+        let stmt = client.prepare(sql)?;
+        let params = self::params(&stmt, params);
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+        Ok(client.query(&stmt, &params)?.iter().map(values).collect())
+    }
+
+    fn transaction(&mut self, statements: &[(String, Vec<SqlValue>)]) -> Result<()> {
+        let mut tx = self.client()?.transaction()?;
+        for (sql, params) in statements {
+            let stmt = tx.prepare(sql)?;
+            let params = self::params(&stmt, params);
+            let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+            tx.execute(&stmt, &params)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
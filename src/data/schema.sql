@@ -0,0 +1,87 @@
+-- Shared by SQLite and PostgreSQL, keep to the types and syntax both understand.
+-- Timestamps of chrono values are RFC 3339 text, session times are milliseconds.
+-- This is the first version of the schema, later changes are migrations in sql.rs.
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    "user" TEXT,
+    pending TEXT,
+    mfa BIGINT NOT NULL DEFAULT 0,
+    created BIGINT NOT NULL DEFAULT 0,
+    ts BIGINT NOT NULL,
+    ip TEXT
+);
+CREATE INDEX IF NOT EXISTS sessions_ts ON sessions (ts);
+CREATE INDEX IF NOT EXISTS sessions_ip ON sessions (ip);
+
+CREATE TABLE IF NOT EXISTS session_clients (
+    session_id TEXT NOT NULL,
+    seq BIGINT NOT NULL,
+    client_id TEXT NOT NULL,
+    PRIMARY KEY (session_id, seq)
+);
+CREATE INDEX IF NOT EXISTS session_clients_client ON session_clients (client_id);
+
+CREATE TABLE IF NOT EXISTS domain_sessions (
+    id TEXT PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    domain TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS domain_sessions_session ON domain_sessions (session_id);
+
+CREATE TABLE IF NOT EXISTS handoffs (
+    id TEXT PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    rd TEXT NOT NULL,
+    exp BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS handoffs_exp ON handoffs (exp);
+
+CREATE TABLE IF NOT EXISTS grants (
+    owner_id TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    "until" TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS grants_client ON grants (client_id);
+
+-- Codes and tokens of a session, the session id is the owner
+CREATE TABLE IF NOT EXISTS tokens (
+    owner_id TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS authorization_codes (
+    owner_id TEXT NOT NULL,
+    seq BIGINT NOT NULL,
+    code TEXT NOT NULL,
+    expires TEXT NOT NULL,
+    PRIMARY KEY (owner_id, seq)
+);
+CREATE INDEX IF NOT EXISTS authorization_codes_code ON authorization_codes (code);
+
+CREATE TABLE IF NOT EXISTS access_tokens (
+    owner_id TEXT NOT NULL,
+    seq BIGINT NOT NULL,
+    token TEXT NOT NULL,
+    refresh TEXT NOT NULL,
+    expires TEXT NOT NULL,
+    PRIMARY KEY (owner_id, seq)
+);
+CREATE INDEX IF NOT EXISTS access_tokens_token ON access_tokens (token);
+CREATE INDEX IF NOT EXISTS access_tokens_refresh ON access_tokens (refresh);
+
+-- Cookie and JWT signing keys
+CREATE TABLE IF NOT EXISTS server_keys (
+    name TEXT PRIMARY KEY,
+    value BYTEA NOT NULL
+);
+
+-- Everything else, by tree
+CREATE TABLE IF NOT EXISTS entries (
+    tree TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value BYTEA NOT NULL,
+    PRIMARY KEY (tree, key)
+);
@@ -0,0 +1,79 @@
+use {
+    super::{Entries, Storage},
+    crate::Result,
+    sled::Db,
+    std::path::Path,
+};
+
+pub struct SledStore {
+    db: Db,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::Config::default()
+            .path(path)
+            .flush_every_ms(Some(1000))
+            .open()?;
+        Ok(SledStore { db })
+    }
+}
+
+impl Storage for SledStore {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let tree = self.db.open_tree(tree)?;
+        Ok(tree.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &str, value: &[u8]) -> Result<()> {
+        let tree = self.db.open_tree(tree)?;
+        tree.insert(key, value)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let tree = self.db.open_tree(tree)?;
+        let old = tree.remove(key)?.map(|v| v.to_vec());
+        tree.flush()?;
+        Ok(old)
+    }
+
+    fn entries(&self, tree: &str) -> Result<Entries> {
+        let tree = self.db.open_tree(tree)?;
+        let mut entries = vec![];
+        for entry in tree.iter() {
+            let (k, v) = entry?;
+            entries.push((String::from_utf8_lossy(&k).into_owned(), v.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn compare_and_swap(
+        &self,
+        tree: &str,
+        key: &str,
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        let tree = self.db.open_tree(tree)?;
+        let swapped = tree.compare_and_swap(key, old, Some(new))?.is_ok();
+        tree.flush()?;
+        Ok(swapped)
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        Ok(self
+            .db
+            .tree_names()
+            .iter()
+            .map(|n| String::from_utf8_lossy(n).into_owned())
+            .filter(|n| n != "__sled__default")
+            .collect())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
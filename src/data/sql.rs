@@ -0,0 +1,807 @@
+use {
+    super::{Entries, Storage},
+    crate::{Error, Result},
+    parking_lot::{Mutex, MutexGuard},
+    serde_json::{Map, Value},
+    std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+};
+
+/// Changes to the schema by version, each applied once in a transaction of
+/// its own. Only ever append to these, instances may have run any of them.
+const MIGRATIONS: &[&str] = &[
+    include_str!("schema.sql"),
+    "ALTER TABLE sessions ADD COLUMN slot BIGINT;",
+];
+
+/// Value bound to or read from a statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Int(i64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+/// Connection of a SQL database, statements use `$1`, `$2`... placeholders
+pub trait Sql: Send {
+    fn execute_batch(&mut self, sql: &str) -> Result<()>;
+    /// Runs a statement, returning how many rows it changed
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> Result<u64>;
+    fn query(&mut self, sql: &str, params: &[SqlValue]) -> Result<Vec<Vec<SqlValue>>>;
+    /// Runs the statements in a single transaction
+    fn transaction(&mut self, statements: &[(String, Vec<SqlValue>)]) -> Result<()>;
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Int,
+    Bool,
+    /// Structured values, such as the user and their attributes
+    Json,
+}
+
+/// Field of a record kept in a column of the same name
+struct Column(&'static str, Kind);
+
+/// List field of a record kept in rows of its own table, list items that are
+/// tuples spread over several columns
+struct Child {
+    field: &'static str,
+    table: &'static str,
+    parent: &'static str,
+    columns: &'static [Column],
+}
+
+/// Table a tree is kept in, keyed by the column of its ID field
+struct Table {
+    tree: &'static str,
+    table: &'static str,
+    key: &'static str,
+    columns: &'static [Column],
+    children: &'static [Child],
+}
+
+const TABLES: &[Table] = &[
+    Table {
+        tree: "sessions",
+        table: "sessions",
+        key: "id",
+        columns: &[
+            Column("user", Kind::Json),
+            Column("pending", Kind::Json),
+            Column("mfa", Kind::Bool),
+            Column("created", Kind::Int),
+            Column("ts", Kind::Int),
+            Column("ip", Kind::Text),
+            Column("slot", Kind::Int),
+        ],
+        children: &[Child {
+            field: "clients",
+            table: "session_clients",
+            parent: "session_id",
+            columns: &[Column("client_id", Kind::Text)],
+        }],
+    },
+    Table {
+        tree: "domain_sessions",
+        table: "domain_sessions",
+        key: "id",
+        columns: &[
+            Column("session_id", Kind::Text),
+            Column("domain", Kind::Text),
+        ],
+        children: &[],
+    },
+    Table {
+        tree: "handoffs",
+        table: "handoffs",
+        key: "id",
+        columns: &[
+            Column("session_id", Kind::Text),
+            Column("domain", Kind::Text),
+            Column("rd", Kind::Text),
+            Column("exp", Kind::Int),
+        ],
+        children: &[],
+    },
+    Table {
+        tree: "grants",
+        table: "grants",
+        key: "owner_id",
+        columns: &[
+            Column("client_id", Kind::Text),
+            Column("scope", Kind::Text),
+            Column("redirect_uri", Kind::Text),
+            Column("until", Kind::Text),
+        ],
+        children: &[],
+    },
+    Table {
+        tree: "tokens",
+        table: "tokens",
+        key: "owner_id",
+        columns: &[],
+        children: &[
+            Child {
+                field: "authorizations",
+                table: "authorization_codes",
+                parent: "owner_id",
+                columns: &[Column("code", Kind::Text), Column("expires", Kind::Text)],
+            },
+            Child {
+                field: "tokens",
+                table: "access_tokens",
+                parent: "owner_id",
+                columns: &[
+                    Column("token", Kind::Text),
+                    Column("refresh", Kind::Text),
+                    Column("expires", Kind::Text),
+                ],
+            },
+        ],
+    },
+];
+
+/// Trees holding raw bytes rather than records
+const RAW_TABLES: &[(&str, &str)] = &[("server", "server_keys")];
+
+fn names(columns: &[Column]) -> Vec<String> {
+    columns.iter().map(|c| format!("\"{}\"", c.0)).collect()
+}
+
+fn placeholders(from: usize, count: usize) -> String {
+    (from..from + count)
+        .map(|i| format!("${}", i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn to_sql(value: &Value, kind: Kind) -> Result<SqlValue> {
+    Ok(match (value, kind) {
+        (Value::Null, _) => SqlValue::Null,
+        (Value::Bool(b), Kind::Bool) => SqlValue::Int(*b as i64),
+        (Value::Number(n), Kind::Int) => SqlValue::Int(n.as_i64().ok_or_else(|| {
+            Error::Service(format!("Number out of range for the database -> {}", n))
+        })?),
+        (Value::String(s), Kind::Text) => SqlValue::Text(s.to_string()),
+        (value, Kind::Json) => SqlValue::Text(serde_json::to_string(value)?),
+        (value, _) => {
+            return Err(Error::Service(format!(
+                "Unexpected value for the database -> {}",
+                value
+            )))
+        }
+    })
+}
+
+fn from_sql(value: SqlValue, kind: Kind) -> Result<Value> {
+    Ok(match (value, kind) {
+        (SqlValue::Null, _) => Value::Null,
+        (SqlValue::Int(i), Kind::Bool) => Value::Bool(i != 0),
+        (SqlValue::Int(i), _) => Value::from(i),
+        (SqlValue::Text(s), Kind::Json) => serde_json::from_str(&s)?,
+        (SqlValue::Text(s), _) => Value::String(s),
+        (SqlValue::Bytes(_), _) => {
+            return Err(Error::Service("Unexpected bytes from the database".into()))
+        }
+    })
+}
+
+fn text(value: SqlValue) -> Result<String> {
+    match value {
+        SqlValue::Text(s) => Ok(s),
+        other => Err(Error::Service(format!(
+            "Expected text from the database, found {:?}",
+            other
+        ))),
+    }
+}
+
+fn bytes(value: SqlValue) -> Result<Vec<u8>> {
+    match value {
+        SqlValue::Bytes(b) => Ok(b),
+        SqlValue::Text(s) => Ok(s.into_bytes()),
+        other => Err(Error::Service(format!(
+            "Expected bytes from the database, found {:?}",
+            other
+        ))),
+    }
+}
+
+impl Child {
+    fn item(&self, row: Vec<SqlValue>) -> Result<Value> {
+        let mut values = row
+            .into_iter()
+            .zip(self.columns)
+            .map(|(v, c)| from_sql(v, c.1))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(match values.len() {
+            1 => values.remove(0),
+            _ => Value::Array(values),
+        })
+    }
+
+    /// Items of the list field for every record, by key
+    fn load(&self, sql: &mut dyn Sql, key: Option<&str>) -> Result<HashMap<String, Vec<Value>>> {
+        let mut query = format!(
+            "SELECT \"{}\", {} FROM {}",
+            self.parent,
+            names(self.columns).join(", "),
+            self.table
+        );
+        let mut params = vec![];
+        if let Some(key) = key {
+            query.push_str(&format!(" WHERE \"{}\" = $1", self.parent));
+            params.push(SqlValue::Text(key.to_string()));
+        }
+        query.push_str(&format!(" ORDER BY \"{}\", seq", self.parent));
+        let mut items: HashMap<String, Vec<Value>> = HashMap::new();
+        for mut row in sql.query(&query, &params)? {
+            let parent = text(row.remove(0))?;
+            items.entry(parent).or_default().push(self.item(row)?);
+        }
+        Ok(items)
+    }
+
+    fn rows(&self, key: &str, list: &Value) -> Result<Vec<(String, Vec<SqlValue>)>> {
+        let insert = format!(
+            "INSERT INTO {} (\"{}\", seq, {}) VALUES ({})",
+            self.table,
+            self.parent,
+            names(self.columns).join(", "),
+            placeholders(1, self.columns.len() + 2)
+        );
+        let items = match list {
+            Value::Array(items) => items.as_slice(),
+            _ => &[],
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(seq, item)| {
+                let values = match (item, self.columns.len()) {
+                    (Value::Array(values), n) if n > 1 => values.as_slice(),
+                    (item, _) => std::slice::from_ref(item),
+                };
+                let mut params = vec![SqlValue::Text(key.to_string()), SqlValue::Int(seq as i64)];
+                for (value, column) in values.iter().zip(self.columns) {
+                    params.push(to_sql(value, column.1)?);
+                }
+                Ok((insert.clone(), params))
+            })
+            .collect()
+    }
+
+    fn delete(&self, key: &str) -> (String, Vec<SqlValue>) {
+        (
+            format!("DELETE FROM {} WHERE \"{}\" = $1", self.table, self.parent),
+            vec![SqlValue::Text(key.to_string())],
+        )
+    }
+}
+
+impl Table {
+    fn select(&self) -> String {
+        let mut columns = vec![format!("\"{}\"", self.key)];
+        columns.extend(names(self.columns));
+        format!("SELECT {} FROM {}", columns.join(", "), self.table)
+    }
+
+    fn load(&self, sql: &mut dyn Sql, key: Option<&str>) -> Result<Entries> {
+        let mut query = self.select();
+        let mut params = vec![];
+        match key {
+            Some(key) => {
+                query.push_str(&format!(" WHERE \"{}\" = $1", self.key));
+                params.push(SqlValue::Text(key.to_string()));
+            }
+            None => query.push_str(&format!(" ORDER BY \"{}\"", self.key)),
+        }
+        let rows = sql.query(&query, &params)?;
+        if rows.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut children = self
+            .children
+            .iter()
+            .map(|c| c.load(sql, key))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut entries = vec![];
+        for row in rows {
+            let mut row = row.into_iter();
+            let id = text(row.next().unwrap_or(SqlValue::Null))?;
+            let mut record = Map::new();
+            record.insert(self.key.into(), Value::String(id.to_string()));
+            for (value, column) in row.zip(self.columns) {
+                record.insert(column.0.into(), from_sql(value, column.1)?);
+            }
+            for (child, items) in self.children.iter().zip(children.iter_mut()) {
+                let items = items.remove(&id).unwrap_or_default();
+                record.insert(child.field.into(), Value::Array(items));
+            }
+            entries.push((id, serde_json::to_vec(&record)?));
+        }
+        Ok(entries)
+    }
+
+    fn save(&self, sql: &mut dyn Sql, key: &str, value: &[u8]) -> Result<()> {
+        let record: Map<String, Value> = serde_json::from_slice(value)?;
+        // Catches a field added to the record without a column to keep it in
+        if let Some(field) = record.keys().find(|f| {
+            *f != self.key
+                && !self.columns.iter().any(|c| c.0 == *f)
+                && !self.children.iter().any(|c| c.field == *f)
+        }) {
+            return Err(Error::Service(format!(
+                "No column for {} of {}",
+                field, self.tree
+            )));
+        }
+        let mut params = vec![SqlValue::Text(key.to_string())];
+        for column in self.columns {
+            params.push(to_sql(
+                record.get(column.0).unwrap_or(&Value::Null),
+                column.1,
+            )?);
+        }
+        let mut columns = vec![format!("\"{}\"", self.key)];
+        columns.extend(names(self.columns));
+        let update = match self.columns.len() {
+            0 => "NOTHING".to_string(),
+            _ => format!(
+                "UPDATE SET {}",
+                names(self.columns)
+                    .iter()
+                    .map(|c| format!("{} = excluded.{}", c, c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut statements = vec![(
+            format!(
+                "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT (\"{}\") DO {}",
+                self.table,
+                columns.join(", "),
+                placeholders(1, columns.len()),
+                self.key,
+                update
+            ),
+            params,
+        )];
+        for child in self.children {
+            statements.push(child.delete(key));
+            let list = record.get(child.field).unwrap_or(&Value::Null);
+            statements.extend(child.rows(key, list)?);
+        }
+        sql.transaction(&statements)
+    }
+
+    fn remove(&self, sql: &mut dyn Sql, key: &str) -> Result<Option<Vec<u8>>> {
+        let old = self.load(sql, Some(key))?.pop().map(|(_, v)| v);
+        if old.is_some() {
+            let mut statements: Vec<_> = self.children.iter().map(|c| c.delete(key)).collect();
+            statements.push((
+                format!("DELETE FROM {} WHERE \"{}\" = $1", self.table, self.key),
+                vec![SqlValue::Text(key.to_string())],
+            ));
+            sql.transaction(&statements)?;
+        }
+        Ok(old)
+    }
+}
+
+/// Where a tree is kept, the raw ones in a key and value table
+enum Layout {
+    Table(&'static Table),
+    Raw {
+        table: &'static str,
+        key: &'static str,
+        tree: Option<String>,
+    },
+}
+
+impl Layout {
+    fn of(tree: &str) -> Self {
+        if let Some(table) = TABLES.iter().find(|t| t.tree == tree) {
+            return Layout::Table(table);
+        }
+        match RAW_TABLES.iter().find(|(t, _)| *t == tree) {
+            Some((_, table)) => Layout::Raw {
+                table,
+                key: "name",
+                tree: None,
+            },
+            None => Layout::Raw {
+                table: "entries",
+                key: "key",
+                tree: Some(tree.to_string()),
+            },
+        }
+    }
+
+    /// WHERE clause picking the rows of a raw tree, or just the one of a key,
+    /// its parameters go after those of the statement so far
+    fn filter(
+        tree: &Option<String>,
+        column: &str,
+        key: Option<&str>,
+        mut params: Vec<SqlValue>,
+    ) -> (String, Vec<SqlValue>) {
+        let mut clauses = vec![];
+        if let Some(tree) = tree {
+            params.push(SqlValue::Text(tree.to_string()));
+            clauses.push(format!("tree = ${}", params.len()));
+        }
+        if let Some(key) = key {
+            params.push(SqlValue::Text(key.to_string()));
+            clauses.push(format!("\"{}\" = ${}", column, params.len()));
+        }
+        match clauses.len() {
+            0 => (String::new(), params),
+            _ => (format!(" WHERE {}", clauses.join(" AND ")), params),
+        }
+    }
+}
+
+/// Statements of a migration, without the comments
+fn statements(migration: &str) -> Vec<(String, Vec<SqlValue>)> {
+    migration
+        .lines()
+        .filter(|l| !l.trim_start().starts_with("--"))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| (s.to_string(), vec![]))
+        .collect()
+}
+
+/// Latest migration applied to the database, 0 for none
+fn schema_version(sql: &mut dyn Sql) -> Result<i64> {
+    let row = sql
+        .query("SELECT MAX(version) FROM schema_version", &[])?
+        .pop();
+    match row.and_then(|mut row| row.pop()) {
+        Some(SqlValue::Int(version)) => Ok(version),
+        _ => Ok(0),
+    }
+}
+
+/// Brings the schema up to date. Databases made before there were versions
+/// get the first one again, its statements only create what is missing.
+fn migrate(sql: &mut dyn Sql) -> Result<()> {
+    sql.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version BIGINT PRIMARY KEY)")?;
+    for (i, migration) in MIGRATIONS.iter().enumerate() {
+        let version = i as i64 + 1;
+        if schema_version(sql)? >= version {
+            continue;
+        }
+        let mut migration = statements(migration);
+        migration.insert(
+            0,
+            (
+                "INSERT INTO schema_version (version) VALUES ($1)".into(),
+                vec![SqlValue::Int(version)],
+            ),
+        );
+        match sql.transaction(&migration) {
+            Ok(()) => info!("Migrated the database to version {}", version),
+            // Another instance starting at the same time got there first
+            Err(_) if schema_version(sql)? >= version => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Storage with proper tables for the records that are looked up or shared
+/// between replicas, on SQLite or PostgreSQL
+pub struct SqlStore {
+    pool: Vec<Mutex<Box<dyn Sql>>>,
+    next: AtomicUsize,
+}
+
+impl SqlStore {
+    /// Store using the connections in turn, the schema is migrated with the
+    /// first
+    pub fn new(mut pool: Vec<Box<dyn Sql>>) -> Result<Self> {
+        let first = pool
+            .first_mut()
+            .ok_or_else(|| Error::Service("No database connections".into()))?;
+        migrate(&mut **first)?;
+        Ok(SqlStore {
+            pool: pool.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// An idle connection, or waits for the next one in turn when all are
+    /// busy
+    fn sql(&self) -> MutexGuard<'_, Box<dyn Sql>> {
+        if let Some(sql) = self.pool.iter().find_map(|c| c.try_lock()) {
+            return sql;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.pool[next % self.pool.len()].lock()
+    }
+}
+
+impl Storage for SqlStore {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut sql = self.sql();
+        match Layout::of(tree) {
+            Layout::Table(table) => Ok(table.load(&mut **sql, Some(key))?.pop().map(|(_, v)| v)),
+            Layout::Raw {
+                table,
+                key: k,
+                tree,
+            } => {
+                let (filter, params) = Layout::filter(&tree, k, Some(key), vec![]);
+                let query = format!("SELECT value FROM {}{}", table, filter);
+                match sql.query(&query, &params)?.pop() {
+                    Some(mut row) => Ok(Some(bytes(row.remove(0))?)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    fn insert(&self, tree: &str, key: &str, value: &[u8]) -> Result<()> {
+        let mut sql = self.sql();
+        match Layout::of(tree) {
+            Layout::Table(table) => table.save(&mut **sql, key, value),
+            Layout::Raw {
+                table,
+                key: k,
+                tree,
+            } => {
+                let mut columns = vec![];
+                let mut params = vec![];
+                if let Some(tree) = &tree {
+                    columns.push("tree".to_string());
+                    params.push(SqlValue::Text(tree.to_string()));
+                }
+                let conflict = columns
+                    .iter()
+                    .cloned()
+                    .chain(Some(format!("\"{}\"", k)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                columns.push(format!("\"{}\"", k));
+                params.push(SqlValue::Text(key.to_string()));
+                columns.push("value".to_string());
+                params.push(SqlValue::Bytes(value.to_vec()));
+                let statement = format!(
+                    "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET value = excluded.value",
+                    table,
+                    columns.join(", "),
+                    placeholders(1, columns.len()),
+                    conflict
+                );
+                sql.transaction(&[(statement, params)])
+            }
+        }
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut sql = self.sql();
+        match Layout::of(tree) {
+            Layout::Table(table) => table.remove(&mut **sql, key),
+            Layout::Raw {
+                table,
+                key: k,
+                tree,
+            } => {
+                let (filter, params) = Layout::filter(&tree, k, Some(key), vec![]);
+                let old = sql
+                    .query(&format!("SELECT value FROM {}{}", table, filter), &params)?
+                    .pop();
+                let old = match old {
+                    Some(mut row) => Some(bytes(row.remove(0))?),
+                    None => return Ok(None),
+                };
+                sql.transaction(&[(format!("DELETE FROM {}{}", table, filter), params)])?;
+                Ok(old)
+            }
+        }
+    }
+
+    fn entries(&self, tree: &str) -> Result<Entries> {
+        let mut sql = self.sql();
+        match Layout::of(tree) {
+            Layout::Table(table) => table.load(&mut **sql, None),
+            Layout::Raw {
+                table,
+                key: k,
+                tree,
+            } => {
+                let (filter, params) = Layout::filter(&tree, k, None, vec![]);
+                let query = format!(
+                    "SELECT \"{}\", value FROM {}{} ORDER BY \"{}\"",
+                    k, table, filter, k
+                );
+                sql.query(&query, &params)?
+                    .into_iter()
+                    .map(|mut row| {
+                        let value = bytes(row.remove(1))?;
+                        Ok((text(row.remove(0))?, value))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        tree: &str,
+        key: &str,
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        let (table, k, tree) = match Layout::of(tree) {
+            Layout::Raw { table, key, tree } => (table, key, tree),
+            Layout::Table(table) => {
+                return Err(Error::Service(format!(
+                    "Records of {} can't be swapped",
+                    table.tree
+                )))
+            }
+        };
+        let mut sql = self.sql();
+        let changed = match old {
+            Some(old) => {
+                let new = vec![SqlValue::Bytes(new.to_vec())];
+                let (filter, mut params) = Layout::filter(&tree, k, Some(key), new);
+                params.push(SqlValue::Bytes(old.to_vec()));
+                let statement = format!(
+                    "UPDATE {} SET value = $1{} AND value = ${}",
+                    table,
+                    filter,
+                    params.len()
+                );
+                sql.execute(&statement, &params)?
+            }
+            None => {
+                let mut columns = vec![];
+                let mut params = vec![];
+                if let Some(tree) = &tree {
+                    columns.push("tree".to_string());
+                    params.push(SqlValue::Text(tree.to_string()));
+                }
+                columns.push(format!("\"{}\"", k));
+                params.push(SqlValue::Text(key.to_string()));
+                columns.push("value".to_string());
+                params.push(SqlValue::Bytes(new.to_vec()));
+                let statement = format!(
+                    "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT DO NOTHING",
+                    table,
+                    columns.join(", "),
+                    placeholders(1, columns.len())
+                );
+                sql.execute(&statement, &params)?
+            }
+        };
+        Ok(changed == 1)
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        let mut trees: Vec<String> = TABLES.iter().map(|t| t.tree.to_string()).collect();
+        trees.extend(RAW_TABLES.iter().map(|(t, _)| t.to_string()));
+        for mut row in self.sql().query("SELECT DISTINCT tree FROM entries", &[])? {
+            trees.push(text(row.remove(0))?);
+        }
+        Ok(trees)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::data::{
+            postgres::{tls, Postgres},
+            sqlite::Sqlite,
+        },
+    };
+
+    #[test]
+    fn keeps_records_in_tables() -> Result<()> {
+        keeps_records(SqlStore::new(vec![Box::new(Sqlite::in_memory()?)])?)?;
+        // e.g. postgres://postgres@localhost/postgres, the records are replaced.
+        // TEST_POSTGRES_CA is the CA file when the URL has sslmode=require.
+        if let Ok(url) = std::env::var("TEST_POSTGRES_URL") {
+            let ca = std::env::var_os("TEST_POSTGRES_CA").map(std::path::PathBuf::from);
+            let connect = || -> Result<Box<dyn Sql>> {
+                Ok(Box::new(Postgres::connect(&url, tls(ca.as_deref())?)?))
+            };
+            keeps_records(SqlStore::new(vec![connect()?, connect()?])?)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_databases_without_versions() -> Result<()> {
+        let mut sql = Sqlite::in_memory()?;
+        sql.execute_batch(
+            "CREATE TABLE sessions (id TEXT PRIMARY KEY, \"user\" TEXT, pending TEXT,
+                mfa BIGINT NOT NULL DEFAULT 0, created BIGINT NOT NULL DEFAULT 0,
+                ts BIGINT NOT NULL, ip TEXT);
+             INSERT INTO sessions (id, ts) VALUES ('s0', 1);",
+        )?;
+        let store = SqlStore::new(vec![Box::new(sql)])?;
+        keeps_records(store)?;
+
+        let mut sql = Sqlite::in_memory()?;
+        migrate(&mut sql)?;
+        assert_eq!(schema_version(&mut sql)?, MIGRATIONS.len() as i64);
+        migrate(&mut sql)?;
+        Ok(())
+    }
+
+    fn keeps_records(store: SqlStore) -> Result<()> {
+        for (tree, key) in &[("tokens", "s1"), ("sessions", "s1"), ("totp", "jdoe")] {
+            store.remove(tree, key)?;
+        }
+        let tokens = serde_json::json!({
+            "owner_id": "s1",
+            "authorizations": [["code", "2030-01-01T00:00:00Z"]],
+            "tokens": [
+                ["t1", "r1", "2030-01-01T00:00:00Z"],
+                ["t2", "r2", "2030-01-02T00:00:00Z"],
+            ],
+        });
+        store.insert("tokens", "s1", &serde_json::to_vec(&tokens)?)?;
+        let session = serde_json::json!({
+            "id": "s1",
+            "user": {"username": "jdoe", "attributes": [["mail", ["jdoe@example.com"]]]},
+            "pending": null,
+            "mfa": true,
+            "clients": ["app"],
+            "created": 1_600_000_000_000u64,
+            "ts": 1_600_000_000_001u64,
+            "ip": "10.0.0.1",
+            "slot": 3,
+        });
+        store.insert("sessions", "s1", &serde_json::to_vec(&session)?)?;
+        store.insert("server", "signing", b"\x00\xff")?;
+        store.insert("totp", "jdoe", b"{}")?;
+
+        let get = |tree, key| -> Result<Value> {
+            Ok(serde_json::from_slice(&store.get(tree, key)?.unwrap())?)
+        };
+        assert_eq!(get("tokens", "s1")?, tokens);
+        assert_eq!(get("sessions", "s1")?, session);
+        assert_eq!(store.get("server", "signing")?, Some(b"\x00\xff".to_vec()));
+        let swap =
+            |old: &[u8], new: &[u8]| store.compare_and_swap("server", "signing", Some(old), new);
+        assert!(swap(b"\x00\xff", b"\x01")?);
+        assert!(!swap(b"\x00\xff", b"\x02")?);
+        assert_eq!(store.get("server", "signing")?, Some(b"\x01".to_vec()));
+        assert_eq!(
+            store.entries("totp")?,
+            vec![("jdoe".into(), b"{}".to_vec())]
+        );
+
+        let rows = store.sql().query(
+            "SELECT owner_id FROM access_tokens WHERE token = $1",
+            &[SqlValue::Text("t2".into())],
+        )?;
+        assert_eq!(rows, vec![vec![SqlValue::Text("s1".into())]]);
+
+        assert!(store.remove("tokens", "s1")?.is_some());
+        assert!(store.get("tokens", "s1")?.is_none());
+        let rows = store.sql().query("SELECT token FROM access_tokens", &[])?;
+        assert!(rows.is_empty());
+        Ok(())
+    }
+}
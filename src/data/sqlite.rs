@@ -0,0 +1,76 @@
+use {
+    super::sql::{Sql, SqlValue},
+    crate::Result,
+    rusqlite::{
+        types::{Null, ToSqlOutput, ValueRef},
+        Connection, ToSql,
+    },
+    std::path::Path,
+};
+
+/// SQLite database file, for a single instance or trying the SQL schema out
+pub struct Sqlite(Connection);
+
+impl Sqlite {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+        Ok(Sqlite(conn))
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self> {
+        Ok(Sqlite(Connection::open_in_memory()?))
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            SqlValue::Null => ToSqlOutput::from(Null),
+            SqlValue::Int(i) => ToSqlOutput::from(*i),
+            SqlValue::Text(s) => ToSqlOutput::from(s.as_str()),
+            SqlValue::Bytes(b) => ToSqlOutput::from(b.as_slice()),
+        })
+    }
+}
+
+impl Sql for Sqlite {
+    fn execute_batch(&mut self, sql: &str) -> Result<()> {
+        self.0.execute_batch(sql)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> Result<u64> {
+        Ok(self.0.prepare_cached(sql)?.execute(params)? as u64)
+    }
+
+    fn query(&mut self, sql: &str, params: &[SqlValue]) -> Result<Vec<Vec<SqlValue>>> {
+        let mut stmt = self.0.prepare_cached(sql)?;
+        let columns = stmt.column_count();
+        let mut rows = stmt.query(params)?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            let values = (0..columns)
+                .map(|i| match row.get_raw(i) {
+                    ValueRef::Null => SqlValue::Null,
+                    ValueRef::Integer(i) => SqlValue::Int(i),
+                    ValueRef::Real(f) => SqlValue::Int(f as i64),
+                    ValueRef::Text(t) => SqlValue::Text(String::from_utf8_lossy(t).into_owned()),
+                    ValueRef::Blob(b) => SqlValue::Bytes(b.to_vec()),
+                })
+                .collect();
+            result.push(values);
+        }
+        Ok(result)
+    }
+
+    fn transaction(&mut self, statements: &[(String, Vec<SqlValue>)]) -> Result<()> {
+        let tx = self.0.transaction()?;
+        for (sql, params) in statements {
+            tx.prepare_cached(sql)?.execute(params)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
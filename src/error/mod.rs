@@ -27,12 +27,42 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<serde_cbor::Error> for Error {
+    fn from(e: serde_cbor::Error) -> Self {
+        Self::Authentication(format!("CBOR parse failure -> {}", e))
+    }
+}
+
 impl From<sled::Error> for Error {
     fn from(e: sled::Error) -> Self {
         Self::IOError(format!("Failed database operation -> {}", e))
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::IOError(format!("Failed database operation -> {}", e))
+    }
+}
+
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Self {
+        Self::IOError(format!("Failed database operation -> {}", e))
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(e: native_tls::Error) -> Self {
+        Self::Service(format!("Unable to set up TLS -> {}", e))
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Service(format!("Upstream request failed -> {}", e))
+    }
+}
+
 impl From<hmac::crypto_mac::InvalidKeyLength> for Error {
     fn from(e: hmac::crypto_mac::InvalidKeyLength) -> Self {
         Self::Authentication(format!("Signing failed -> {}", e))
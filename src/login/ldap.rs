@@ -1,37 +1,330 @@
 use super::*;
-use crate::args::LdapAuthenticator;
-use ldap3::{LdapConn, Scope, SearchEntry};
+use crate::args::{FailoverStrategy, LdapAuthenticator};
+use ldap3::{LdapConn, LdapConnSettings, LdapResult, Scope, SearchEntry};
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-impl Authenticator for LdapAuthenticator {
+/// LDAP result code returned for a bad username or password
+const INVALID_CREDENTIALS: u32 = 49;
+
+struct Job {
+    username: String,
+    password: String,
+    reply: Sender<Result<User>>,
+}
+
+struct Server {
+    url: String,
+    down_until: Mutex<Option<Instant>>,
+}
+
+struct Servers {
+    config: LdapAuthenticator,
+    servers: Vec<Server>,
+    next: AtomicUsize,
+}
+
+struct PooledConn {
+    conn: LdapConn,
+    checked: Instant,
+}
+
+/// Pool of LDAP connections with failover between the configured servers.
+///
+/// The ldap3 library is based on a deprecated version of tokio and its connections
+/// can't leave the thread that created them, so every pooled connection is owned by
+/// a worker thread and logins are handed to the workers over a channel.
+pub struct LdapPool {
+    jobs: Mutex<Sender<Job>>,
+    wait: Duration,
+}
+
+impl LdapPool {
+    pub fn new(config: LdapAuthenticator) -> Self {
+        let (tx, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let per_server = config.connect_timeout + config.timeout * 2;
+        let wait = Duration::from_secs(per_server * config.urls.len().max(1) as u64 + 1);
+        let servers = Arc::new(Servers::new(config));
+        for i in 0..servers.config.pool_size.max(1) {
+            let servers = servers.clone();
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("ldap-worker-{}", i))
+                .spawn(move || Worker::new(servers).run(rx))
+                .expect("Failed to start LDAP worker");
+        }
+        LdapPool {
+            jobs: Mutex::new(tx),
+            wait,
+        }
+    }
+}
+
+impl Authenticator for LdapPool {
     fn login(&self, path: &str, password: &str) -> Result<User> {
-        // The ldap3 library is based on a deprecated version of tokio
-        // As a result, it must be run in a different thread
-        // This could be optimized by using channels, but this works for now
-        let path = path.to_string();
-        let password = password.to_string();
-        let zelf = self.clone();
-        std::thread::spawn(move || {
-            let ldap = LdapConn::new(&zelf.url)?;
-            let login_path = format!("{}={},{}", zelf.user_dn, path, zelf.bind_dn);
-            ldap.simple_bind(&login_path, &password)?.success()?;
-            let (rs, _) = ldap
-                .search(&login_path, Scope::Base, "(objectClass=*)", vec!["*"])?
-                .success()?;
-            let mut user = User {
-                username: path,
-                attributes: Default::default(),
+        let (reply, rx) = channel();
+        self.jobs
+            .lock()
+            .send(Job {
+                username: path.to_string(),
+                password: password.to_string(),
+                reply,
+            })
+            .map_err(|_| Error::Service("LDAP pool is not running".into()))?;
+        rx.recv_timeout(self.wait)
+            .map_err(|_| Error::Service("Timed out waiting for LDAP login".into()))?
+    }
+}
+
+impl Servers {
+    fn new(config: LdapAuthenticator) -> Self {
+        Servers {
+            servers: config
+                .urls
+                .iter()
+                .map(|url| Server {
+                    url: url.to_string(),
+                    down_until: Mutex::new(None),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            config,
+        }
+    }
+
+    /// Server indexes in the order they should be tried, servers that recently
+    /// failed are moved to the back
+    fn candidates(&self) -> Vec<usize> {
+        let count = self.servers.len();
+        if count == 0 {
+            return vec![];
+        }
+        let start = match self.config.failover {
+            FailoverStrategy::Ordered => 0,
+            FailoverStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % count,
+        };
+        let now = Instant::now();
+        let (mut up, down): (Vec<usize>, Vec<usize>) = (0..count)
+            .map(|i| (start + i) % count)
+            .partition(|i| match *self.servers[*i].down_until.lock() {
+                Some(until) => now >= until,
+                None => true,
+            });
+        up.extend(down);
+        up
+    }
+
+    fn mark_down(&self, idx: usize) {
+        let retry = Duration::from_secs(self.config.retry_after);
+        *self.servers[idx].down_until.lock() = Some(Instant::now() + retry);
+    }
+
+    fn mark_up(&self, idx: usize) {
+        *self.servers[idx].down_until.lock() = None;
+    }
+}
+
+struct Worker {
+    servers: Arc<Servers>,
+    conns: Vec<Option<PooledConn>>,
+}
+
+impl Worker {
+    fn new(servers: Arc<Servers>) -> Self {
+        let conns = servers.servers.iter().map(|_| None).collect();
+        Worker { servers, conns }
+    }
+
+    fn run(mut self, jobs: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match jobs.lock().recv() {
+                Ok(job) => job,
+                Err(_) => return,
             };
-            for item in rs.into_iter() {
-                let entry = SearchEntry::construct(item);
-                for attr in entry.attrs.into_iter() {
-                    if zelf.attrs.contains(&attr.0) {
-                        user.attributes.push((attr.0, attr.1));
-                    }
+            let res = self.login(&job.username, &job.password);
+            job.reply.send(res).unwrap_or_default();
+        }
+    }
+
+    fn login(&mut self, path: &str, password: &str) -> Result<User> {
+        let mut last_err = Error::Service("No LDAP servers configured".into());
+        for idx in self.servers.candidates() {
+            match self.try_server(idx, path, password) {
+                Ok(user) => {
+                    self.servers.mark_up(idx);
+                    return Ok(user);
+                }
+                Err(LoginFailure::Rejected(e)) => return Err(e),
+                Err(LoginFailure::Unavailable(e)) => {
+                    warn!(
+                        "LDAP server {} failed: {}",
+                        self.servers.servers[idx].url, e
+                    );
+                    self.conns[idx] = None;
+                    self.servers.mark_down(idx);
+                    last_err = Error::Service(format!("LDAP unavailable -> {}", e));
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn try_server(
+        &mut self,
+        idx: usize,
+        path: &str,
+        password: &str,
+    ) -> std::result::Result<User, LoginFailure> {
+        let servers = self.servers.clone();
+        let config = &servers.config;
+        let timeout = Duration::from_secs(config.timeout);
+        let conn = self.connection(idx)?;
+        let login_path = format!("{}={},{}", config.user_dn, path, config.bind_dn);
+        let res = conn
+            .with_timeout(timeout)
+            .simple_bind(&login_path, password)?;
+        answered(res)?;
+        let search = conn.with_timeout(timeout).search(
+            &login_path,
+            Scope::Base,
+            "(objectClass=*)",
+            vec!["*"],
+        )?;
+        answered(search.1)?;
+        let rs = search.0;
+        let mut user = User {
+            username: path.to_string(),
+            attributes: Default::default(),
+        };
+        for item in rs.into_iter() {
+            let entry = SearchEntry::construct(item);
+            for attr in entry.attrs.into_iter() {
+                if config.attrs.contains(&attr.0) {
+                    user.attributes.push((attr.0, attr.1));
                 }
             }
-            Ok(user)
-        })
-        .join()
-        .unwrap()
+        }
+        Ok(user)
+    }
+
+    /// Returns a pooled connection to the server, reconnecting if it is missing
+    /// or fails a health check
+    fn connection(&mut self, idx: usize) -> std::io::Result<&LdapConn> {
+        let config = &self.servers.config;
+        let interval = Duration::from_secs(config.health_check_interval);
+        let healthy = match self.conns[idx] {
+            Some(ref pooled) if pooled.checked.elapsed() < interval => true,
+            Some(ref mut pooled) => {
+                let check = pooled
+                    .conn
+                    .with_timeout(Duration::from_secs(config.timeout))
+                    .search("", Scope::Base, "(objectClass=*)", vec!["1.1"])
+                    .and_then(|r| r.success());
+                if let Err(ref e) = check {
+                    debug!("Pooled LDAP connection failed health check: {}", e);
+                }
+                pooled.checked = Instant::now();
+                check.is_ok()
+            }
+            None => false,
+        };
+        if !healthy {
+            let settings = LdapConnSettings::new()
+                .set_conn_timeout(Duration::from_secs(config.connect_timeout));
+            let conn = LdapConn::with_settings(settings, &self.servers.servers[idx].url)?;
+            self.conns[idx] = Some(PooledConn {
+                conn,
+                checked: Instant::now(),
+            });
+        }
+        Ok(&self.conns[idx].as_ref().unwrap().conn)
+    }
+}
+
+enum LoginFailure {
+    /// The server answered, but refused the credentials
+    Rejected(Error),
+    /// The server could not be reached or failed, the next one should be tried
+    Unavailable(std::io::Error),
+}
+
+/// Any result code is an answer from a working server, a locked or expired
+/// account mustn't take it out of rotation
+fn answered(res: LdapResult) -> std::result::Result<(), LoginFailure> {
+    match res.rc {
+        0 => Ok(()),
+        INVALID_CREDENTIALS => Err(LoginFailure::Rejected(Error::Authentication(
+            "Invalid credentials".into(),
+        ))),
+        rc => Err(LoginFailure::Rejected(Error::Authentication(format!(
+            "Login refused by the directory -> {} ({})",
+            res.text, rc
+        )))),
+    }
+}
+
+impl From<std::io::Error> for LoginFailure {
+    fn from(e: std::io::Error) -> Self {
+        LoginFailure::Unavailable(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn servers(failover: FailoverStrategy) -> Servers {
+        let config = LdapAuthenticator {
+            urls: vec!["ldap://a".into(), "ldap://b".into(), "ldap://c".into()],
+            failover,
+            retry_after: 30,
+            ..Default::default()
+        };
+        Servers::new(config)
+    }
+
+    #[test]
+    fn ordered_failover() {
+        let s = servers(FailoverStrategy::Ordered);
+        assert_eq!(s.candidates(), vec![0, 1, 2]);
+        s.mark_down(0);
+        assert_eq!(s.candidates(), vec![1, 2, 0]);
+        s.mark_up(0);
+        assert_eq!(s.candidates(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn result_codes_are_rejections() {
+        let result = |rc| LdapResult {
+            rc,
+            matched: String::new(),
+            text: "account locked".into(),
+            refs: vec![],
+            ctrls: vec![],
+        };
+        assert!(answered(result(0)).is_ok());
+        for rc in &[19, 49, 50, 53] {
+            match answered(result(*rc)) {
+                Err(LoginFailure::Rejected(_)) => (),
+                _ => panic!("rc {} should reject the login", rc),
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin_failover() {
+        let s = servers(FailoverStrategy::RoundRobin);
+        assert_eq!(s.candidates(), vec![0, 1, 2]);
+        assert_eq!(s.candidates(), vec![1, 2, 0]);
+        s.mark_down(0);
+        assert_eq!(s.candidates(), vec![2, 1, 0]);
     }
 }
@@ -1,24 +1,128 @@
-use crate::error::*;
+use crate::{data::Persistable, error::*};
 use serde::{Deserialize, Serialize};
 
 mod ldap;
+mod throttle;
+mod totp;
+mod upstream;
+mod webauthn;
 
-pub use crate::args::LdapAuthenticator;
+pub use {
+    ldap::LdapPool,
+    throttle::{record_failure, record_success, throttled},
+    totp::TotpEnrollment,
+    upstream::{PendingLogin, UpstreamAuthenticator},
+    webauthn::{AssertionResponse, RegistrationResponse, RelyingParty, WebauthnCredentials},
+};
 
 pub trait Authenticator {
     fn login(&self, user: &str, password: &str) -> Result<User>;
 }
 
+/// Authenticator that sends the user to another identity provider and gets
+/// them back with an authorization code
+pub trait FederatedAuthenticator {
+    fn name(&self) -> &str;
+    fn authorize_url(&self, state: &str) -> String;
+    fn complete(&self, code: &str) -> Result<User>;
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     username: String,
     attributes: Vec<(String, Vec<String>)>,
 }
 
+impl User {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&Vec<String>> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Checks group membership, accepting either plain group names or
+    /// DNs such as `cn=admins,ou=groups,dc=example,dc=com`
+    pub fn member_of(&self, attr: &str, group: &str) -> bool {
+        self.attribute(attr)
+            .map(|values| {
+                values.iter().any(|v| {
+                    v.eq_ignore_ascii_case(group) || group_name(v).eq_ignore_ascii_case(group)
+                })
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Plain group name for DNs such as `cn=admins,ou=groups,dc=example,dc=com`
+pub fn group_name(group: &str) -> &str {
+    let rdn = group.split(',').next().unwrap_or_default();
+    rdn.splitn(2, '=').last().unwrap_or(group)
+}
+
 lazy_static! {
-    static ref AUTH: &'static LdapAuthenticator = &crate::CONFIG.login;
+    static ref AUTH: LdapPool = LdapPool::new(crate::CONFIG.login.clone());
+    static ref UPSTREAM: Result<Option<UpstreamAuthenticator>> =
+        UpstreamAuthenticator::from_config(&crate::CONFIG.upstream);
+    static ref RELYING_PARTY: Option<RelyingParty> =
+        RelyingParty::from_config(&crate::CONFIG.webauthn);
 }
 
 pub fn login(path: &str, password: &str) -> Result<User> {
     AUTH.login(path, password)
 }
+
+pub fn upstream() -> Result<Option<&'static UpstreamAuthenticator>> {
+    UPSTREAM.as_ref().map(|u| u.as_ref()).map_err(|e| e.clone())
+}
+
+pub fn relying_party() -> Option<&'static RelyingParty> {
+    RELYING_PARTY.as_ref()
+}
+
+/// Second factors the user has set up, by the name used on the login page
+pub fn second_factors(user: &User) -> Result<Vec<&'static str>> {
+    let mut factors = vec![];
+    if TotpEnrollment::is_enrolled(&user.username)? {
+        factors.push("totp");
+    }
+    if relying_party().is_some() && WebauthnCredentials::is_registered(&user.username)? {
+        factors.push("webauthn");
+    }
+    Ok(factors)
+}
+
+/// Whether the user has to pass a second factor before logging in to the client
+pub fn mfa_required(user: &User, client_id: &str) -> Result<bool> {
+    let config = &crate::CONFIG.mfa;
+    if config.required
+        || config.clients.iter().any(|c| c == client_id)
+        || config
+            .groups
+            .iter()
+            .any(|g| user.member_of(&config.group_attr, g))
+    {
+        return Ok(true);
+    }
+    Ok(!second_factors(user)?.is_empty())
+}
+
+/// Deletes expired upstream logins, WebAuthn challenges and failed login
+/// counters, returning how many were removed
+pub fn purge_expired() -> Result<u64> {
+    Ok(PendingLogin::purge_expired()?
+        + webauthn::WebauthnChallenge::purge_expired()?
+        + throttle::LoginAttempts::purge_expired()?)
+}
+
+/// Checks a second factor code for the user
+pub fn verify_second_factor(user: &User, code: &str) -> Result<bool> {
+    match TotpEnrollment::get(user.username.to_string())? {
+        Some(mut enrollment) => enrollment.check(code),
+        None => Ok(false),
+    }
+}
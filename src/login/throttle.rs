@@ -0,0 +1,144 @@
+use super::*;
+use crate::{args::ThrottleOptions, data::Persistable};
+use chrono::prelude::*;
+use std::net::IpAddr;
+
+/// Failed login counter for a username or a client address
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginAttempts {
+    pub key: String,
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+}
+
+impl Persistable for LoginAttempts {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "login_attempts"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.key.to_string()
+    }
+}
+
+impl LoginAttempts {
+    fn load(key: String, config: &ThrottleOptions, now: DateTime<Utc>) -> Result<Self> {
+        let forget = chrono::Duration::seconds(config.lockout as i64);
+        Ok(match Self::get(key.to_string())? {
+            Some(a) if now < a.last_failure + forget => a,
+            _ => LoginAttempts {
+                key,
+                failures: 0,
+                last_failure: now,
+            },
+        })
+    }
+
+    /// Deletes the counters that have been forgotten
+    pub fn purge_expired() -> Result<u64> {
+        let config = &crate::CONFIG.throttle;
+        let forget = chrono::Duration::seconds(config.lockout as i64);
+        let now = Utc::now();
+        Self::delete_all(|a| a.last_failure + forget <= now)
+    }
+
+    /// When the next attempt is allowed, backing off exponentially until
+    /// `max_failures` is reached and the key is locked out
+    fn retry_at(&self, max_failures: u32, config: &ThrottleOptions) -> Option<DateTime<Utc>> {
+        if self.failures == 0 {
+            return None;
+        }
+        let wait = if self.failures >= max_failures {
+            config.lockout
+        } else {
+            let factor = 1u64.checked_shl(self.failures - 1).unwrap_or(u64::MAX);
+            config
+                .backoff
+                .saturating_mul(factor)
+                .min(config.backoff_max)
+        };
+        Some(self.last_failure + chrono::Duration::seconds(wait as i64))
+    }
+}
+
+fn keys(username: &str, ip: Option<IpAddr>, config: &ThrottleOptions) -> Vec<(String, u32)> {
+    let mut keys = vec![(
+        format!("user:{}", username.to_lowercase()),
+        config.max_failures,
+    )];
+    if let Some(ip) = ip {
+        keys.push((format!("ip:{}", ip), config.max_failures_per_ip));
+    }
+    keys
+}
+
+/// Returns when the login may be retried if the username or address is
+/// currently backing off or locked out. Unknown usernames are counted like
+/// any other, so a lockout says nothing about whether an account exists.
+pub fn throttled(username: &str, ip: Option<IpAddr>) -> Result<Option<DateTime<Utc>>> {
+    let config = &crate::CONFIG.throttle;
+    let now = Utc::now();
+    let mut until = None;
+    for (key, max_failures) in keys(username, ip, config) {
+        let retry = LoginAttempts::load(key, config, now)?.retry_at(max_failures, config);
+        if let Some(r) = retry {
+            if now < r && until.map(|u| u < r).unwrap_or(true) {
+                until = Some(r);
+            }
+        }
+    }
+    Ok(until)
+}
+
+pub fn record_failure(username: &str, ip: Option<IpAddr>) -> Result<()> {
+    let config = &crate::CONFIG.throttle;
+    let now = Utc::now();
+    for (key, _) in keys(username, ip, config) {
+        let mut attempts = LoginAttempts::load(key, config, now)?;
+        attempts.failures += 1;
+        attempts.last_failure = now;
+        attempts.save()?;
+    }
+    Ok(())
+}
+
+/// Clears the username counter, the address counter is left alone so an
+/// attacker can't reset it by logging into their own account
+pub fn record_success(username: &str) -> Result<()> {
+    let key = format!("user:{}", username.to_lowercase());
+    if let Some(attempts) = LoginAttempts::get(key)? {
+        attempts.delete()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backs_off_then_locks_out() {
+        let config = ThrottleOptions {
+            max_failures: 4,
+            max_failures_per_ip: 10,
+            backoff: 2,
+            backoff_max: 5,
+            lockout: 900,
+        };
+        let now = Utc::now();
+        let mut attempts = LoginAttempts {
+            key: "user:test".into(),
+            failures: 0,
+            last_failure: now,
+        };
+        let wait = |a: &LoginAttempts| a.retry_at(4, &config).map(|r| (r - now).num_seconds());
+        assert_eq!(wait(&attempts), None);
+        let expected = [Some(2), Some(4), Some(5), Some(900)];
+        for e in expected.iter() {
+            attempts.failures += 1;
+            assert_eq!(wait(&attempts), *e);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use super::*;
+use crate::data::Persistable;
+use hmac::{crypto_mac::Mac, Hmac};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Seconds each code is valid for
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Number of steps either side of the current one that are still accepted
+const SKEW: u64 = 1;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 10;
+
+/// HMAC-based one-time password as defined in RFC 4226
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> Result<String> {
+    let mut mac = HmacSha1::new_varkey(secret)?;
+    mac.input(&counter.to_be_bytes());
+    let hash = mac.result().code();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    Ok(format!(
+        "{:0width$}",
+        bin % 10u32.pow(digits),
+        width = digits as usize
+    ))
+}
+
+/// A user's TOTP (RFC 6238) secret and recovery codes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub username: String,
+    pub secret: String,
+    pub confirmed: bool,
+    pub last_step: u64,
+    pub recovery_codes: Vec<String>,
+    /// Secret and recovery codes replacing a confirmed enrollment, which stays
+    /// in use until a code of the new secret comes in
+    #[serde(default)]
+    pub pending: Option<(String, Vec<String>)>,
+}
+
+impl Persistable for TotpEnrollment {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "totp"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.username.to_string()
+    }
+}
+
+impl TotpEnrollment {
+    /// Starts a new enrollment for the user, returning the new secret and the
+    /// plain recovery codes as only their hashes are stored. A confirmed
+    /// enrollment is only replaced given one of its codes, and keeps working
+    /// until the new secret is confirmed. `None` when the code is missing or wrong.
+    pub fn enroll(username: &str, code: Option<&str>) -> Result<Option<(String, Vec<String>)>> {
+        let mut secret = [0u8; SECRET_LEN];
+        OsRng.fill_bytes(&mut secret);
+        let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret);
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| recovery_code()).collect();
+        let hashed = codes.iter().map(|c| hash_code(c)).collect();
+        match Self::get(username.to_string())? {
+            Some(mut enrollment) if enrollment.confirmed => {
+                match code {
+                    Some(code) if enrollment.check(code)? => (),
+                    _ => return Ok(None),
+                }
+                enrollment.pending = Some((secret.to_string(), hashed));
+                enrollment.save()?;
+            }
+            _ => TotpEnrollment {
+                username: username.to_string(),
+                secret: secret.to_string(),
+                confirmed: false,
+                last_step: 0,
+                recovery_codes: hashed,
+                pending: None,
+            }
+            .save()?,
+        }
+        Ok(Some((secret, codes)))
+    }
+
+    pub fn is_enrolled(username: &str) -> Result<bool> {
+        Ok(Self::get(username.to_string())?
+            .map(|e| e.confirmed)
+            .unwrap_or(false))
+    }
+
+    /// The otpauth:// URI used to add a secret to an authenticator app
+    pub fn uri(issuer: &str, username: &str, secret: &str) -> String {
+        let mut url: url::Url = "otpauth://totp/".parse().unwrap();
+        url.set_path(&format!("{}:{}", issuer, username));
+        url.query_pairs_mut()
+            .append_pair("secret", secret)
+            .append_pair("issuer", issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &STEP.to_string());
+        url.to_string()
+    }
+
+    /// Checks a TOTP or recovery code, confirming the enrollment on the first
+    /// valid TOTP code and switching to a pending secret on its first code.
+    /// Used codes can't be replayed.
+    pub fn check(&mut self, code: &str) -> Result<bool> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let valid = self.check_at(code, now)?;
+        if valid {
+            self.save()?;
+        }
+        Ok(valid)
+    }
+
+    fn check_at(&mut self, code: &str, now: u64) -> Result<bool> {
+        let code = code.trim();
+        if let Some(step) = matching_step(&self.secret, code, now, self.last_step)? {
+            self.last_step = step;
+            self.confirmed = true;
+            return Ok(true);
+        }
+        if let Some((secret, recovery_codes)) = self.pending.clone() {
+            if let Some(step) = matching_step(&secret, code, now, 0)? {
+                info!("New TOTP secret confirmed for {}", self.username);
+                self.secret = secret;
+                self.recovery_codes = recovery_codes;
+                self.pending = None;
+                self.last_step = step;
+                return Ok(true);
+            }
+        }
+
+        // Recovery codes only work once the enrollment has been confirmed
+        let hashed = hash_code(code);
+        if self.confirmed && self.recovery_codes.contains(&hashed) {
+            warn!("Recovery code used for {}", self.username);
+            self.recovery_codes.retain(|c| *c != hashed);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// Step of the window around `now` the code belongs to, only steps after
+/// `after` count
+fn matching_step(secret: &str, code: &str, now: u64, after: u64) -> Result<Option<u64>> {
+    let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+        .ok_or_else(|| Error::Service("Stored TOTP secret is corrupt".into()))?;
+    let current = now / STEP;
+    for step in current.saturating_sub(SKEW)..=current + SKEW {
+        if step > after && hotp(&secret, step, DIGITS)? == code {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+fn recovery_code() -> String {
+    let code: String = OsRng
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .collect::<String>()
+        .to_uppercase();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+fn hash_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_uppercase();
+    base64::encode(&Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rfc6238_vectors() -> Result<()> {
+        let secret = b"12345678901234567890";
+        for (time, expected) in &[
+            (59u64, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+        ] {
+            assert_eq!(hotp(secret, time / STEP, 8)?, *expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn codes_are_single_use() -> Result<()> {
+        let secret = b"12345678901234567890";
+        let mut enrollment = TotpEnrollment {
+            username: "test".into(),
+            secret: base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret),
+            confirmed: false,
+            last_step: 0,
+            recovery_codes: vec![hash_code("ABCDE-12345")],
+            pending: None,
+        };
+        assert!(!enrollment.check_at("abcde-12345", 59)?);
+        assert!(enrollment.check_at("287082", 59)?);
+        assert!(enrollment.confirmed);
+        assert!(!enrollment.check_at("287082", 59)?);
+        assert!(enrollment.check_at("abcde-12345", 59)?);
+        assert!(!enrollment.check_at("ABCDE-12345", 59)?);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_secret_until_replacement_is_confirmed() -> Result<()> {
+        let old = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            b"12345678901234567890",
+        );
+        let new = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            b"abcdefghijabcdefghij",
+        );
+        let mut enrollment = TotpEnrollment {
+            username: "test".into(),
+            secret: old.to_string(),
+            confirmed: true,
+            last_step: 0,
+            recovery_codes: vec![],
+            pending: Some((new.to_string(), vec![hash_code("ABCDE-12345")])),
+        };
+        let now = 1_111_111_109;
+        let code = |secret: &str| {
+            let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret);
+            hotp(&secret.unwrap(), now / STEP, DIGITS)
+        };
+        assert!(enrollment.check_at(&code(&old)?, now)?);
+        assert_eq!(enrollment.secret, old);
+        assert!(enrollment.check_at(&code(&new)?, now)?);
+        assert_eq!(enrollment.secret, new);
+        assert!(enrollment.pending.is_none());
+        assert!(enrollment.check_at("ABCDE-12345", now)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use super::*;
+use crate::{args::UpstreamOptions, data::Persistable};
+use chrono::prelude::*;
+use reqwest::header::{ACCEPT, USER_AGENT};
+use serde_json::Value;
+use std::time::Duration;
+use url::Url;
+
+/// Seconds a user has to finish logging in with the upstream provider
+const PENDING_LOGIN_TTL: i64 = 600;
+
+/// Talks to the upstream provider over HTTP, split out so it can be mocked
+pub trait UpstreamProvider: Send + Sync {
+    /// Redeem an authorization code for an upstream access token
+    fn exchange_code(&self, code: &str) -> Result<String>;
+
+    /// Fetch the claims of the user the access token was issued to
+    fn claims(&self, access_token: &str) -> Result<Value>;
+}
+
+pub struct HttpProvider {
+    client: reqwest::Client,
+    token_url: String,
+    userinfo_url: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+impl HttpProvider {
+    pub fn new(config: &UpstreamOptions) -> Result<Self> {
+        Ok(HttpProvider {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.timeout))
+                .build()?,
+            token_url: required(&config.token_url, "upstream-token-url")?,
+            userinfo_url: required(&config.userinfo_url, "upstream-userinfo-url")?,
+            client_id: required(&config.client_id, "upstream-client-id")?,
+            client_secret: config.client_secret.clone().unwrap_or_default(),
+            redirect_url: required(&config.redirect_url, "upstream-redirect-url")?,
+        })
+    }
+}
+
+impl UpstreamProvider for HttpProvider {
+    fn exchange_code(&self, code: &str) -> Result<String> {
+        let resp: TokenResponse = self
+            .client
+            .post(&self.token_url)
+            .header(ACCEPT, "application/json")
+            .header(USER_AGENT, "oauth-lite")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(resp.access_token)
+    }
+
+    fn claims(&self, access_token: &str) -> Result<Value> {
+        Ok(self
+            .client
+            .get(&self.userinfo_url)
+            .bearer_auth(access_token)
+            .header(ACCEPT, "application/json")
+            .header(USER_AGENT, "oauth-lite")
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+}
+
+/// Logs users in through an authorization code flow against an upstream
+/// OAuth2 or OIDC provider such as GitHub, GitLab or a corporate IdP
+pub struct UpstreamAuthenticator {
+    name: String,
+    authorize_url: Url,
+    client_id: String,
+    redirect_url: String,
+    scope: String,
+    username_claim: String,
+    attrs: Vec<String>,
+    provider: Box<dyn UpstreamProvider>,
+}
+
+impl UpstreamAuthenticator {
+    /// Build the authenticator from the configuration, `None` if federation is disabled
+    pub fn from_config(config: &UpstreamOptions) -> Result<Option<Self>> {
+        if config.name.is_none() {
+            return Ok(None);
+        }
+        let provider = HttpProvider::new(config)?;
+        Self::new(config, Box::new(provider)).map(Some)
+    }
+
+    pub fn new(config: &UpstreamOptions, provider: Box<dyn UpstreamProvider>) -> Result<Self> {
+        let authorize_url = required(&config.authorize_url, "upstream-authorize-url")?;
+        Ok(UpstreamAuthenticator {
+            name: required(&config.name, "upstream-name")?,
+            authorize_url: authorize_url
+                .parse()
+                .map_err(|e| Error::Service(format!("Invalid upstream authorize URL -> {}", e)))?,
+            client_id: required(&config.client_id, "upstream-client-id")?,
+            redirect_url: required(&config.redirect_url, "upstream-redirect-url")?,
+            scope: config.scope.to_string(),
+            username_claim: config.username_claim.to_string(),
+            attrs: config.attrs.clone(),
+            provider,
+        })
+    }
+
+    fn user_from_claims(&self, claims: &Value) -> Result<User> {
+        let claim = match claims.get(&self.username_claim) {
+            Some(Value::String(s)) => s.to_string(),
+            Some(Value::Number(n)) => n.to_string(),
+            _ => {
+                return Err(Error::Authentication(format!(
+                    "Upstream claims are missing '{}'",
+                    self.username_claim
+                )))
+            }
+        };
+        // Namespaced by the upstream, an upstream `admin` isn't the LDAP `admin`
+        let username = format!(
+            "upstream:{}:{}",
+            self.authorize_url.host_str().unwrap_or_default(),
+            claim
+        );
+        let mut user = User {
+            username,
+            attributes: Default::default(),
+        };
+        for attr in self.attrs.iter() {
+            let values = match claims.get(attr) {
+                Some(Value::Array(a)) => a.iter().filter_map(claim_string).collect(),
+                Some(v) => claim_string(v).into_iter().collect(),
+                None => continue,
+            };
+            user.attributes.push((attr.to_string(), values));
+        }
+        Ok(user)
+    }
+}
+
+impl FederatedAuthenticator for UpstreamAuthenticator {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn authorize_url(&self, state: &str) -> String {
+        let mut url = self.authorize_url.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scope)
+            .append_pair("state", state);
+        url.to_string()
+    }
+
+    fn complete(&self, code: &str) -> Result<User> {
+        let token = self.provider.exchange_code(code)?;
+        let claims = self.provider.claims(&token)?;
+        debug!("Upstream claims: {:?}", claims);
+        self.user_from_claims(&claims)
+    }
+}
+
+/// An upstream login that has been started but not yet completed, it
+/// remembers the `/authorize` request to continue once the user returns
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub state: String,
+    pub session_id: String,
+    pub query: String,
+    pub until: DateTime<Utc>,
+}
+
+impl Persistable for PendingLogin {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "upstream_logins"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.state.to_string()
+    }
+}
+
+impl PendingLogin {
+    pub fn start(session_id: &str, query: String) -> Result<Self> {
+        let pending = PendingLogin {
+            state: Self::gen_id(),
+            session_id: session_id.to_string(),
+            query,
+            until: Utc::now()
+                .checked_add_signed(chrono::Duration::seconds(PENDING_LOGIN_TTL))
+                .unwrap(),
+        };
+        pending.save()?;
+        Ok(pending)
+    }
+
+    /// Deletes the logins the user never came back from
+    pub fn purge_expired() -> Result<u64> {
+        let now = Utc::now();
+        Self::delete_all(|p| p.until <= now)
+    }
+
+    /// Removes the pending login for this state, it can only be used once
+    pub fn take(state: &str) -> Result<Option<Self>> {
+        let pending = match Self::get(state.to_string())? {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        pending.delete()?;
+        Ok(if Utc::now() < pending.until {
+            Some(pending)
+        } else {
+            None
+        })
+    }
+}
+
+fn required(val: &Option<String>, name: &str) -> Result<String> {
+    val.clone()
+        .ok_or_else(|| Error::Service(format!("Upstream federation requires --{}", name)))
+}
+
+fn claim_string(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    struct MockProvider;
+
+    impl UpstreamProvider for MockProvider {
+        fn exchange_code(&self, code: &str) -> Result<String> {
+            match code {
+                "good-code" => Ok("mock-token".into()),
+                _ => Err(Error::Authentication("Unknown code".into())),
+            }
+        }
+
+        fn claims(&self, access_token: &str) -> Result<Value> {
+            assert_eq!(access_token, "mock-token");
+            Ok(json!({
+                "login": "octocat",
+                "email": "octocat@example.com",
+                "groups": ["admins", "users"],
+            }))
+        }
+    }
+
+    fn authenticator() -> UpstreamAuthenticator {
+        let config = UpstreamOptions {
+            name: Some("mock".into()),
+            authorize_url: Some("http://upstream.localhost/authorize".into()),
+            client_id: Some("oauth-lite".into()),
+            redirect_url: Some("http://auth.localhost/upstream/callback".into()),
+            scope: "read:user".into(),
+            username_claim: "login".into(),
+            attrs: vec!["email".into(), "groups".into()],
+            ..Default::default()
+        };
+        UpstreamAuthenticator::new(&config, Box::new(MockProvider)).unwrap()
+    }
+
+    #[test]
+    fn maps_upstream_claims() -> Result<()> {
+        let user = authenticator().complete("good-code")?;
+        assert_eq!(user.username, "upstream:upstream.localhost:octocat");
+        assert_eq!(
+            user.attributes,
+            vec![
+                ("email".to_string(), vec!["octocat@example.com".to_string()]),
+                (
+                    "groups".to_string(),
+                    vec!["admins".to_string(), "users".to_string()]
+                ),
+            ]
+        );
+        assert!(authenticator().complete("bad-code").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn builds_authorize_url() {
+        let url: Url = authenticator().authorize_url("some+state").parse().unwrap();
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert!(query.contains(&("client_id".into(), "oauth-lite".into())));
+        assert!(query.contains(&("state".into(), "some+state".into())));
+    }
+}
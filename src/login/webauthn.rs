@@ -0,0 +1,630 @@
+use super::*;
+use crate::{args::WebauthnOptions, data::Persistable};
+use chrono::prelude::*;
+use rand::{rngs::OsRng, RngCore};
+use ring::signature;
+use serde_cbor::Value as Cbor;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// Seconds a browser has to answer a registration or login challenge
+const CHALLENGE_TTL: i64 = 300;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+/// COSE identifier for ECDSA with P-256 and SHA-256
+const COSE_ES256: i128 = -7;
+
+/// A registered WebAuthn credential
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Passkey {
+    pub id: String,
+    pub public_key: String,
+    pub sign_count: u32,
+    pub created: DateTime<Utc>,
+}
+
+/// All passkeys registered by a user, along with the user details to use
+/// when one of them is used for a passwordless login
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnCredentials {
+    pub username: String,
+    pub user: User,
+    /// When the user details were last taken from a directory or upstream login
+    #[serde(default)]
+    pub user_at: i64,
+    pub passkeys: Vec<Passkey>,
+}
+
+impl Persistable for WebauthnCredentials {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "webauthn"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.username.to_string()
+    }
+}
+
+impl WebauthnCredentials {
+    pub fn is_registered(username: &str) -> Result<bool> {
+        Ok(Self::get(username.to_string())?
+            .map(|c| !c.passkeys.is_empty())
+            .unwrap_or(false))
+    }
+
+    fn by_passkey(id: &str) -> Result<Option<Self>> {
+        Self::find(|c| c.passkeys.iter().any(|p| p.id == id))
+    }
+
+    /// User the passkey is registered to, if it is known at all
+    pub fn owner(id: &str) -> Result<Option<String>> {
+        Ok(Self::by_passkey(id)?.map(|c| c.username))
+    }
+
+    /// Keeps the user details of a fresh login for later passwordless logins,
+    /// so that they follow changes to groups and attributes
+    pub fn refresh_user(user: &User) -> Result<()> {
+        if let Some(mut creds) = Self::get(user.username.to_string())? {
+            creds.user = user.clone();
+            creds.user_at = Utc::now().timestamp();
+            creds.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// A challenge handed to the browser, bound to the session that asked for it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnChallenge {
+    pub challenge: String,
+    pub session_id: String,
+    pub until: DateTime<Utc>,
+}
+
+impl Persistable for WebauthnChallenge {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "webauthn_challenges"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.challenge.to_string()
+    }
+}
+
+impl WebauthnChallenge {
+    fn start(session_id: &str) -> Result<Self> {
+        let mut challenge = [0u8; 32];
+        OsRng.fill_bytes(&mut challenge);
+        let challenge = WebauthnChallenge {
+            challenge: b64url(&challenge),
+            session_id: session_id.to_string(),
+            until: Utc::now()
+                .checked_add_signed(chrono::Duration::seconds(CHALLENGE_TTL))
+                .unwrap(),
+        };
+        challenge.save()?;
+        Ok(challenge)
+    }
+
+    /// Deletes the challenges that were never answered
+    pub fn purge_expired() -> Result<u64> {
+        let now = Utc::now();
+        Self::delete_all(|c| c.until <= now)
+    }
+
+    /// Removes the challenge, each one can only be answered once
+    fn take(challenge: &str, session_id: &str) -> Result<Self> {
+        let found = Self::get(challenge.to_string())?;
+        if let Some(ref c) = found {
+            c.delete()?;
+        }
+        match found {
+            Some(c) if c.session_id == session_id && Utc::now() < c.until => Ok(c),
+            _ => Err(Error::Authentication("Unknown WebAuthn challenge".into())),
+        }
+    }
+}
+
+/// Answer to `navigator.credentials.create()` as sent by the login page
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistrationResponse {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// Answer to `navigator.credentials.get()` as sent by the login page
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    pub id: String,
+    pub response: AuthenticatorAssertion,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthenticatorAssertion {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    credential: Option<(Vec<u8>, Cbor)>,
+}
+
+/// The WebAuthn relying party, runs the registration and login ceremonies
+pub struct RelyingParty {
+    id: String,
+    name: String,
+    origin: String,
+    max_user_age: i64,
+}
+
+impl RelyingParty {
+    /// Build the relying party from the configuration, `None` if passkeys are disabled
+    pub fn from_config(config: &WebauthnOptions) -> Option<Self> {
+        config.rp_id.as_ref().map(|id| RelyingParty {
+            id: id.to_string(),
+            name: config.rp_name.to_string(),
+            origin: config
+                .origin
+                .clone()
+                .unwrap_or_else(|| format!("https://{}", id)),
+            max_user_age: config.max_user_age as i64,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Options for `navigator.credentials.create()`
+    pub fn registration_options(&self, user: &User, session_id: &str) -> Result<Value> {
+        let challenge = WebauthnChallenge::start(session_id)?;
+        let existing = WebauthnCredentials::get(user.username.to_string())?
+            .map(|c| c.passkeys)
+            .unwrap_or_default();
+        Ok(json!({
+            "challenge": challenge.challenge,
+            "rp": { "id": self.id, "name": self.name },
+            "user": {
+                "id": b64url(user.username.as_bytes()),
+                "name": user.username,
+                "displayName": user.username,
+            },
+            "pubKeyCredParams": [{ "type": "public-key", "alg": COSE_ES256 as i64 }],
+            "excludeCredentials": existing
+                .iter()
+                .map(|p| json!({ "type": "public-key", "id": p.id }))
+                .collect::<Vec<Value>>(),
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "preferred",
+            },
+            "attestation": "none",
+            "timeout": CHALLENGE_TTL * 1000,
+        }))
+    }
+
+    /// Verifies a new credential and stores it for the user. Attestation
+    /// statements are not checked, as with the "none" conveyance requested.
+    pub fn register(
+        &self,
+        user: &User,
+        session_id: &str,
+        resp: &RegistrationResponse,
+    ) -> Result<Passkey> {
+        self.client_data(
+            &resp.response.client_data_json,
+            "webauthn.create",
+            session_id,
+        )?;
+        let attestation: Cbor =
+            serde_cbor::from_slice(&b64url_decode(&resp.response.attestation_object)?)?;
+        let auth_data = match cbor_field(&attestation, "authData") {
+            Some(Cbor::Bytes(b)) => self.auth_data(b, false)?,
+            _ => {
+                return Err(Error::Authentication(
+                    "Attestation is missing authData".into(),
+                ))
+            }
+        };
+        let (id, key) = auth_data
+            .credential
+            .ok_or_else(|| Error::Authentication("Attestation has no credential".into()))?;
+        let passkey = Passkey {
+            id: b64url(&id),
+            public_key: base64::encode(&cose_public_key(&key)?),
+            sign_count: auth_data.sign_count,
+            created: Utc::now(),
+        };
+
+        let mut creds = WebauthnCredentials::get(user.username.to_string())?.unwrap_or_else(|| {
+            WebauthnCredentials {
+                username: user.username.to_string(),
+                user: user.clone(),
+                user_at: 0,
+                passkeys: vec![],
+            }
+        });
+        if WebauthnCredentials::by_passkey(&passkey.id)?.is_some() {
+            return Err(Error::Authentication(
+                "Passkey is already registered".into(),
+            ));
+        }
+        creds.user = user.clone();
+        creds.user_at = Utc::now().timestamp();
+        creds.passkeys.push(passkey.clone());
+        creds.save()?;
+        Ok(passkey)
+    }
+
+    /// Options for `navigator.credentials.get()`, credentials are only listed
+    /// when the user is known, otherwise the browser offers discoverable ones
+    pub fn login_options(&self, username: Option<&str>, session_id: &str) -> Result<Value> {
+        let challenge = WebauthnChallenge::start(session_id)?;
+        let allowed = match username {
+            Some(u) => WebauthnCredentials::get(u.to_string())?
+                .map(|c| c.passkeys)
+                .unwrap_or_default(),
+            None => vec![],
+        };
+        Ok(json!({
+            "challenge": challenge.challenge,
+            "rpId": self.id,
+            "allowCredentials": allowed
+                .iter()
+                .map(|p| json!({ "type": "public-key", "id": p.id }))
+                .collect::<Vec<Value>>(),
+            "userVerification": if username.is_some() { "discouraged" } else { "required" },
+            "timeout": CHALLENGE_TTL * 1000,
+        }))
+    }
+
+    /// Verifies an assertion and returns the user owning the passkey. A
+    /// passwordless login must also prove user verification (PIN or biometrics),
+    /// and is refused once the user details kept with the passkey are too old.
+    pub fn login(
+        &self,
+        session_id: &str,
+        resp: &AssertionResponse,
+        passwordless: bool,
+    ) -> Result<User> {
+        self.client_data(&resp.response.client_data_json, "webauthn.get", session_id)?;
+        let mut creds = WebauthnCredentials::by_passkey(&resp.id)?
+            .ok_or_else(|| Error::Authentication("Unknown passkey".into()))?;
+        let raw_auth_data = b64url_decode(&resp.response.authenticator_data)?;
+        let auth_data = self.auth_data(&raw_auth_data, passwordless)?;
+
+        let passkey = creds.passkeys.iter_mut().find(|p| p.id == resp.id).unwrap();
+        let public_key = base64::decode(&passkey.public_key)
+            .map_err(|_| Error::Service("Stored passkey is corrupt".into()))?;
+        let mut msg = raw_auth_data.clone();
+        msg.extend_from_slice(&Sha256::digest(&b64url_decode(
+            &resp.response.client_data_json,
+        )?));
+        let sig = b64url_decode(&resp.response.signature)?;
+        signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, &public_key)
+            .verify(&msg, &sig)
+            .map_err(|_| Error::Authentication("Invalid passkey signature".into()))?;
+
+        // A counter that doesn't move forward points at a cloned authenticator
+        if (auth_data.sign_count != 0 || passkey.sign_count != 0)
+            && auth_data.sign_count <= passkey.sign_count
+        {
+            return Err(Error::Authentication(
+                "Passkey counter went backwards".into(),
+            ));
+        }
+        passkey.sign_count = auth_data.sign_count;
+        creds.save()?;
+        if passwordless
+            && self.max_user_age > 0
+            && Utc::now().timestamp() - creds.user_at > self.max_user_age
+        {
+            return Err(Error::Authentication(
+                "Log in with a password once to keep using this passkey".into(),
+            ));
+        }
+        Ok(creds.user)
+    }
+
+    fn client_data(&self, raw: &str, kind: &str, session_id: &str) -> Result<()> {
+        let data: ClientData = serde_json::from_slice(&b64url_decode(raw)?)?;
+        if data.kind != kind {
+            return Err(Error::Authentication(format!(
+                "Unexpected WebAuthn ceremony {}",
+                data.kind
+            )));
+        }
+        if data.origin != self.origin {
+            return Err(Error::Authentication(format!(
+                "Unexpected WebAuthn origin {}",
+                data.origin
+            )));
+        }
+        WebauthnChallenge::take(&data.challenge, session_id)?;
+        Ok(())
+    }
+
+    fn auth_data(&self, data: &[u8], require_uv: bool) -> Result<AuthData> {
+        if data.len() < 37 {
+            return Err(Error::Authentication("Authenticator data too short".into()));
+        }
+        let mut count = [0u8; 4];
+        count.copy_from_slice(&data[33..37]);
+        let mut auth_data = AuthData {
+            rp_id_hash: data[..32].to_vec(),
+            flags: data[32],
+            sign_count: u32::from_be_bytes(count),
+            credential: None,
+        };
+        if auth_data.rp_id_hash[..] != Sha256::digest(self.id.as_bytes())[..] {
+            return Err(Error::Authentication("Passkey is for another site".into()));
+        }
+        if auth_data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(Error::Authentication("User was not present".into()));
+        }
+        if require_uv && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(Error::Authentication("User was not verified".into()));
+        }
+        if auth_data.flags & FLAG_ATTESTED_DATA != 0 {
+            // aaguid (16 bytes), credential ID length (2 bytes), credential ID, COSE key
+            let rest = &data[37..];
+            if rest.len() < 18 {
+                return Err(Error::Authentication("Attested data too short".into()));
+            }
+            let len = usize::from(u16::from_be_bytes([rest[16], rest[17]]));
+            if rest.len() < 18 + len {
+                return Err(Error::Authentication("Credential ID too short".into()));
+            }
+            let id = rest[18..18 + len].to_vec();
+            let mut de = serde_cbor::Deserializer::from_slice(&rest[18 + len..]);
+            let key: Cbor = serde::Deserialize::deserialize(&mut de)?;
+            auth_data.credential = Some((id, key));
+        }
+        Ok(auth_data)
+    }
+}
+
+/// Converts an ES256 COSE key into an uncompressed SEC1 point
+fn cose_public_key(key: &Cbor) -> Result<Vec<u8>> {
+    let int = |k: i128| match key {
+        Cbor::Map(m) => m.get(&Cbor::Integer(k)),
+        _ => None,
+    };
+    match (int(1), int(3), int(-1), int(-2), int(-3)) {
+        (
+            Some(Cbor::Integer(2)),
+            Some(Cbor::Integer(COSE_ES256)),
+            Some(Cbor::Integer(1)),
+            Some(Cbor::Bytes(x)),
+            Some(Cbor::Bytes(y)),
+        ) if x.len() == 32 && y.len() == 32 => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(x);
+            point.extend_from_slice(y);
+            Ok(point)
+        }
+        _ => Err(Error::Authentication(
+            "Only ES256 passkeys are supported".into(),
+        )),
+    }
+}
+
+fn cbor_field<'a>(val: &'a Cbor, name: &str) -> Option<&'a Cbor> {
+    match val {
+        Cbor::Map(m) => m.get(&Cbor::Text(name.to_string())),
+        _ => None,
+    }
+}
+
+fn b64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn b64url_decode(data: &str) -> Result<Vec<u8>> {
+    base64::decode_config(data.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| Error::Authentication(format!("Invalid base64 -> {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::{rand::SystemRandom, signature::KeyPair};
+    use std::collections::BTreeMap;
+
+    /// Software authenticator holding a single P-256 key, used in place of a browser
+    struct SoftAuthenticator {
+        key_pair: signature::EcdsaKeyPair,
+        public_key: Vec<u8>,
+        credential_id: Vec<u8>,
+        counter: u32,
+        origin: String,
+    }
+
+    impl SoftAuthenticator {
+        fn new(origin: &str) -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(
+                &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+                &rng,
+            )
+            .unwrap();
+            let key_pair = signature::EcdsaKeyPair::from_pkcs8(
+                &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+                pkcs8.as_ref(),
+                &rng,
+            )
+            .unwrap();
+            let public_key = key_pair.public_key().as_ref().to_vec();
+            let mut credential_id = [0u8; 16];
+            OsRng.fill_bytes(&mut credential_id);
+            SoftAuthenticator {
+                key_pair,
+                public_key,
+                credential_id: credential_id.to_vec(),
+                counter: 0,
+                origin: origin.to_string(),
+            }
+        }
+
+        fn client_data(&self, kind: &str, options: &Value) -> Vec<u8> {
+            serde_json::to_vec(&json!({
+                "type": kind,
+                "challenge": options["challenge"],
+                "origin": self.origin,
+            }))
+            .unwrap()
+        }
+
+        fn auth_data(&mut self, rp_id: &str, flags: u8) -> Vec<u8> {
+            self.counter += 1;
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.counter.to_be_bytes());
+            data
+        }
+
+        fn create(&mut self, options: &Value) -> RegistrationResponse {
+            let rp_id = options["rp"]["id"].as_str().unwrap();
+            let mut auth_data = self.auth_data(rp_id, FLAG_USER_PRESENT | FLAG_ATTESTED_DATA);
+            auth_data.extend_from_slice(&[0u8; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            let mut key = BTreeMap::new();
+            key.insert(Cbor::Integer(1), Cbor::Integer(2));
+            key.insert(Cbor::Integer(3), Cbor::Integer(COSE_ES256));
+            key.insert(Cbor::Integer(-1), Cbor::Integer(1));
+            key.insert(
+                Cbor::Integer(-2),
+                Cbor::Bytes(self.public_key[1..33].to_vec()),
+            );
+            key.insert(
+                Cbor::Integer(-3),
+                Cbor::Bytes(self.public_key[33..].to_vec()),
+            );
+            auth_data.extend(serde_cbor::to_vec(&Cbor::Map(key)).unwrap());
+
+            let mut attestation = BTreeMap::new();
+            attestation.insert(Cbor::Text("fmt".into()), Cbor::Text("none".into()));
+            attestation.insert(Cbor::Text("attStmt".into()), Cbor::Map(BTreeMap::new()));
+            attestation.insert(Cbor::Text("authData".into()), Cbor::Bytes(auth_data));
+            RegistrationResponse {
+                id: b64url(&self.credential_id),
+                response: AttestationResponse {
+                    client_data_json: b64url(&self.client_data("webauthn.create", options)),
+                    attestation_object: b64url(
+                        &serde_cbor::to_vec(&Cbor::Map(attestation)).unwrap(),
+                    ),
+                },
+            }
+        }
+
+        fn get(&mut self, options: &Value, flags: u8) -> AssertionResponse {
+            let auth_data = self.auth_data(options["rpId"].as_str().unwrap(), flags);
+            let client_data = self.client_data("webauthn.get", options);
+            let mut msg = auth_data.clone();
+            msg.extend_from_slice(&Sha256::digest(&client_data));
+            let rng = SystemRandom::new();
+            let sig = self.key_pair.sign(&rng, &msg).unwrap();
+            AssertionResponse {
+                id: b64url(&self.credential_id),
+                response: AuthenticatorAssertion {
+                    client_data_json: b64url(&client_data),
+                    authenticator_data: b64url(&auth_data),
+                    signature: b64url(sig.as_ref()),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn passkey_ceremonies() -> Result<()> {
+        let rp = RelyingParty::from_config(&WebauthnOptions {
+            rp_id: Some("auth.localhost".into()),
+            rp_name: "oauth-lite".into(),
+            origin: Some("http://auth.localhost".into()),
+            max_user_age: 3600,
+        })
+        .unwrap();
+        let user = User {
+            username: "webauthn-test".into(),
+            attributes: vec![],
+        };
+        let mut authenticator = SoftAuthenticator::new("http://auth.localhost");
+
+        let options = rp.registration_options(&user, "session")?;
+        rp.register(&user, "session", &authenticator.create(&options))?;
+
+        // Second factor only needs user presence
+        let options = rp.login_options(Some("webauthn-test"), "session")?;
+        let assertion = authenticator.get(&options, FLAG_USER_PRESENT);
+        assert_eq!(
+            rp.login("session", &assertion, false)?.username,
+            user.username
+        );
+
+        // Passwordless needs user verification, and challenges can't be reused
+        let options = rp.login_options(None, "session")?;
+        let assertion = authenticator.get(&options, FLAG_USER_PRESENT);
+        assert!(rp.login("session", &assertion, true).is_err());
+        assert!(rp.login("session", &assertion, true).is_err());
+        let options = rp.login_options(None, "session")?;
+        let assertion = authenticator.get(&options, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        assert!(rp.login("other-session", &assertion, true).is_err());
+        let options = rp.login_options(None, "session")?;
+        let mut assertion = authenticator.get(&options, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        assertion.response.signature = b64url(&[0u8; 70]);
+        assert!(rp.login("session", &assertion, true).is_err());
+        let options = rp.login_options(None, "session")?;
+        let assertion = authenticator.get(&options, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        assert_eq!(
+            rp.login("session", &assertion, true)?.username,
+            user.username
+        );
+
+        // Stale user details need a password login before passwordless works again
+        let mut creds = WebauthnCredentials::get(user.username.to_string())?.unwrap();
+        creds.user_at -= 7200;
+        creds.save()?;
+        let options = rp.login_options(None, "session")?;
+        let assertion = authenticator.get(&options, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        assert!(rp.login("session", &assertion, true).is_err());
+        WebauthnCredentials::refresh_user(&user)?;
+        let options = rp.login_options(None, "session")?;
+        let assertion = authenticator.get(&options, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        assert!(rp.login("session", &assertion, true).is_ok());
+
+        WebauthnCredentials::get(user.username.to_string())?
+            .map(|c| c.delete())
+            .transpose()?;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
 #[macro_use]
 extern crate warp;
 
@@ -10,6 +12,9 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "bench")]
+extern crate test;
+
 use warp::{http::StatusCode, Filter};
 
 mod api;
@@ -37,34 +42,73 @@ fn main() {
 }
 
 fn configure() -> Result<()> {
+    oauth::check_issuer()?;
+    data::store()?;
+    for ring in data::keyring::Ring::ALL {
+        data::keyring::ring(*ring)?;
+    }
+    oauth::prepare_data()?;
     OAuthEndpoint::add_clients();
+    login::upstream()?;
+    oauth::access_rules()?;
+    oauth::sweeper::start(&CONFIG.session);
     Ok(())
 }
 
 fn serve() -> Result<()> {
+    if CONFIG.storage.reencrypt {
+        let count = data::reencrypt()?;
+        info!("Re-encrypted {} records", count);
+        return data::clean();
+    }
+    if CONFIG.keys.rotate {
+        for ring in data::keyring::Ring::ALL {
+            match data::keyring::rotate(*ring, &CONFIG.keys)? {
+                Some(id) => info!("Rotated the {:?} keys, now signing with {}", ring, id),
+                None => warn!("The {:?} key is supplied, rotate it where it is kept", ring),
+            }
+        }
+        return data::clean();
+    }
     configure()?;
     let log = warp::log("oauth");
 
     let get_routes = warp::get2().and(
         api::get_authorize()
             .or(api::get_authenticate())
-            .or(api::get_userdetail()),
+            .or(api::get_userdetail())
+            .or(api::get_logout())
+            .or(api::get_jwks())
+            .or(api::get_upstream())
+            .or(api::get_webauthn()),
     );
 
     let post_routes = warp::post2().and(
         api::post_authorize()
             .or(api::post_refresh())
-            .or(api::post_token()),
+            .or(api::post_token())
+            .or(api::post_logout())
+            .or(api::post_totp())
+            .or(api::post_webauthn()),
     );
 
     let serve_files = warp::fs::dir("www/");
 
-    let routes = get_routes
+    let routes = api::passthrough()
+        .or(api::ext_authz())
+        .or(get_routes)
         .or(post_routes)
         .or(serve_files)
         .or(warp::any().map(|| StatusCode::from_u16(404).unwrap()));
 
-    warp::serve(routes.with(log)).run(([0, 0, 0, 0], 3030));
+    let server = warp::serve(routes.with(log)).bind(([0, 0, 0, 0], 3030));
+    let grpc_addr = CONFIG.forward_auth.grpc_addr;
+    hyper::rt::run(hyper::rt::lazy(move || {
+        if let Some(addr) = grpc_addr {
+            hyper::rt::spawn(api::ext_authz_grpc(addr));
+        }
+        server
+    }));
     data::clean()?;
     Ok(())
 }
@@ -0,0 +1,279 @@
+use {
+    super::{return_to::domain_matches, SessionData},
+    crate::{login::User, Error, Result},
+    percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS},
+    serde::Deserialize,
+    std::{collections::HashMap, fs::File, path::Path},
+};
+
+/// Characters escaped again when a normalized path is passed on
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+lazy_static! {
+    static ref RULES: Result<Vec<AccessRule>> = match &crate::CONFIG.forward_auth.access_rules {
+        Some(path) => load(path),
+        None => Ok(vec![]),
+    };
+}
+
+/// Who may see the pages under a path of a site. Requirements that are set
+/// must all be met, a user matching any of the listed values is enough.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessRule {
+    /// `example.com` only matches itself, `.example.com` also matches its
+    /// subdomains, rules without a host apply to every site
+    pub host: Option<String>,
+    /// Path prefix matched on whole segments, `/` when left out
+    pub path: Option<String>,
+    /// Let anyone in, logged in or not
+    pub public: bool,
+    pub users: Vec<String>,
+    /// Groups from the --forward-auth-groups-attr attribute
+    pub groups: Vec<String>,
+    pub attributes: HashMap<String, Vec<String>>,
+    /// Require a session that passed a second factor
+    pub mfa: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Allowed,
+    LoginRequired,
+    Denied(String),
+}
+
+impl AccessRule {
+    fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/")
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(domain), Some(host)) => domain_matches(host, domain),
+            (Some(_), None) => false,
+        };
+        let prefix = self.path().trim_end_matches('/');
+        host_matches
+            && path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    }
+
+    /// Longer paths win, then rules naming the exact host over wildcards
+    fn specificity(&self) -> (usize, usize) {
+        let host = match &self.host {
+            None => 0,
+            Some(h) if h.starts_with('.') => 1,
+            Some(_) => 2,
+        };
+        (self.path().trim_end_matches('/').len(), host)
+    }
+
+    /// Why the user may not pass, if they may not
+    fn denial(&self, user: &User, mfa: bool) -> Option<String> {
+        let config = &crate::CONFIG.forward_auth;
+        if !self.users.is_empty() && !self.users.iter().any(|u| u == user.username()) {
+            return Some("user not allowed".into());
+        }
+        if !self.groups.is_empty()
+            && !self
+                .groups
+                .iter()
+                .any(|g| user.member_of(&config.groups_attr, g))
+        {
+            return Some(format!("not a member of {}", self.groups.join(", ")));
+        }
+        for (attr, allowed) in self.attributes.iter() {
+            let values = user.attribute(attr).map(|v| v.as_slice()).unwrap_or(&[]);
+            if !values.iter().any(|v| allowed.contains(v)) {
+                return Some(format!("attribute {} not allowed", attr));
+            }
+        }
+        if self.mfa && !mfa {
+            return Some("second factor required".into());
+        }
+        None
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<AccessRule>> {
+    let file = File::open(path).map_err(|e| {
+        Error::Service(format!(
+            "Unable to read access rules {} -> {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let rules: Vec<AccessRule> = serde_yaml::from_reader(file)?;
+    if let Some(rule) = rules.iter().find(|r| !r.path().starts_with('/')) {
+        return Err(Error::Service(format!(
+            "Access rule paths must start with '/', found {}",
+            rule.path()
+        )));
+    }
+    info!("Loaded {} access rules", rules.len());
+    Ok(rules)
+}
+
+pub fn rules() -> Result<&'static [AccessRule]> {
+    RULES.as_ref().map(|r| r.as_slice()).map_err(|e| e.clone())
+}
+
+/// Path as the upstream ends up serving it: percent-decoded, without empty and
+/// `.` segments and with `..` taking the segment before it along. Rules are
+/// matched against this, so `/health/../admin` or `/%61dmin` can't get past an
+/// `/admin` rule. `None` when it doesn't decode to UTF-8.
+pub fn normalize(path: &str) -> Option<String> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut segments = vec![];
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && decoded.ends_with('/') {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// The normalized path of a URI escaped again, with the query left as it is
+pub fn canonical(uri: &str) -> Option<String> {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+    let mut canonical = utf8_percent_encode(&normalize(path)?, PATH).to_string();
+    if let Some(query) = query {
+        canonical.push('?');
+        canonical.push_str(query);
+    }
+    Some(canonical)
+}
+
+/// The most specific rule for the page
+fn find<'a>(rules: &'a [AccessRule], host: Option<&str>, path: &str) -> Option<&'a AccessRule> {
+    rules
+        .iter()
+        .filter(|r| r.matches(host, path))
+        .max_by_key(|r| r.specificity())
+}
+
+/// Whether the session may see the page, pages without a rule are open to
+/// every logged in user
+pub fn check(host: Option<&str>, uri: &str, sd: &SessionData) -> Result<Access> {
+    let host = host.map(|h| h.split(':').next().unwrap_or_default().to_lowercase());
+    let path = match normalize(uri.split('?').next().unwrap_or_default()) {
+        Some(path) => path,
+        None => return Ok(Access::Denied("malformed path".into())),
+    };
+    let rule = find(rules()?, host.as_deref(), &path);
+    let access = match (rule, &sd.user) {
+        (Some(rule), _) if rule.public => Access::Allowed,
+        (_, None) => Access::LoginRequired,
+        (None, Some(_)) => Access::Allowed,
+        (Some(rule), Some(user)) => match rule.denial(user, sd.mfa) {
+            Some(reason) => {
+                warn!(
+                    target: "audit",
+                    "Denied {} access to {}{}: {}",
+                    user.username(),
+                    host.as_deref().unwrap_or_default(),
+                    uri,
+                    reason
+                );
+                Access::Denied(reason)
+            }
+            None => Access::Allowed,
+        },
+    };
+    Ok(access)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn applies_most_specific_rule() {
+        let rules: Vec<AccessRule> = serde_yaml::from_str(
+            r#"
+- host: .example.com
+  groups: [staff]
+- host: app.example.com
+  path: /admin
+  users: [jdoe]
+  mfa: true
+- path: /health
+  public: true
+"#,
+        )
+        .unwrap();
+        let host = Some("app.example.com");
+        assert!(find(&rules, host, "/administrator").unwrap().groups.len() == 1);
+        assert!(find(&rules, host, "/admin/users").unwrap().mfa);
+        assert!(find(&rules, host, "/health").unwrap().public);
+        assert!(find(&rules, Some("other.org"), "/").is_none());
+
+        let user: User = serde_json::from_value(serde_json::json!({
+            "username": "jdoe",
+            "attributes": [["memberOf", ["cn=staff,ou=groups,dc=example,dc=com"]]],
+        }))
+        .unwrap();
+        assert!(rules[0].denial(&user, false).is_none());
+        assert_eq!(
+            rules[1].denial(&user, false),
+            Some("second factor required".into())
+        );
+        assert!(rules[1].denial(&user, true).is_none());
+    }
+
+    #[test]
+    fn matches_normalized_paths() {
+        let rules: Vec<AccessRule> = serde_yaml::from_str(
+            r#"
+- path: /admin
+  users: [root]
+- path: /health
+  public: true
+"#,
+        )
+        .unwrap();
+        for path in &[
+            "/health/../admin",
+            "/health/%2e%2e/admin",
+            "/health%2F..%2Fadmin",
+            "/%61dmin",
+            "//admin",
+            "/./admin/",
+            "/../admin/users",
+        ] {
+            let normalized = normalize(path).unwrap();
+            assert!(!find(&rules, None, &normalized).unwrap().public, "{}", path);
+        }
+        assert_eq!(normalize("/a//b/./c/../d/").unwrap(), "/a/b/d/");
+        assert_eq!(normalize("").unwrap(), "/");
+        assert!(normalize("/%ff").is_none());
+        assert_eq!(
+            canonical("/health/../a%20b?next=/../x").unwrap(),
+            "/a%20b?next=/../x"
+        );
+        assert_eq!(canonical("/%61dmin//users").unwrap(), "/admin/users");
+    }
+}
@@ -1,4 +1,8 @@
-use {super::*, crate::data::Persistable, chrono::prelude::*};
+use {
+    super::*,
+    crate::data::{hash_token, Persistable},
+    chrono::prelude::*,
+};
 
 #[derive(Clone)]
 pub struct AuthorizationRegistry;
@@ -8,7 +12,7 @@ impl Authorizer for AuthorizationRegistry {
         let mut token = Tokens::from(&grant.owner_id)?;
         let new_token = Tokens::gen_id();
         token.authorizations.push((
-            new_token.to_string(),
+            hash_token(&new_token)?,
             Utc::now()
                 .checked_add_signed(chrono::Duration::seconds(3600))
                 .unwrap(),
@@ -0,0 +1,223 @@
+use {
+    super::{return_to::domain_matches, AuthRequest, SessionData, TokenRegistry},
+    crate::{data::Persistable, login::User, Error, Result},
+    oxide_auth::primitives::issuer::Issuer,
+    parking_lot::Mutex,
+    sha2::{Digest, Sha256},
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
+};
+
+lazy_static! {
+    /// Users of recent Basic logins by a hash of their credentials
+    static ref BASIC_CACHE: Mutex<HashMap<Vec<u8>, (User, Instant)>> = Mutex::new(HashMap::new());
+}
+
+/// Session for the credentials in the Authorization header, for clients that
+/// can't follow the browser login. `None` when there are none or the host
+/// doesn't accept them, an `Authentication` error when they are wrong.
+pub fn session(req: &AuthRequest) -> Result<Option<SessionData>> {
+    let config = &crate::CONFIG.forward_auth;
+    let header = match &req.0.authorization_header {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let host = req
+        .host()
+        .map(|h| h.split(':').next().unwrap_or_default().to_lowercase());
+    let accepts = |hosts: &[String]| match &host {
+        Some(host) => hosts.iter().any(|d| domain_matches(host, d)),
+        None => false,
+    };
+    let clients = bearer_clients(host.as_deref(), &config.bearer_hosts);
+    let mut parts = header.splitn(2, ' ');
+    let scheme = parts.next().unwrap_or_default();
+    let value = parts.next().unwrap_or_default().trim();
+    if scheme.eq_ignore_ascii_case("Bearer") && !clients.is_empty() {
+        bearer(value, &clients).map(Some)
+    } else if scheme.eq_ignore_ascii_case("Basic") && accepts(&config.basic_hosts) {
+        let mut sd = SessionData::anonymous(req.0.client_ip);
+        sd.user = Some(basic(value, req)?);
+        Ok(Some(sd))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Clients whose access tokens the host accepts
+fn bearer_clients<'a>(host: Option<&str>, hosts: &'a [(String, String)]) -> Vec<&'a str> {
+    match host {
+        Some(host) => hosts
+            .iter()
+            .filter(|(d, _)| domain_matches(host, d))
+            .map(|(_, c)| c.as_str())
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The session an access token of one of `clients` was issued under
+fn bearer(token: &str, clients: &[&str]) -> Result<SessionData> {
+    let grant = TokenRegistry
+        .recover_token(token)
+        .map_err(|_| Error::Service("Failed to look up access token".into()))?
+        .ok_or_else(|| Error::Authentication("Unknown or expired access token".into()))?;
+    if !clients.contains(&grant.client_id.as_str()) {
+        return Err(Error::Authentication(format!(
+            "Access tokens of {} aren't accepted here",
+            grant.client_id
+        )));
+    }
+    SessionData::get(grant.owner_id)?
+        .filter(|sd| sd.logged_in() && !sd.is_expired())
+        .ok_or_else(|| Error::Authentication("Access token of an ended session".into()))
+}
+
+/// Checks a Basic username and password against the directory, remembering
+/// successful logins for --forward-auth-basic-cache seconds
+fn basic(value: &str, req: &AuthRequest) -> Result<User> {
+    let decoded = base64::decode(value)
+        .ok()
+        .and_then(|d| String::from_utf8(d).ok())
+        .ok_or_else(|| Error::Authentication("Malformed Basic credentials".into()))?;
+    let mut parts = decoded.splitn(2, ':');
+    let (username, password) = match (parts.next(), parts.next()) {
+        (Some(u), Some(p)) if !u.is_empty() => (u, p),
+        _ => return Err(Error::Authentication("Malformed Basic credentials".into())),
+    };
+
+    let ttl = Duration::from_secs(crate::CONFIG.forward_auth.basic_cache);
+    let key = Sha256::digest(decoded.as_bytes()).to_vec();
+    if let Some(user) = cached(&key, ttl) {
+        return Ok(user);
+    }
+
+    if let Some(until) = crate::login::throttled(username, req.0.client_ip)? {
+        return Err(Error::Authentication(format!(
+            "Basic login for {} throttled until {}",
+            username, until
+        )));
+    }
+    match crate::login::login(username, password) {
+        Ok(user) => {
+            crate::login::record_success(username)?;
+            let user = without_second_factor(user)?;
+            if ttl > Duration::from_secs(0) {
+                BASIC_CACHE
+                    .lock()
+                    .insert(key, (user.clone(), Instant::now()));
+            }
+            Ok(user)
+        }
+        Err(Error::Authentication(e)) => {
+            crate::login::record_failure(username, req.0.client_ip)?;
+            Err(Error::Authentication(e))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// User of a Basic login made less than `ttl` ago
+fn cached(key: &[u8], ttl: Duration) -> Option<User> {
+    let mut cache = BASIC_CACHE.lock();
+    cache.retain(|_, (_, at)| at.elapsed() < ttl);
+    cache.get(key).map(|(user, _)| user.clone())
+}
+
+/// There is no way to ask for a second factor, such users need a token
+fn without_second_factor(user: User) -> Result<User> {
+    if crate::login::mfa_required(&user, "")? {
+        return Err(Error::Authentication(format!(
+            "Basic login for {} needs a second factor",
+            user.username()
+        )));
+    }
+    Ok(user)
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{login::TotpEnrollment, oauth::UserGrant},
+        chrono::Utc,
+    };
+
+    fn user(username: &str) -> User {
+        serde_json::from_value(serde_json::json!({ "username": username, "attributes": [] }))
+            .unwrap()
+    }
+
+    #[test]
+    fn binds_bearer_hosts_to_clients() {
+        let hosts = vec![
+            ("api.example.com".to_string(), "cli".to_string()),
+            (".example.org".to_string(), "cli".to_string()),
+            (".example.org".to_string(), "batch".to_string()),
+        ];
+        assert_eq!(bearer_clients(Some("api.example.com"), &hosts), vec!["cli"]);
+        assert_eq!(
+            bearer_clients(Some("app.example.org"), &hosts),
+            vec!["cli", "batch"]
+        );
+        assert!(bearer_clients(Some("www.example.com"), &hosts).is_empty());
+        assert!(bearer_clients(None, &hosts).is_empty());
+    }
+
+    #[test]
+    fn rejects_tokens_of_other_clients() -> Result<()> {
+        let sd = SessionData::new(Some(user("bearer-test")))?;
+        let grant = UserGrant {
+            owner_id: sd.id.to_string(),
+            client_id: "cli".into(),
+            scope: "default".into(),
+            redirect_uri: "http://localhost/cb".parse().unwrap(),
+            until: Utc::now() + chrono::Duration::hours(1),
+        };
+        grant.save()?;
+        let token = TokenRegistry
+            .issue(grant.into())
+            .map_err(|_| Error::Service("Failed to issue token".into()))?
+            .token;
+        assert_eq!(bearer(&token, &["cli"])?.id, sd.id);
+        assert!(bearer(&token, &["batch"]).is_err());
+        assert!(bearer("unknown", &["cli"]).is_err());
+        sd.end(true)?;
+        assert!(bearer(&token, &["cli"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forgets_basic_logins_after_ttl() {
+        let ttl = Duration::from_secs(60);
+        let (fresh, stale) = (vec![1u8], vec![2u8]);
+        {
+            let mut cache = BASIC_CACHE.lock();
+            cache.insert(fresh.clone(), (user("fresh"), Instant::now()));
+            let old = Instant::now() - Duration::from_secs(61);
+            cache.insert(stale.clone(), (user("stale"), old));
+        }
+        assert_eq!(cached(&fresh, ttl).unwrap().username(), "fresh");
+        assert!(cached(&stale, ttl).is_none());
+        assert!(BASIC_CACHE.lock().get(&stale).is_none());
+    }
+
+    #[test]
+    fn refuses_basic_logins_needing_a_second_factor() -> Result<()> {
+        assert!(without_second_factor(user("basic-plain")).is_ok());
+        let enrollment = TotpEnrollment {
+            username: "basic-mfa".into(),
+            secret: String::new(),
+            confirmed: true,
+            last_step: 0,
+            recovery_codes: vec![],
+            pending: None,
+        };
+        enrollment.save()?;
+        assert!(without_second_factor(user("basic-mfa")).is_err());
+        enrollment.delete()?;
+        Ok(())
+    }
+}
@@ -1,10 +1,19 @@
 use {
-    super::*,
-    crate::{data::Persistable, Error},
+    super::{access::Access, *},
+    crate::{
+        args::ForwardAuthMode,
+        data::Persistable,
+        login::{
+            AssertionResponse, FederatedAuthenticator, PendingLogin, RegistrationResponse,
+            TotpEnrollment, User, WebauthnCredentials,
+        },
+        Error,
+    },
+    chrono::{DateTime, Utc},
     oxide_auth::{frontends::simple::endpoint::*, primitives::prelude::*},
     parking_lot::Mutex,
-    std::{str::FromStr, sync::Arc},
-    warp::http::Uri,
+    std::{collections::HashMap, net::IpAddr, str::FromStr, sync::Arc},
+    url::form_urlencoded,
 };
 
 pub use oxide_auth::primitives::grant::Grant;
@@ -34,6 +43,7 @@ impl OAuthEndpoint {
         access_token_flow(&*CLIENT_MAP, &mut ep.auth_map, &mut ep.issuer)
             .execute(req.clone())
             .map_err(|_| Error::Authentication("Authentication failure occurred".into()))
+            .and_then(with_id_token)
             .map(|r| r.with_request(req))
     }
 
@@ -63,47 +73,622 @@ impl OAuthEndpoint {
             .map(|r| r.with_request(req))
     }
 
+    /// Forward auth target for nginx `auth_request`, Traefik ForwardAuth and
+    /// Caddy `forward_auth`. Logged in users get their identity headers.
     pub fn authenticate(req: AuthRequest) -> Result<AuthResponse, Error> {
         let mut resp = AuthResponse::default();
+        let config = &crate::CONFIG.forward_auth;
+
+        // Traefik and Caddy pass the callbacks on in X-Forwarded-Uri
+        let param = |name: &str| {
+            req.0
+                .query
+                .get(name)
+                .cloned()
+                .or_else(|| match config.mode {
+                    ForwardAuthMode::Nginx => None,
+                    _ => req.0.forwarded.query_param(name),
+                })
+        };
+
+        // Handoff from the auth domain, logging the user in on this domain
+        if let Some(token) = param("handoff") {
+            if let Some((cookie, rd)) = handoff::redeem(&token, req.host())? {
+                resp.status = 302;
+                resp.headers.push(("Set-Cookie".into(), cookie));
+                resp.location = Some(rd);
+                return Ok(resp);
+            }
+        }
 
         // Validate a code
-        if let Some(code) = req.0.query.get("code") {
-            if let Some(t) = Tokens::authorize(code)? {
-                if let Some(sd) = SessionData::get(t.owner_id)? {
-                    resp.session = Some(Arc::new(Mutex::new(sd)));
+        if let Some(code) = param("code") {
+            if let Some(t) = Tokens::authorize(&code)? {
+                let session = SessionData::get(t.owner_id)?.filter(|sd| !sd.is_expired());
+                if let Some(sd) = session {
+                    let rd = param("state")
+                        .and_then(|s| return_to::from_state(&s))
+                        .unwrap_or_else(|| "/".into());
                     resp.status = 302;
-                    resp.location = Some("/".into());
+                    // Pages on other protected domains are reached through a handoff
+                    resp.location = Some(match handoff::redirect(&sd, &rd, req.host())? {
+                        Some(url) => url,
+                        None => rd,
+                    });
+                    if handoff::is_auth_domain(req.host()) {
+                        resp.session = Some(Arc::new(Mutex::new(sd)));
+                    } else {
+                        let cookie = handoff::cookie_for(&sd, req.host())?;
+                        resp.headers.push(("Set-Cookie".into(), cookie));
+                    }
                     return Ok(resp);
                 }
             }
         }
 
-        // New authorization request
-        if req.0.query.get("client_id").is_some() && req.0.query.get("response_type").is_some() {
-            let (c, r) = (
-                req.0.query.get("client_id"),
-                req.0.query.get("response_type"),
-            );
+        // New authorization request, the page to return to rides along in the state
+        if let (Some(c), Some(r)) = (
+            req.0.query.get("client_id"),
+            req.0.query.get("response_type"),
+        ) {
+            let rd = req
+                .0
+                .query
+                .get("rd")
+                .cloned()
+                .or_else(|| req.0.forwarded.original_url());
+            // Users already logged in here go straight back to the other domain
+            if let Some(rd) = &rd {
+                let sd = req.0.session.lock();
+                if sd.logged_in() {
+                    if let Some(url) = handoff::redirect(&sd, rd, req.host())? {
+                        resp.status = 302;
+                        resp.location = Some(url);
+                        return Ok(resp);
+                    }
+                }
+            }
+            let mut fragment = form_urlencoded::Serializer::new(String::new());
+            fragment
+                .append_pair("client_id", c)
+                .append_pair("response_type", r);
+            let state = rd.and_then(|rd| return_to::state(&rd));
+            if let Some(state) = state {
+                fragment.append_pair("state", &state);
+            }
             resp.status = 302;
-            resp.location = Some(format!(
-                "/#client_id={}&response_type={}",
-                c.unwrap(),
-                r.unwrap()
-            ));
+            resp.location = Some(format!("/#{}", fragment.finish()));
             return Ok(resp);
         }
 
-        // User is already authenticated
-        if req.0.session.lock().user.is_some() {
-            resp.status = 200;
+        if let Some(resp) = Self::check(&req)? {
+            return Ok(resp);
+        }
+
+        match (config.mode, &config.login_url) {
+            (ForwardAuthMode::Nginx, _) | (_, None) => {
+                resp.status = 403;
+                resp.body = Some("Authentication failed".into());
+                Ok(resp)
+            }
+            (_, Some(login_url)) => forward_login(&req, login_url),
+        }
+    }
+
+    /// Decides on the page a proxy asks about from the session cookie or the
+    /// credentials sent along, `None` when the user has to log in first
+    fn check(req: &AuthRequest) -> Result<Option<AuthResponse>, Error> {
+        let mut resp = AuthResponse::default();
+        let config = &crate::CONFIG.forward_auth;
+
+        // Tools that can't log in through the browser send credentials instead
+        let sd = if req.0.session.lock().logged_in() {
+            let mut sd = req.0.session.lock();
+            // Keeps the session from idling out while the user browses protected pages
+            if let Err(e) = sd.update() {
+                warn!("Error updating session -> {}", e);
+            }
+            sd.clone()
+        } else {
+            match credentials::session(req) {
+                Ok(Some(sd)) => sd,
+                Ok(None) => req.0.session.lock().clone(),
+                Err(Error::Authentication(e)) => {
+                    warn!("Forward auth credentials rejected! {}", e);
+                    resp.status = 401;
+                    resp.www_authenticate = Some("Basic realm=\"oauth-lite\"".into());
+                    return Ok(Some(resp));
+                }
+                Err(e) => {
+                    error!("Failed to check forward auth credentials! {}", e);
+                    resp.status = 500;
+                    return Ok(Some(resp));
+                }
+            }
+        };
+
+        // Check the access rules for the page the proxy asks about
+        let uri = req.0.forwarded.uri.as_deref().unwrap_or("/");
+        match access::check(req.0.forwarded.host.as_deref(), uri, &sd)? {
+            Access::Allowed => {
+                resp.status = 200;
+                if let Some(user) = &sd.user {
+                    resp.headers = forward_auth::identity_headers(user, config);
+                }
+            }
+            Access::Denied(_) => {
+                resp.status = 403;
+                resp.body = Some("Access denied".into());
+            }
+            Access::LoginRequired => return Ok(None),
+        }
+        Ok(Some(resp))
+    }
+
+    /// Envoy ext_authz check, 200 with the identity headers allows the request
+    /// and any other response is sent to the client as is
+    pub fn ext_authz(req: AuthRequest) -> Result<AuthResponse, Error> {
+        if let Some(resp) = Self::check(&req)? {
+            return Ok(resp);
+        }
+        match &crate::CONFIG.forward_auth.login_url {
+            Some(login_url) => forward_login(&req, login_url),
+            None => Ok(AuthResponse {
+                status: 401,
+                body: Some("Authentication required".into()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Ends the session, following OIDC RP-initiated logout when the client
+    /// sends an `id_token_hint` and a registered `post_logout_redirect_uri`.
+    /// Clients the session signed into are told through their logout URIs.
+    pub fn logout(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let params = req.0.urlbody.as_ref().unwrap_or(&req.0.query);
+        let hint = match params.get("id_token_hint") {
+            Some(token) => match jwt::decode::<IdToken>(token).and_then(|c| {
+                c.check(params.get("client_id").map(|c| c.as_str()))
+                    .map(|_| c)
+            }) {
+                Ok(claims) => Some(claims),
+                Err(e) => {
+                    warn!("Rejected logout id_token_hint -> {}", e);
+                    resp.status = 400;
+                    resp.body = Some("Invalid id_token_hint".into());
+                    return Ok(resp);
+                }
+            },
+            None => None,
+        };
+        let client_id = hint
+            .as_ref()
+            .map(|h| &h.aud)
+            .or_else(|| params.get("client_id"));
+
+        let redirect = match params.get("post_logout_redirect_uri") {
+            Some(uri) => {
+                let registered = crate::CONFIG
+                    .oauth
+                    .post_logout_redirect_uris
+                    .iter()
+                    .any(|(c, u)| Some(c) == client_id && u == uri);
+                if !registered {
+                    warn!("Unregistered post_logout_redirect_uri: {}", uri);
+                    resp.status = 400;
+                    resp.body = Some("Unregistered post_logout_redirect_uri".into());
+                    return Ok(resp);
+                }
+                let mut url: url::Url = uri
+                    .parse()
+                    .map_err(|e| Error::Service(format!("Invalid logout redirect -> {}", e)))?;
+                if let Some(state) = params.get("state") {
+                    url.query_pairs_mut().append_pair("state", state);
+                }
+                Some(url.to_string())
+            }
+            None => None,
+        };
+
+        let revoke = crate::CONFIG.oauth.logout_revoke_tokens;
+        let mut ended = vec![req.0.session.lock().clone()];
+        if let Some(hint) = hint.filter(|h| h.sid != ended[0].sid()) {
+            if let Some(hinted) = SessionData::by_sid(&hint.sid)? {
+                // The hinted session isn't the caller's, it only ends once the
+                // user confirms it on our own page
+                let confirmed =
+                    req.0.urlbody.is_some() && params.contains_key("confirm") && req.same_origin();
+                if !confirmed {
+                    resp.content_type = Some("text/html".into());
+                    resp.body = Some(logout::confirm_page(params));
+                    return Ok(resp);
+                }
+                ended.push(hinted);
+            }
+        }
+        let mut frames = vec![];
+        for sd in ended.iter() {
+            frames.extend(logout::propagate(sd));
+            sd.end(revoke)?;
+            debug!("Session {} logged out", sd.id);
+        }
+
+        resp.expire_cookie = true;
+        if !frames.is_empty() {
+            resp.content_type = Some("text/html".into());
+            resp.body = Some(logout::frontchannel_page(&frames, redirect.as_deref()));
             return Ok(resp);
         }
+        match redirect {
+            Some(location) => {
+                resp.status = 302;
+                resp.location = Some(location);
+            }
+            None => {
+                resp.content_type = Some("text/plain".into());
+                resp.body = Some("You have been logged out".into());
+            }
+        }
+        Ok(resp)
+    }
+
+    pub fn jwks() -> Result<AuthResponse, Error> {
+        Ok(AuthResponse {
+            content_type: Some("application/json".into()),
+            body: Some(jwt::jwks()?.to_string()),
+            ..Default::default()
+        })
+    }
+
+    pub fn upstream_provider() -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        match crate::login::upstream()? {
+            Some(upstream) => {
+                resp.content_type = Some("application/json".into());
+                resp.body = Some(serde_json::json!({ "name": upstream.name() }).to_string());
+            }
+            None => resp.status = 404,
+        }
+        Ok(resp)
+    }
+
+    pub fn upstream_login(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let upstream = match crate::login::upstream()? {
+            Some(u) => u,
+            None => {
+                resp.status = 404;
+                return Ok(resp);
+            }
+        };
+
+        // Remember the pending authorization request, bound to this session
+        let session_id = {
+            let mut sd = req.0.session.lock();
+            if !sd.is_persisted() && !sd.keep()? {
+                resp.status = 429;
+                drop(sd);
+                return Ok(resp.with_request(req));
+            }
+            sd.id.clone()
+        };
+        let pending = PendingLogin::start(&session_id, encode_query(&req.0.query))?;
+        resp.status = 302;
+        resp.location = Some(upstream.authorize_url(&pending.state));
+        Ok(resp.with_request(req))
+    }
+
+    pub fn upstream_callback(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let upstream = match crate::login::upstream()? {
+            Some(u) => u,
+            None => {
+                resp.status = 404;
+                return Ok(resp);
+            }
+        };
+
+        let pending = match req.0.query.get("state") {
+            Some(state) => PendingLogin::take(state)?,
+            None => None,
+        };
+        let pending = match pending {
+            Some(p) if p.session_id == req.0.session.lock().id => p,
+            _ => {
+                warn!("Upstream login returned with an unknown state");
+                resp.status = 403;
+                resp.body = Some("Authentication failed".into());
+                return Ok(resp);
+            }
+        };
+
+        let user = match req.0.query.get("code") {
+            Some(code) => upstream.complete(code),
+            None => Err(Error::Authentication(format!(
+                "Upstream provider returned an error: {}",
+                req.0
+                    .query
+                    .get("error")
+                    .map(|e| e.as_str())
+                    .unwrap_or("none")
+            ))),
+        };
+        resp.status = 302;
+        match user {
+            Ok(user) => {
+                debug!("Upstream user found: {:?}", user);
+                WebauthnCredentials::refresh_user(&user)?;
+                let client_id = form_urlencoded::parse(pending.query.as_bytes())
+                    .find(|(k, _)| k == "client_id")
+                    .map(|(_, v)| v.to_string())
+                    .unwrap_or_default();
+                let mut sd = req.0.session.lock();
+                if crate::login::mfa_required(&user, &client_id)? {
+                    let step = second_factor_step(&user);
+                    sd.pending = Some(user);
+                    resp.location = Some(format!("/#{}&mfa={}", pending.query, step));
+                } else {
+                    sd.user = Some(user);
+                    resp.location = Some(format!("/authorize?{}", pending.query));
+                }
+                sd.rotate()?;
+            }
+            Err(e) => {
+                warn!("Upstream login attempt failed! {}", e);
+                resp.location = Some(format!("/#{}", pending.query));
+            }
+        }
+        Ok(resp.with_request(req))
+    }
+
+    pub fn totp_enroll(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        if !req.same_origin() {
+            warn!("Refused a cross-site TOTP enrollment");
+            resp.status = 403;
+            resp.body = Some("Cross-site request refused".into());
+            return Ok(resp.with_request(req));
+        }
+        let user = {
+            let sd = req.0.session.lock();
+            match (&sd.user, &sd.pending) {
+                (Some(user), _) => Some(user.clone()),
+                // Halfway through a login only users without any second factor may enroll,
+                // otherwise a password would be enough to get past a passkey
+                (None, Some(user)) if crate::login::second_factors(user)?.is_empty() => {
+                    Some(user.clone())
+                }
+                _ => None,
+            }
+        };
+        let user = match user {
+            Some(user) => user,
+            None => {
+                resp.status = 403;
+                resp.body = Some("Authentication failed".into());
+                return Ok(resp.with_request(req));
+            }
+        };
+        if let Some(until) = throttled(user.username(), req.0.client_ip) {
+            warn!(
+                "TOTP enrollment for {} throttled until {}",
+                user.username(),
+                until
+            );
+            resp.status = 429;
+            return Ok(resp.with_request(req));
+        }
+        let code = req.0.urlbody.as_ref().and_then(|b| b.get("totp"));
+        match TotpEnrollment::enroll(user.username(), code.map(|c| c.as_str()))? {
+            Some((secret, recovery_codes)) => {
+                let issuer = &crate::CONFIG.mfa.totp_issuer;
+                resp.content_type = Some("application/json".into());
+                resp.body = Some(
+                    serde_json::json!({
+                        "secret": secret,
+                        "uri": TotpEnrollment::uri(issuer, user.username(), &secret),
+                        "recovery_codes": recovery_codes,
+                    })
+                    .to_string(),
+                );
+            }
+            None => {
+                warn!(
+                    "TOTP re-enrollment without a valid code for {}",
+                    user.username()
+                );
+                if code.is_some() {
+                    crate::login::record_failure(user.username(), req.0.client_ip)
+                        .unwrap_or_default();
+                }
+                resp.status = 403;
+                resp.body = Some("The current code is needed to replace the enrollment".into());
+            }
+        }
+        Ok(resp.with_request(req))
+    }
+
+    pub fn totp_confirm(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let user = req.0.session.lock().user.clone();
+        let user = match user {
+            Some(user) => user,
+            None => {
+                resp.status = 403;
+                return Ok(resp.with_request(req));
+            }
+        };
+        let code = match req.0.urlbody.as_ref().and_then(|b| b.get("totp")) {
+            Some(code) => code,
+            None => {
+                resp.status = 400;
+                return Ok(resp.with_request(req));
+            }
+        };
+        if let Some(until) = throttled(user.username(), req.0.client_ip) {
+            warn!(
+                "TOTP confirmation for {} throttled until {}",
+                user.username(),
+                until
+            );
+            resp.status = 429;
+            return Ok(resp.with_request(req));
+        }
+        if crate::login::verify_second_factor(&user, code)? {
+            crate::login::record_success(user.username()).unwrap_or_default();
+            resp.status = 200;
+        } else {
+            warn!("TOTP confirmation failed for {}", user.username());
+            crate::login::record_failure(user.username(), req.0.client_ip).unwrap_or_default();
+            resp.status = 400;
+        }
+        Ok(resp.with_request(req))
+    }
 
-        resp.status = 403;
-        resp.body = Some("Authentication failed".into());
+    pub fn webauthn_provider() -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        match crate::login::relying_party() {
+            Some(rp) => {
+                resp.content_type = Some("application/json".into());
+                resp.body = Some(serde_json::json!({ "rp_id": rp.id() }).to_string());
+            }
+            None => resp.status = 404,
+        }
         Ok(resp)
     }
 
+    pub fn webauthn_register_options(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let user = req.0.session.lock().user.clone();
+        match (crate::login::relying_party(), user) {
+            (Some(rp), Some(user)) => {
+                let session_id = req.0.session.lock().id.clone();
+                let options = rp.registration_options(&user, &session_id)?;
+                resp.content_type = Some("application/json".into());
+                resp.body = Some(options.to_string());
+            }
+            (None, _) => resp.status = 404,
+            (_, None) => resp.status = 403,
+        }
+        Ok(resp.with_request(req))
+    }
+
+    pub fn webauthn_register(
+        req: AuthRequest,
+        credential: RegistrationResponse,
+    ) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let user = req.0.session.lock().user.clone();
+        match (crate::login::relying_party(), user) {
+            (Some(rp), Some(user)) => {
+                let session_id = req.0.session.lock().id.clone();
+                if let Err(e) = rp.register(&user, &session_id, &credential) {
+                    warn!("Passkey registration failed! {}", e);
+                    resp.status = 400;
+                }
+            }
+            (None, _) => resp.status = 404,
+            (_, None) => resp.status = 403,
+        }
+        Ok(resp.with_request(req))
+    }
+
+    pub fn webauthn_login_options(req: AuthRequest) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let rp = match crate::login::relying_party() {
+            Some(rp) => rp,
+            None => {
+                resp.status = 404;
+                return Ok(resp);
+            }
+        };
+        let (session_id, pending) = {
+            // The challenge is bound to the session, so it has to be kept
+            let mut sd = req.0.session.lock();
+            if !sd.is_persisted() && !sd.keep()? {
+                resp.status = 429;
+                drop(sd);
+                return Ok(resp.with_request(req));
+            }
+            (sd.id.clone(), sd.pending.clone())
+        };
+        let options = rp.login_options(pending.as_ref().map(|u| u.username()), &session_id)?;
+        resp.content_type = Some("application/json".into());
+        resp.body = Some(options.to_string());
+        Ok(resp.with_request(req))
+    }
+
+    /// Completes a passkey login, either as the second factor of a pending
+    /// login or as a passwordless login
+    pub fn webauthn_login(
+        req: AuthRequest,
+        assertion: AssertionResponse,
+    ) -> Result<AuthResponse, Error> {
+        let mut resp = AuthResponse::default();
+        let rp = match crate::login::relying_party() {
+            Some(rp) => rp,
+            None => {
+                resp.status = 404;
+                return Ok(resp);
+            }
+        };
+        {
+            let mut sd = req.0.session.lock();
+            let pending = sd.pending.clone();
+            // Passwordless logins are counted against the owner of the passkey. The
+            // credential ID is chosen by the client, so for an unknown passkey, which
+            // can't log anyone in, only the address counter holds anything back
+            let username = match pending {
+                Some(ref p) => p.username().to_string(),
+                None => match WebauthnCredentials::owner(&assertion.id)? {
+                    Some(owner) => owner,
+                    None => format!("passkey:{}", assertion.id),
+                },
+            };
+            if let Some(until) = throttled(&username, req.0.client_ip) {
+                warn!("Passkey login for {} throttled until {}", username, until);
+                resp.status = 429;
+                drop(sd);
+                return Ok(resp.with_request(req));
+            }
+            let login = rp.login(&sd.id, &assertion, pending.is_none());
+            match login {
+                Ok(_) => crate::login::record_success(&username).unwrap_or_default(),
+                Err(Error::Authentication(_)) => {
+                    crate::login::record_failure(&username, req.0.client_ip).unwrap_or_default();
+                }
+                Err(_) => {}
+            }
+            match login {
+                Ok(user) => match pending {
+                    Some(ref p) if p.username() != user.username() => {
+                        warn!("Passkey of {} used for {}", user.username(), p.username());
+                        resp.status = 403;
+                    }
+                    // Keep the fresh details from the password login
+                    Some(p) => {
+                        sd.pending = None;
+                        sd.user = Some(p);
+                        sd.mfa = true;
+                        sd.rotate()?;
+                    }
+                    // User verification on the authenticator makes it a second factor
+                    None => {
+                        sd.user = Some(user);
+                        sd.mfa = true;
+                        sd.rotate()?;
+                    }
+                },
+                Err(e) => {
+                    warn!("Passkey login failed! {}", e);
+                    resp.status = 403;
+                }
+            }
+        }
+        Ok(resp.with_request(req))
+    }
+
     pub fn add_clients() {
         for (client_id, url) in crate::CONFIG.oauth.client_ids.iter() {
             let new_client = Client::public(
@@ -113,6 +698,17 @@ impl OAuthEndpoint {
             );
             CLIENT_MAP.0.lock().register_client(new_client);
         }
+        let config = &crate::CONFIG.oauth;
+        if config.enable_passthrough {
+            for (domain, _) in config.pass_through_domains.iter() {
+                let new_client = Client::public(
+                    domain.as_str(),
+                    passthrough::redirect_uri(domain).parse().unwrap(),
+                    "default".parse().unwrap(),
+                );
+                CLIENT_MAP.0.lock().register_client(new_client);
+            }
+        }
     }
 }
 
@@ -145,7 +741,9 @@ impl ResponseMappings
                     body: None,
                     content_type: None,
                     session: None,
+                    expire_cookie: false,
                     www_authenticate: None,
+                    headers: vec![],
                 }
             }
         }
@@ -153,57 +751,413 @@ impl ResponseMappings
     }
 }
 
-fn solicitor(req: &mut AuthRequest, _: &PreGrant) -> OwnerConsent<AuthResponse> {
-    let mut failed = false;
+fn solicitor(req: &mut AuthRequest, grant: &PreGrant) -> OwnerConsent<AuthResponse> {
+    let mut error = None;
     let mut sd = req.0.session.lock();
-    if sd.logged_in() {
-        return OwnerConsent::Authorized(sd.id.clone());
+    if let Some(user) = sd.user.clone() {
+        // Logged in without a second factor, which this client may require
+        match (sd.mfa, crate::login::mfa_required(&user, &grant.client_id)) {
+            (true, _) | (false, Ok(false)) => return authorized(&mut sd, grant),
+            (false, Ok(true)) => {
+                debug!(
+                    "{} needs a second factor for {}",
+                    user.username(),
+                    grant.client_id
+                );
+                sd.user = None;
+                sd.pending = Some(user.clone());
+                if let Err(e) = sd.store() {
+                    drop(sd);
+                    return unstored(e);
+                }
+                drop(sd);
+                return redirect_to_second_factor(&user, req);
+            }
+            (false, Err(e)) => {
+                warn!("Second factor lookup failed! {}", e);
+                drop(sd);
+                return redirect_to_login(Some("invalid"), req);
+            }
+        }
     }
     if let Some(body) = &req.0.urlbody {
+        if let (Some(code), Some(user)) = (body.get("totp"), sd.pending.clone()) {
+            if let Some(until) = throttled(user.username(), req.0.client_ip) {
+                warn!(
+                    "Second factor for {} throttled until {}",
+                    user.username(),
+                    until
+                );
+                drop(sd);
+                return redirect_to_login(Some("locked"), req);
+            }
+            match crate::login::verify_second_factor(&user, code) {
+                Ok(true) => {
+                    debug!("Second factor passed for {}", user.username());
+                    crate::login::record_success(user.username()).unwrap_or_default();
+                    sd.pending = None;
+                    sd.user = Some(user);
+                    sd.mfa = true;
+                    if let Err(e) = sd.rotate() {
+                        drop(sd);
+                        return unstored(e);
+                    }
+                    return authorized(&mut sd, grant);
+                }
+                Ok(false) => {
+                    warn!("Second factor failed for {}", user.username());
+                    crate::login::record_failure(user.username(), req.0.client_ip)
+                        .unwrap_or_default();
+                }
+                Err(e) => warn!("Second factor check failed! {}", e),
+            }
+            drop(sd);
+            return redirect_to_second_factor(&user, req);
+        }
         if let (Some(username), Some(password)) = (body.get("login"), body.get("password")) {
+            if let Some(until) = throttled(username, req.0.client_ip) {
+                warn!("Login for {} throttled until {}", username, until);
+                drop(sd);
+                return redirect_to_login(Some("locked"), req);
+            }
             match crate::login::login(username, password) {
                 Ok(user) => {
                     debug!("User found: {:?}", user);
-                    sd.user = Some(user);
-                    sd.save().unwrap_or_default();
-                    return OwnerConsent::Authorized(sd.id.clone());
+                    WebauthnCredentials::refresh_user(&user).unwrap_or_default();
+                    match crate::login::mfa_required(&user, &grant.client_id) {
+                        Ok(false) => {
+                            crate::login::record_success(username).unwrap_or_default();
+                            sd.user = Some(user);
+                            if let Err(e) = sd.rotate() {
+                                drop(sd);
+                                return unstored(e);
+                            }
+                            return authorized(&mut sd, grant);
+                        }
+                        Ok(true) => {
+                            sd.pending = Some(user.clone());
+                            if let Err(e) = sd.rotate() {
+                                drop(sd);
+                                return unstored(e);
+                            }
+                            drop(sd);
+                            return redirect_to_second_factor(&user, req);
+                        }
+                        Err(e) => {
+                            warn!("Second factor lookup failed! {}", e);
+                            error = Some("invalid");
+                        }
+                    }
+                }
+                Err(Error::Authentication(e)) => {
+                    warn!("Login attempt failed! {}", e);
+                    crate::login::record_failure(username, req.0.client_ip).unwrap_or_default();
+                    error = Some("invalid");
                 }
                 Err(e) => {
                     warn!("Login attempt failed! {}", e);
-                    failed = true;
+                    error = Some("invalid");
                 }
             }
         }
     }
     drop(sd); //Drop the lock before calling this
-    redirect_to_login(failed, &req)
+    redirect_to_login(error, req)
 }
 
-fn redirect_to_login(_: bool, req: &AuthRequest) -> OwnerConsent<AuthResponse> {
-    let mut builder = Uri::builder();
-    let root_path = "/";
-    if !req.0.query.is_empty() {
-        let path = format!(
-            "{}#{}",
-            root_path,
-            req.0
-                .query
-                .iter()
-                .map(|(k, v)| format!("{}={}", k, v))
-                .collect::<Vec<String>>()
-                .join("&")
-        );
-        builder.path_and_query(path.as_str());
-    } else {
-        builder.path_and_query(root_path);
+/// Sends the user to --forward-auth-login-url, coming back to the page they asked for
+fn forward_login(req: &AuthRequest, login_url: &str) -> Result<AuthResponse, Error> {
+    let mut url: url::Url = login_url
+        .parse()
+        .map_err(|e| Error::Service(format!("Invalid forward auth login URL -> {}", e)))?;
+    if let Some(rd) = req.0.forwarded.original_url() {
+        url.query_pairs_mut().append_pair("rd", &rd);
     }
-    let response = AuthResponse {
+    Ok(AuthResponse {
+        status: 302,
+        location: Some(url.to_string()),
+        ..Default::default()
+    })
+}
+
+/// Remembers the client the session signed into so logouts can be propagated to it
+fn authorized(sd: &mut SessionData, grant: &PreGrant) -> OwnerConsent<AuthResponse> {
+    if !sd.clients.contains(&grant.client_id) {
+        sd.clients.push(grant.client_id.clone());
+    }
+    sd.save().unwrap_or_default();
+    OwnerConsent::Authorized(sd.id.clone())
+}
+
+/// Failing open if the attempt counters can't be read, an unusable database
+/// already stops logins from going anywhere
+fn throttled(username: &str, ip: Option<IpAddr>) -> Option<DateTime<Utc>> {
+    crate::login::throttled(username, ip).unwrap_or_else(|e| {
+        warn!("Failed to check login attempts! {}", e);
+        None
+    })
+}
+
+/// Adds an ID token for the logged in user to a successful token response
+fn with_id_token(mut resp: AuthResponse) -> Result<AuthResponse, Error> {
+    let mut body: serde_json::Value = match (resp.status, &resp.body) {
+        (200, Some(body)) => serde_json::from_str(body)?,
+        _ => return Ok(resp),
+    };
+    let tokens = match body["access_token"].as_str() {
+        Some(token) => Tokens::access(token)?,
+        None => None,
+    };
+    if let Some(tokens) = tokens {
+        let session = SessionData::get(tokens.owner_id.to_string())?;
+        let grant = UserGrant::get(tokens.owner_id.to_string())?;
+        if let (Some(sd), Some(grant)) = (session, grant) {
+            if let Some(user) = &sd.user {
+                let claims = IdToken::new(user.username(), &grant.client_id, &sd.sid());
+                body["id_token"] = jwt::encode(&claims)?.into();
+                resp.body = Some(body.to_string());
+            }
+        }
+    }
+    Ok(resp)
+}
+
+fn encode_query(query: &HashMap<String, String>) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query.iter())
+        .finish()
+}
+
+/// Answers 429 when the session can't be stored because its client address
+/// holds too many already, any other storage failure fails the request
+fn unstored(e: Error) -> OwnerConsent<AuthResponse> {
+    warn!("Failed to store session! {}", e);
+    match e {
+        Error::Authentication(_) => OwnerConsent::InProgress(AuthResponse {
+            status: 429,
+            ..Default::default()
+        }),
+        _ => OwnerConsent::Error(OAuthError::PrimitiveError),
+    }
+}
+
+fn redirect_to_login(error: Option<&str>, req: &AuthRequest) -> OwnerConsent<AuthResponse> {
+    OwnerConsent::InProgress(login_page(req, None, error))
+}
+
+fn redirect_to_second_factor(user: &User, req: &AuthRequest) -> OwnerConsent<AuthResponse> {
+    OwnerConsent::InProgress(login_page(req, Some(&second_factor_step(user)), None))
+}
+
+/// The login page step asking for a second factor, users without one are
+/// enrolled in TOTP first
+fn second_factor_step(user: &User) -> String {
+    match crate::login::second_factors(user) {
+        Ok(ref f) if f.is_empty() => "totp-enroll".into(),
+        Ok(f) => f.join(","),
+        Err(_) => "totp".into(),
+    }
+}
+
+fn login_page(req: &AuthRequest, mfa: Option<&str>, error: Option<&str>) -> AuthResponse {
+    // The login page reads the authorization request from the fragment, which
+    // Uri would drop, so the location is put together by hand
+    let mut fragment = form_urlencoded::Serializer::new(String::new());
+    fragment.extend_pairs(
+        req.0
+            .query
+            .iter()
+            .filter(|(k, _)| k.as_str() != "mfa" && k.as_str() != "error"),
+    );
+    if let Some(step) = mfa {
+        fragment.append_pair("mfa", step);
+    }
+    if let Some(error) = error {
+        fragment.append_pair("error", error);
+    }
+    let fragment = fragment.finish();
+    let location = if fragment.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/#{}", fragment)
+    };
+    AuthResponse {
         status: 303,
         session: None,
+        expire_cookie: false,
         body: None,
-        location: Some(builder.build().unwrap().to_string()),
+        location: Some(location),
         content_type: None,
         www_authenticate: None,
-    };
-    OwnerConsent::InProgress(response)
+        headers: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, serde_json::json, std::net::SocketAddr, warp::http::HeaderMap};
+
+    fn user(username: &str) -> User {
+        serde_json::from_value(json!({ "username": username, "attributes": [] })).unwrap()
+    }
+
+    /// Request from our own login page carrying the cookie of the session
+    fn request(sd: &SessionData) -> Result<AuthRequest, Error> {
+        form(sd, &[])
+    }
+
+    fn form(sd: &SessionData, fields: &[(&str, &str)]) -> Result<AuthRequest, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Origin", crate::CONFIG.oauth.issuer.parse().unwrap());
+        let body = match fields {
+            [] => None,
+            fields => Some(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+        };
+        Ok(AuthRequest::new(
+            String::new(),
+            headers,
+            body,
+            Some(sd.signed_token()?),
+            None,
+        ))
+    }
+
+    #[test]
+    fn refuses_totp_enrollment_past_a_passkey() -> Result<(), Error> {
+        let user = user("enroll-passkey");
+        let mut sd = SessionData::new(None)?;
+        sd.pending = Some(user.clone());
+        sd.update()?;
+        assert_eq!(OAuthEndpoint::totp_enroll(request(&sd)?)?.status, 200);
+        TotpEnrollment::get(user.username().to_string())?
+            .unwrap()
+            .delete()?;
+
+        let credentials: WebauthnCredentials = serde_json::from_value(json!({
+            "username": user.username(),
+            "user": user,
+            "passkeys": [{
+                "id": "passkey",
+                "public_key": "",
+                "sign_count": 0,
+                "created": Utc::now(),
+            }],
+        }))?;
+        credentials.save()?;
+        assert_eq!(OAuthEndpoint::totp_enroll(request(&sd)?)?.status, 403);
+        assert!(TotpEnrollment::get(user.username().to_string())?.is_none());
+        credentials.delete()?;
+        sd.delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn throttles_totp_confirmation() -> Result<(), Error> {
+        let sd = SessionData::new(Some(user("confirm-throttled")))?;
+        let wrong = [("totp", "000000")];
+        assert_eq!(OAuthEndpoint::totp_confirm(form(&sd, &wrong)?)?.status, 400);
+        assert_eq!(OAuthEndpoint::totp_confirm(form(&sd, &wrong)?)?.status, 429);
+        crate::login::record_success("confirm-throttled")?;
+        sd.delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn counts_passkey_failures_against_the_owner() -> Result<(), Error> {
+        let credentials: WebauthnCredentials = serde_json::from_value(json!({
+            "username": "passkey-owner",
+            "user": user("passkey-owner"),
+            "passkeys": [{
+                "id": "owned-passkey",
+                "public_key": "",
+                "sign_count": 0,
+                "created": Utc::now(),
+            }],
+        }))?;
+        credentials.save()?;
+        let sd = SessionData::new(None)?;
+        // Answers a registration challenge, which no login accepts
+        let client_data = base64::encode_config(
+            &json!({ "type": "webauthn.create", "challenge": "", "origin": "" }).to_string(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let assertion = |id: &str| -> AssertionResponse {
+            serde_json::from_value(json!({
+                "id": id,
+                "response": {
+                    "clientDataJSON": client_data,
+                    "authenticatorData": "",
+                    "signature": "",
+                },
+            }))
+            .unwrap()
+        };
+        let resp = OAuthEndpoint::webauthn_login(request(&sd)?, assertion("owned-passkey"))?;
+        assert_eq!(resp.status, 403);
+        assert!(throttled("passkey-owner", None).is_some());
+        assert!(throttled("passkey:owned-passkey", None).is_none());
+        crate::login::record_success("passkey-owner")?;
+        credentials.delete()?;
+        sd.delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn answers_429_at_the_session_limit() -> Result<(), Error> {
+        let remote: SocketAddr = "192.0.2.37:4000".parse().unwrap();
+        let anonymous =
+            || AuthRequest::new(String::new(), HeaderMap::new(), None, None, Some(remote));
+        assert_eq!(crate::CONFIG.session.max_per_ip, 1);
+        assert_eq!(
+            OAuthEndpoint::webauthn_login_options(anonymous())?.status,
+            200
+        );
+        assert_eq!(
+            OAuthEndpoint::webauthn_login_options(anonymous())?.status,
+            429
+        );
+        SessionData::delete_all(|sd| sd.ip == Some(remote.ip()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn answers_429_when_stepping_up_at_the_session_limit() -> Result<(), Error> {
+        let ip: IpAddr = "192.0.2.38".parse().unwrap();
+        let user = user("step-up-limit");
+        let credentials: WebauthnCredentials = serde_json::from_value(json!({
+            "username": user.username(),
+            "user": user,
+            "passkeys": [{
+                "id": "passkey",
+                "public_key": "",
+                "sign_count": 0,
+                "created": Utc::now(),
+            }],
+        }))?;
+        credentials.save()?;
+        SessionData::anonymous(Some(ip)).store()?;
+
+        // A session that was never stored, as one logging in straight away is
+        let mut sd = SessionData::anonymous(Some(ip));
+        sd.user = Some(user);
+        let mut req = request(&sd)?;
+        *req.0.session.lock() = sd;
+        let grant = PreGrant {
+            client_id: "step-up".into(),
+            redirect_uri: "https://app.example.com/callback".parse().unwrap(),
+            scope: SCOPE[0].clone(),
+        };
+        match solicitor(&mut req, &grant) {
+            OwnerConsent::InProgress(resp) => assert_eq!(resp.status, 429),
+            _ => panic!("Expected a 429 response"),
+        }
+        credentials.delete()?;
+        SessionData::delete_all(|sd| sd.ip == Some(ip))?;
+        Ok(())
+    }
 }
@@ -0,0 +1,69 @@
+use {
+    crate::{
+        args::ForwardAuthOptions,
+        login::{group_name, User},
+    },
+    warp::http::header::{HeaderName, HeaderValue},
+};
+
+/// Headers telling the protected upstream who the user is. Values that can't
+/// be sent as a header are left out rather than failing the request.
+pub fn identity_headers(user: &User, config: &ForwardAuthOptions) -> Vec<(String, String)> {
+    let mut headers = vec![(config.user_header.to_string(), user.username().to_string())];
+    if let Some(email) = user.attribute(&config.email_attr).and_then(|v| v.first()) {
+        headers.push((config.email_header.to_string(), email.to_string()));
+    }
+    if let Some(groups) = user.attribute(&config.groups_attr) {
+        let names: Vec<&str> = groups.iter().map(|g| group_name(g)).collect();
+        headers.push((config.groups_header.to_string(), names.join(",")));
+    }
+    for (attr, header) in config.headers.iter() {
+        if let Some(values) = user.attribute(attr) {
+            headers.push((header.to_string(), values.join(",")));
+        }
+    }
+    headers.retain(|(name, value)| {
+        let valid =
+            HeaderName::from_bytes(name.as_bytes()).is_ok() && HeaderValue::from_str(value).is_ok();
+        if !valid {
+            warn!("Skipping identity header {} for {}", name, user.username());
+        }
+        valid
+    });
+    headers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_identity_headers() {
+        let user: User = serde_json::from_value(serde_json::json!({
+            "username": "jdoe",
+            "attributes": [
+                ["mail", ["jdoe@example.com"]],
+                ["memberOf", ["cn=admins,ou=groups,dc=example,dc=com", "users"]],
+                ["uid", ["1000"]],
+                ["cn", ["J\u{f6}rg\nDoe"]],
+            ],
+        }))
+        .unwrap();
+        let config = ForwardAuthOptions {
+            headers: vec![
+                ("uid".into(), "X-Auth-Uid".into()),
+                ("cn".into(), "X-Auth-Name".into()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            identity_headers(&user, &config),
+            vec![
+                ("X-Auth-User".to_string(), "jdoe".to_string()),
+                ("X-Auth-Email".to_string(), "jdoe@example.com".to_string()),
+                ("X-Auth-Groups".to_string(), "admins,users".to_string()),
+                ("X-Auth-Uid".to_string(), "1000".to_string()),
+            ]
+        );
+    }
+}
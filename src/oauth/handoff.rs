@@ -0,0 +1,221 @@
+use {
+    super::{return_to, DomainSession, SessionData},
+    crate::{data::Persistable, Error, Result},
+    chrono::prelude::*,
+    serde::{Deserialize, Serialize},
+    url::Url,
+};
+
+/// Seconds the browser has to carry a handoff over to the protected domain
+const HANDOFF_TTL: i64 = 60;
+
+/// One-time ticket taking a logged in user from the auth domain to a
+/// protected domain, where it is traded for a cookie of that domain
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handoff {
+    pub id: String,
+    pub session_id: String,
+    pub domain: String,
+    pub rd: String,
+    pub exp: i64,
+}
+
+impl Persistable for Handoff {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "handoffs"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.id.to_string()
+    }
+}
+
+impl Handoff {
+    pub fn purge_expired() -> Result<u64> {
+        let now = Utc::now().timestamp();
+        Self::delete_all(|h| h.exp <= now)
+    }
+}
+
+/// Host without the port, lowercased
+pub fn domain(host: &str) -> String {
+    host.split(':').next().unwrap_or_default().to_lowercase()
+}
+
+/// --oauth-issuer when it isn't given
+const DEFAULT_ISSUER: &str = "http://localhost:3030";
+
+/// Refuses an issuer no login could work with, as sessions only get a cookie on
+/// its host. The default is only good enough for local development over HTTP.
+pub fn check_issuer(issuer: &str, insecure: bool) -> Result<()> {
+    if Url::parse(issuer).map_or(true, |u| u.host_str().is_none()) {
+        return Err(Error::Service(format!(
+            "--oauth-issuer {} has no host to set the session cookie on",
+            issuer
+        )));
+    }
+    if issuer == DEFAULT_ISSUER && !insecure {
+        return Err(Error::Service(format!(
+            "--oauth-issuer is still the default {}, the session cookie is only set on its \
+             host. Set it to the URL users reach this server on, or turn on --cookie-insecure \
+             for local development",
+            DEFAULT_ISSUER
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the host shares the central session cookie, which is only the
+/// case for the host of --oauth-issuer. An unknown host never is.
+pub fn is_auth_domain(host: Option<&str>) -> bool {
+    let issuer = Url::parse(&crate::CONFIG.oauth.issuer)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_lowercase()));
+    match (host, issuer) {
+        (Some(host), Some(issuer)) => domain(host) == issuer,
+        _ => false,
+    }
+}
+
+/// Cookie of the host the request came in on, a domain session standing in
+/// for the central one on protected domains
+pub fn cookie_for(sd: &SessionData, host: Option<&str>) -> Result<String> {
+    match host {
+        Some(host) if is_auth_domain(Some(host)) => sd.cookie_string(),
+        Some(host) => DomainSession::create(sd, &domain(host))?.cookie_string(sd),
+        None => Err(Error::Authentication(
+            "No host to issue the session cookie for".into(),
+        )),
+    }
+}
+
+/// URL handing the session over to the protected domain `rd` is on, `None`
+/// when `rd` is on the current host, the auth domain or not allowed
+pub fn redirect(sd: &SessionData, rd: &str, host: Option<&str>) -> Result<Option<String>> {
+    let mut url = match Url::parse(rd) {
+        Ok(url) => url,
+        Err(_) => return Ok(None),
+    };
+    let target = match url.host_str() {
+        Some(h) => h.to_lowercase(),
+        None => return Ok(None),
+    };
+    if host.map(domain).as_deref() == Some(target.as_str())
+        || is_auth_domain(Some(&target))
+        || !return_to::allowed(rd)
+    {
+        return Ok(None);
+    }
+    let handoff = Handoff {
+        id: Handoff::gen_id(),
+        session_id: sd.id.to_string(),
+        domain: target,
+        rd: rd.to_string(),
+        exp: Utc::now().timestamp() + HANDOFF_TTL,
+    };
+    handoff.save()?;
+    url.set_path("/authenticate");
+    url.set_fragment(None);
+    url.set_query(None);
+    url.query_pairs_mut()
+        .append_pair("handoff", &handoff.signed_token()?);
+    Ok(Some(url.to_string()))
+}
+
+/// Trades a handoff for a cookie of the domain it was issued for, returning
+/// the cookie and the page to go on to
+pub fn redeem(token: &str, host: Option<&str>) -> Result<Option<(String, String)>> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 2 {
+        return Ok(None);
+    }
+    let handoff = match Handoff::get(parts[0].to_string())? {
+        Some(handoff) => handoff,
+        None => return Ok(None),
+    };
+    // Used up whether it is honoured or not
+    handoff.delete()?;
+    if handoff.verify(parts[1]).is_err() {
+        warn!("Handoff signature validation failed for {}", handoff.domain);
+        return Ok(None);
+    }
+    if handoff.exp <= Utc::now().timestamp() {
+        debug!("Handoff to {} has expired", handoff.domain);
+        return Ok(None);
+    }
+    if host.map(domain).as_deref() != Some(handoff.domain.as_str()) {
+        warn!(
+            "Handoff for {} presented to {}",
+            handoff.domain,
+            host.unwrap_or("an unknown host")
+        );
+        return Ok(None);
+    }
+    let sd = SessionData::get(handoff.session_id.to_string())?
+        .filter(|sd| sd.logged_in() && !sd.is_expired());
+    Ok(match sd {
+        Some(sd) => Some((cookie_for(&sd, host)?, handoff.rd)),
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redeems_handoffs_once() -> Result<()> {
+        let user: crate::login::User =
+            serde_json::from_value(serde_json::json!({"username": "jdoe", "attributes": []}))?;
+        let sd = SessionData::new(Some(user))?;
+        let handoff = Handoff {
+            id: Handoff::gen_id(),
+            session_id: sd.id.to_string(),
+            domain: "app.example.org".into(),
+            rd: "https://app.example.org/page".into(),
+            exp: Utc::now().timestamp() + HANDOFF_TTL,
+        };
+        handoff.save()?;
+        let token = handoff.signed_token()?;
+
+        let (cookie, rd) = redeem(&token, Some("App.example.org:443"))?.unwrap();
+        assert_eq!(rd, "https://app.example.org/page");
+        let value = cookie
+            .split(';')
+            .next()
+            .unwrap()
+            .splitn(2, '=')
+            .last()
+            .unwrap();
+        assert_eq!(
+            SessionData::get_cookie(value, Some("app.example.org"))?
+                .unwrap()
+                .id,
+            sd.id
+        );
+        assert!(SessionData::get_cookie(value, Some("evil.example.org"))?.is_none());
+        assert!(redeem(&token, Some("app.example.org"))?.is_none());
+
+        handoff.save()?;
+        assert!(redeem(&token, Some("evil.example.org"))?.is_none());
+        assert!(redeem(&token, Some("app.example.org"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_issuers_without_a_cookie_host() {
+        assert!(check_issuer("https://auth.example.com", false).is_ok());
+        assert!(check_issuer(DEFAULT_ISSUER, true).is_ok());
+        assert!(check_issuer(DEFAULT_ISSUER, false).is_err());
+        assert!(check_issuer("auth.example.com", true).is_err());
+    }
+
+    #[test]
+    fn only_the_issuer_is_the_auth_domain() {
+        assert!(is_auth_domain(Some("LOCALHOST:3030")));
+        assert!(!is_auth_domain(Some("app.example.org")));
+        assert!(!is_auth_domain(None));
+    }
+}
@@ -0,0 +1,221 @@
+use {
+    crate::{
+        args::OauthOptions,
+        data::keyring::{self, Ring},
+        Error, Result,
+    },
+    chrono::prelude::*,
+    rand::{rngs::OsRng, RngCore},
+    ring::{
+        rand::SystemRandom,
+        signature::{self, EcdsaKeyPair},
+    },
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    serde_json::{json, Value},
+};
+
+/// Seconds an ID token is valid for
+const ID_TOKEN_TTL: i64 = 3600;
+
+/// Claims of the OIDC ID token handed out alongside access tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdToken {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    /// Session the token was issued under, used by RP-initiated logout
+    pub sid: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+impl IdToken {
+    pub fn new(username: &str, client_id: &str, session_id: &str) -> Self {
+        let now = Utc::now().timestamp();
+        IdToken {
+            iss: crate::CONFIG.oauth.issuer.to_string(),
+            sub: username.to_string(),
+            aud: client_id.to_string(),
+            sid: session_id.to_string(),
+            iat: now,
+            exp: now + ID_TOKEN_TTL,
+        }
+    }
+
+    /// Checks an ID token a client sent back, such as a logout hint, is one
+    /// of ours issued to a known client, `client_id` when it is given
+    pub fn check(&self, client_id: Option<&str>) -> Result<()> {
+        self.check_at(&crate::CONFIG.oauth, client_id, Utc::now().timestamp())
+    }
+
+    fn check_at(&self, config: &OauthOptions, client_id: Option<&str>, now: i64) -> Result<()> {
+        if self.iss != config.issuer {
+            return Err(Error::Authentication("ID token of another issuer".into()));
+        }
+        if self.exp < now {
+            return Err(Error::Authentication("ID token has expired".into()));
+        }
+        let known = config.client_ids.iter().any(|(c, _)| *c == self.aud)
+            || config
+                .pass_through_domains
+                .iter()
+                .any(|(d, _)| *d == self.aud);
+        if !known || client_id.filter(|c| *c != self.aud).is_some() {
+            return Err(Error::Authentication(format!(
+                "ID token of unexpected client {}",
+                self.aud
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Claims of the logout token POSTed to back-channel logout URIs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutToken {
+    pub iss: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    pub aud: String,
+    pub iat: i64,
+    pub jti: String,
+    pub sid: String,
+    pub events: Value,
+}
+
+impl LogoutToken {
+    pub fn new(username: Option<&str>, client_id: &str, session_id: &str) -> Self {
+        let mut jti = [0u8; 16];
+        OsRng.fill_bytes(&mut jti);
+        LogoutToken {
+            iss: crate::CONFIG.oauth.issuer.to_string(),
+            sub: username.map(|u| u.to_string()),
+            aud: client_id.to_string(),
+            iat: Utc::now().timestamp(),
+            jti: b64(&jti),
+            sid: session_id.to_string(),
+            events: json!({ "http://schemas.openid.net/event/backchannel-logout": {} }),
+        }
+    }
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(data: &str) -> Result<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| Error::Authentication("Malformed JWT".into()))
+}
+
+/// Uncompressed P-256 point, the last 65 bytes of the PKCS#8 document
+fn public_key(pkcs8: &[u8]) -> &[u8] {
+    &pkcs8[pkcs8.len() - 65..]
+}
+
+/// Signs the claims as an ES256 JWT with the newest key
+pub fn encode<T: Serialize>(claims: &T) -> Result<String> {
+    let keys = keyring::ring(Ring::Jwt)?;
+    let current = keys.current();
+    let pkcs8 = &current.key;
+    let header = json!({
+        "alg": "ES256",
+        "typ": "JWT",
+        "kid": current.id,
+    });
+    let message = format!(
+        "{}.{}",
+        b64(&serde_json::to_vec(&header)?),
+        b64(&serde_json::to_vec(claims)?)
+    );
+    let rng = SystemRandom::new();
+    let key_pair =
+        EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+            .map_err(|_| Error::Service("Stored JWT key is corrupt".into()))?;
+    let sig = key_pair
+        .sign(&rng, message.as_bytes())
+        .map_err(|_| Error::Service("Failed to sign JWT".into()))?;
+    Ok(format!("{}.{}", message, b64(sig.as_ref())))
+}
+
+/// Checks the signature of a JWT issued by this server and returns its
+/// claims, expiry is left to the caller as some uses accept expired tokens
+pub fn decode<T: DeserializeOwned>(token: &str) -> Result<T> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(Error::Authentication("Malformed JWT".into()));
+    }
+    let header: Value = serde_json::from_slice(&unb64(parts[0])?)?;
+    let kid = header["kid"].as_str().unwrap_or_default();
+    let keys = keyring::find(Ring::Jwt, |k| k.id == kid)?;
+    let pkcs8 = &keys
+        .first()
+        .ok_or_else(|| Error::Authentication("JWT signed with an unknown key".into()))?
+        .key;
+    let message = format!("{}.{}", parts[0], parts[1]);
+    signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, public_key(pkcs8))
+        .verify(message.as_bytes(), &unb64(parts[2])?)
+        .map_err(|_| Error::Authentication("Invalid JWT signature".into()))?;
+    Ok(serde_json::from_slice(&unb64(parts[1])?)?)
+}
+
+/// The public keys that aren't retired as a JWK set so clients can verify our
+/// tokens, including those signed before the last rotation
+pub fn jwks() -> Result<Value> {
+    let keys: Vec<Value> = keyring::ring(Ring::Jwt)?
+        .keys
+        .iter()
+        .map(|k| {
+            let point = public_key(&k.key);
+            json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "use": "sig",
+                "alg": "ES256",
+                "kid": k.id,
+                "x": b64(&point[1..33]),
+                "y": b64(&point[33..]),
+            })
+        })
+        .collect();
+    Ok(json!({ "keys": keys }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signs_and_verifies() -> Result<()> {
+        let token = encode(&IdToken::new("test", "default", "session"))?;
+        let claims: IdToken = decode(&token)?;
+        assert_eq!(claims.sub, "test");
+        assert_eq!(claims.sid, "session");
+
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = b64(br#"{"sub":"admin"}"#);
+        parts[1] = &forged;
+        assert!(decode::<Value>(&parts.join(".")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn checks_id_token_hints() {
+        let config = OauthOptions {
+            issuer: crate::CONFIG.oauth.issuer.to_string(),
+            client_ids: vec![("app".into(), "http://app.localhost/cb".into())],
+            ..Default::default()
+        };
+        let claims = IdToken::new("test", "app", "session");
+        let now = claims.iat;
+        assert!(claims.check_at(&config, None, now).is_ok());
+        assert!(claims.check_at(&config, Some("app"), now).is_ok());
+        assert!(claims.check_at(&config, Some("other"), now).is_err());
+        assert!(claims
+            .check_at(&config, None, now + ID_TOKEN_TTL + 1)
+            .is_err());
+        assert!(IdToken::new("test", "other", "session")
+            .check_at(&config, None, now)
+            .is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use {
+    super::{jwt, jwt::LogoutToken, SessionData},
+    crate::{data::Persistable, Result},
+    chrono::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::{collections::HashMap, thread, time::Duration},
+};
+
+/// Seconds to wait for a client to acknowledge a back-channel logout
+const DELIVERY_TIMEOUT: u64 = 10;
+
+/// A back-channel logout that could not be delivered after all retries,
+/// kept as an audit trail of clients that may still hold the session
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FailedLogout {
+    pub id: String,
+    pub client_id: String,
+    pub uri: String,
+    pub sid: String,
+    pub sub: Option<String>,
+    pub attempts: u32,
+    pub error: String,
+    pub ts: DateTime<Utc>,
+}
+
+impl Persistable for FailedLogout {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "failed_logouts"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.id.to_string()
+    }
+}
+
+/// Notifies the clients the session signed into that it has ended. Logout
+/// tokens are sent to back-channel URIs in the background, the front-channel
+/// URIs to load in iframes are returned.
+pub fn propagate(sd: &SessionData) -> Vec<String> {
+    let config = &crate::CONFIG.oauth;
+    let sub = sd.user.as_ref().map(|u| u.username());
+    let mut frames = vec![];
+    for client_id in sd.clients.iter() {
+        for (_, uri) in config
+            .backchannel_logout_uris
+            .iter()
+            .filter(|(c, _)| c == client_id)
+        {
+            match jwt::encode(&LogoutToken::new(sub, client_id, &sd.sid())) {
+                Ok(token) => deliver(client_id, uri, &sd.sid(), sub, token),
+                Err(e) => warn!("Failed to create logout token for {} -> {}", client_id, e),
+            }
+        }
+        for (_, uri) in config
+            .frontchannel_logout_uris
+            .iter()
+            .filter(|(c, _)| c == client_id)
+        {
+            match uri.parse::<url::Url>() {
+                Ok(mut url) => {
+                    url.query_pairs_mut()
+                        .append_pair("iss", &config.issuer)
+                        .append_pair("sid", &sd.sid());
+                    frames.push(url.to_string());
+                }
+                Err(e) => warn!("Invalid front-channel logout URI {} -> {}", uri, e),
+            }
+        }
+    }
+    frames
+}
+
+fn deliver(client_id: &str, uri: &str, sid: &str, sub: Option<&str>, token: String) {
+    let mut failed = FailedLogout {
+        id: FailedLogout::gen_id(),
+        client_id: client_id.to_string(),
+        uri: uri.to_string(),
+        sid: sid.to_string(),
+        sub: sub.map(|s| s.to_string()),
+        attempts: 0,
+        error: String::new(),
+        ts: Utc::now(),
+    };
+    thread::spawn(move || {
+        let retries = crate::CONFIG.oauth.logout_retries;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                thread::sleep(Duration::from_secs(1 << (attempt - 1).min(6)));
+            }
+            failed.attempts += 1;
+            match send(&failed.uri, &token) {
+                Ok(()) => {
+                    debug!("Back-channel logout delivered to {}", failed.uri);
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "Back-channel logout to {} failed, attempt {} -> {}",
+                        failed.uri, failed.attempts, e
+                    );
+                    failed.error = e.to_string();
+                }
+            }
+        }
+        failed.ts = Utc::now();
+        error!(
+            "Giving up on back-channel logout of session {} at {}",
+            failed.sid, failed.uri
+        );
+        if let Err(e) = failed.save() {
+            error!("Failed to record failed logout -> {}", e);
+        }
+    });
+}
+
+fn send(uri: &str, token: &str) -> Result<()> {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(DELIVERY_TIMEOUT))
+        .build()?
+        .post(uri)
+        .form(&[("logout_token", token)])
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Page loading the front-channel logout iframes before moving on to `redirect`
+pub fn frontchannel_page(frames: &[String], redirect: Option<&str>) -> String {
+    let iframes: String = frames
+        .iter()
+        .map(|f| {
+            format!(
+                r#"<iframe style="display: none" src="{}"></iframe>"#,
+                escape(f)
+            )
+        })
+        .collect();
+    let script = match redirect {
+        Some(r) => format!(
+            "<script>window.onload = function() {{ window.location = {}; }}</script>",
+            serde_json::to_string(r)
+                .unwrap_or_default()
+                .replace("</", "<\\/")
+        ),
+        None => String::new(),
+    };
+    format!(
+        "<!DOCTYPE html><html><head><title>Logged out</title>{}</head>\
+         <body><p>You have been logged out</p>{}</body></html>",
+        script, iframes
+    )
+}
+
+/// Page asking the user to confirm a logout of a session other than their
+/// own, posting the logout request back with `confirm` added
+pub fn confirm_page(params: &HashMap<String, String>) -> String {
+    let fields: String = params
+        .iter()
+        .filter(|(k, _)| *k != "confirm")
+        .map(|(k, v)| {
+            format!(
+                r#"<input type="hidden" name="{}" value="{}">"#,
+                escape(k),
+                escape(v)
+            )
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html><html><head><title>Log out</title></head>\
+         <body><form method=\"post\" action=\"/logout\">{}\
+         <p>Do you want to log out?</p>\
+         <button type=\"submit\" name=\"confirm\" value=\"1\">Log out</button>\
+         </form></body></html>",
+        fields
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
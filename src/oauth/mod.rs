@@ -1,18 +1,31 @@
-use crate::Error;
+use crate::{
+    login::{AssertionResponse, RegistrationResponse},
+    Error,
+};
 use oxide_auth::endpoint::*;
 
+mod access;
 mod authorization_registry;
 mod client_registry;
+mod credentials;
 mod endpoint;
+mod forward_auth;
+mod handoff;
+mod jwt;
+mod logout;
+mod passthrough;
 mod request;
 mod response;
+mod return_to;
 mod session;
+pub mod sweeper;
 mod token_registry;
 mod user_grant;
 
 pub use {
-    authorization_registry::*, client_registry::*, endpoint::*, request::*, response::*,
-    session::*, token_registry::*, user_grant::*,
+    authorization_registry::*, client_registry::*, endpoint::*, jwt::IdToken,
+    passthrough::ProxyFuture, request::*, response::*, session::*, token_registry::*,
+    user_grant::*,
 };
 
 pub fn token(req: AuthRequest) -> Result<AuthResponse, Error> {
@@ -34,3 +47,102 @@ pub fn refresh(req: AuthRequest) -> Result<AuthResponse, Error> {
 pub fn authenticate(req: AuthRequest) -> Result<AuthResponse, Error> {
     OAuthEndpoint::authenticate(req)
 }
+
+pub fn ext_authz(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::ext_authz(req)
+}
+
+pub fn logout(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::logout(req)
+}
+
+pub fn jwks() -> Result<AuthResponse, Error> {
+    OAuthEndpoint::jwks()
+}
+
+/// Hashes the tokens earlier versions saved in the clear and indexes the
+/// tokens and sessions saved before there were indexes
+pub fn prepare_data() -> Result<(), Error> {
+    use crate::data::{migrate, Persistable};
+    migrate("hash_tokens", Tokens::hash_stored)?;
+    migrate("index_tokens", || {
+        info!("Indexed the tokens of {} sessions", Tokens::reindex()?);
+        Ok(())
+    })?;
+    migrate("index_sessions", || {
+        info!("Indexed {} sessions", SessionData::reindex()?);
+        Ok(())
+    })
+}
+
+/// Makes sure the session cookie has a host to be set on
+pub fn check_issuer() -> Result<(), Error> {
+    handoff::check_issuer(&crate::CONFIG.oauth.issuer, crate::CONFIG.cookie.insecure)
+}
+
+/// Loads the forward auth access rules, failing on a broken rules file
+pub fn access_rules() -> Result<(), Error> {
+    access::rules().map(|_| ())
+}
+
+pub fn passthrough_upstream(headers: &warp::http::HeaderMap) -> Option<(String, String)> {
+    passthrough::upstream(headers)
+}
+
+pub fn passthrough(
+    req: AuthRequest,
+    domain: &str,
+    upstream: &str,
+    method: warp::http::Method,
+    path: &str,
+    headers: warp::http::HeaderMap,
+    body: hyper::Body,
+) -> ProxyFuture {
+    passthrough::passthrough(req, domain, upstream, method, path, headers, body)
+}
+
+pub fn upstream_provider() -> Result<AuthResponse, Error> {
+    OAuthEndpoint::upstream_provider()
+}
+
+pub fn upstream_login(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::upstream_login(req)
+}
+
+pub fn upstream_callback(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::upstream_callback(req)
+}
+
+pub fn totp_enroll(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::totp_enroll(req)
+}
+
+pub fn totp_confirm(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::totp_confirm(req)
+}
+
+pub fn webauthn_provider() -> Result<AuthResponse, Error> {
+    OAuthEndpoint::webauthn_provider()
+}
+
+pub fn webauthn_register_options(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::webauthn_register_options(req)
+}
+
+pub fn webauthn_register(
+    req: AuthRequest,
+    credential: RegistrationResponse,
+) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::webauthn_register(req, credential)
+}
+
+pub fn webauthn_login_options(req: AuthRequest) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::webauthn_login_options(req)
+}
+
+pub fn webauthn_login(
+    req: AuthRequest,
+    assertion: AssertionResponse,
+) -> Result<AuthResponse, Error> {
+    OAuthEndpoint::webauthn_login(req, assertion)
+}
@@ -0,0 +1,242 @@
+use {
+    super::{
+        access::{self, Access},
+        cookie_name, forward_auth, AuthRequest, AuthResponse,
+    },
+    hyper::{client::HttpConnector, rt::lazy, Body, Client, Request, Uri},
+    url::form_urlencoded,
+    warp::{
+        http::{header, HeaderMap, HeaderValue, Method},
+        reply::Response,
+        Future, Rejection, Reply,
+    },
+};
+
+lazy_static! {
+    static ref CLIENT: Client<HttpConnector> = Client::new();
+}
+
+/// Headers that only apply to a single connection and are never forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub type ProxyFuture = Box<dyn Future<Item = Response, Error = Rejection> + Send>;
+
+/// The pass-through domain the request was sent to and its upstream
+pub fn upstream(headers: &HeaderMap) -> Option<(String, String)> {
+    let config = &crate::CONFIG.oauth;
+    if !config.enable_passthrough {
+        return None;
+    }
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    let host = host.split(':').next().unwrap_or_default();
+    config
+        .pass_through_domains
+        .iter()
+        .find(|(domain, _)| domain.eq_ignore_ascii_case(host))
+        .cloned()
+}
+
+/// Redirect URI registered for the client of a pass-through domain, the code
+/// is redeemed by /authenticate on the domain itself to set its cookie
+pub fn redirect_uri(domain: &str) -> String {
+    let scheme = url::Url::parse(&crate::CONFIG.oauth.issuer)
+        .map(|u| u.scheme().to_string())
+        .unwrap_or_else(|_| "http".into());
+    format!("{}://{}/authenticate", scheme, domain)
+}
+
+/// Proxies requests the access rules allow to the upstream of the domain,
+/// anonymous users are sent to log in and brought back to the page they asked for
+pub fn passthrough(
+    req: AuthRequest,
+    domain: &str,
+    upstream: &str,
+    method: Method,
+    path: &str,
+    headers: HeaderMap,
+    body: Body,
+) -> ProxyFuture {
+    // The rules are checked against the path as the upstream serves it, so
+    // exactly that path is what gets proxied
+    let canonical = match access::canonical(path) {
+        Some(path) => path,
+        None => {
+            let resp = AuthResponse {
+                status: 400,
+                body: Some("Bad request".into()),
+                ..Default::default()
+            }
+            .into_response();
+            return Box::new(lazy(move || Ok(resp)));
+        }
+    };
+    let path = canonical.as_str();
+    let (access, user) = {
+        let mut sd = req.0.session.lock();
+        if let Err(e) = sd.update() {
+            warn!("Error updating session -> {}", e);
+        }
+        (access::check(Some(domain), path, &sd), sd.user.clone())
+    };
+    let resp = match access {
+        Ok(Access::Allowed) => None,
+        Ok(Access::LoginRequired) => Some(login_redirect(domain, path)),
+        Ok(Access::Denied(_)) => Some(AuthResponse {
+            status: 403,
+            body: Some("Access denied".into()),
+            ..Default::default()
+        }),
+        Err(e) => {
+            error!("Failed to check access to {} -> {}", domain, e);
+            Some(AuthResponse {
+                status: 500,
+                ..Default::default()
+            })
+        }
+    };
+    if let Some(resp) = resp {
+        let resp = resp.into_response();
+        return Box::new(lazy(move || Ok(resp)));
+    }
+
+    let uri: Uri = match format!("{}{}", upstream.trim_end_matches('/'), path).parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            error!("Invalid upstream {} for {} -> {}", upstream, domain, e);
+            return Box::new(lazy(|| Ok(bad_gateway())));
+        }
+    };
+    let mut forwarded = forward_headers(&req, headers);
+    // Public pages are also proxied for anonymous users, without identity headers
+    if let Some(user) = user {
+        for (name, value) in forward_auth::identity_headers(&user, &crate::CONFIG.forward_auth) {
+            if let (Ok(name), Ok(value)) = (
+                name.parse::<header::HeaderName>(),
+                HeaderValue::from_str(&value),
+            ) {
+                forwarded.insert(name, value);
+            }
+        }
+    }
+    let mut proxied = Request::new(body);
+    *proxied.method_mut() = method;
+    *proxied.uri_mut() = uri;
+    *proxied.headers_mut() = forwarded;
+
+    let upstream = upstream.to_string();
+    Box::new(CLIENT.request(proxied).then(move |res| {
+        Ok(match res {
+            Ok(mut resp) => {
+                strip_hop_by_hop(resp.headers_mut());
+                resp
+            }
+            Err(e) => {
+                warn!("Failed to proxy to {} -> {}", upstream, e);
+                bad_gateway()
+            }
+        })
+    }))
+}
+
+fn login_redirect(domain: &str, path: &str) -> AuthResponse {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("client_id", domain)
+        .append_pair("response_type", "code")
+        .append_pair("rd", path)
+        .finish();
+    AuthResponse {
+        status: 302,
+        location: Some(format!(
+            "{}/authenticate?{}",
+            crate::CONFIG.oauth.issuer.trim_end_matches('/'),
+            query
+        )),
+        ..Default::default()
+    }
+}
+
+/// Request headers for the upstream. Identity headers sent by the client and
+/// our session cookie are dropped so the upstream can only see what we vouch for.
+fn forward_headers(req: &AuthRequest, mut headers: HeaderMap) -> HeaderMap {
+    let config = &crate::CONFIG.forward_auth;
+    strip_hop_by_hop(&mut headers);
+    let host = headers.remove(header::HOST);
+    let identity = [
+        &config.user_header,
+        &config.email_header,
+        &config.groups_header,
+    ];
+    for name in identity
+        .iter()
+        .map(|h| h.as_str())
+        .chain(config.headers.iter().map(|(_, h)| h.as_str()))
+    {
+        headers.remove(name);
+    }
+
+    let cookies: Vec<String> = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(';'))
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty() && c.split('=').next() != Some(cookie_name()))
+        .map(|c| c.to_string())
+        .collect();
+    headers.remove(header::COOKIE);
+    if let Ok(cookies) = HeaderValue::from_str(&cookies.join("; ")) {
+        if !cookies.is_empty() {
+            headers.insert(header::COOKIE, cookies);
+        }
+    }
+
+    let trusted = crate::CONFIG.general.trust_forwarded_headers;
+    if !trusted {
+        headers.remove("X-Forwarded-For");
+        headers.remove("X-Forwarded-Host");
+        headers.remove("X-Forwarded-Proto");
+    }
+    // A trusted X-Forwarded-For already ends with the client address
+    if let Some(ip) = req.0.client_ip {
+        if !headers.contains_key("X-Forwarded-For") {
+            if let Ok(value) = HeaderValue::from_str(&ip.to_string()) {
+                headers.insert("X-Forwarded-For", value);
+            }
+        }
+    }
+    if let Some(host) = host {
+        headers
+            .entry("X-Forwarded-Host")
+            .expect("valid header name")
+            .or_insert(host);
+    }
+    headers
+        .entry("X-Forwarded-Proto")
+        .expect("valid header name")
+        .or_insert_with(|| HeaderValue::from_static("http"));
+    headers
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+fn bad_gateway() -> Response {
+    AuthResponse {
+        status: 502,
+        body: Some("Bad gateway".into()),
+        ..Default::default()
+    }
+    .into_response()
+}
@@ -2,7 +2,11 @@ use {
     super::*,
     oxide_auth::frontends::dev::*,
     parking_lot::Mutex,
-    std::{collections::HashMap, sync::Arc},
+    std::{
+        collections::HashMap,
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+    },
     url::form_urlencoded,
     warp::http::HeaderMap,
 };
@@ -16,25 +20,107 @@ pub struct InnerAuthRequest {
     pub authorization_header: Option<String>,
     pub urlbody: Option<HashMap<String, String>>,
     pub cookie: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    pub host: Option<String>,
+    /// Origin, or else Referer, of the page that sent the request
+    pub origin: Option<String>,
+    pub forwarded: ForwardedRequest,
     pub session: Arc<Mutex<SessionData>>,
 }
 
+/// The original request a reverse proxy asks /authenticate about
+#[derive(Debug, Default, Clone)]
+pub struct ForwardedRequest {
+    pub proto: Option<String>,
+    pub host: Option<String>,
+    pub uri: Option<String>,
+}
+
+impl ForwardedRequest {
+    /// Read from the X-Forwarded-* headers set by Traefik and Caddy, or the
+    /// X-Original-URI nginx is usually configured to send. The host and scheme
+    /// decide which cookie counts, so they are only taken from a trusted proxy.
+    fn from_headers(headers: &HeaderMap, trusted: bool) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string())
+        };
+        ForwardedRequest {
+            proto: header("X-Forwarded-Proto").filter(|_| trusted),
+            host: header("X-Forwarded-Host").filter(|_| trusted),
+            uri: header("X-Forwarded-Uri").or_else(|| header("X-Original-URI")),
+        }
+    }
+
+    /// URL the user asked for, relative when the proxy only sent the path
+    pub fn original_url(&self) -> Option<String> {
+        let uri = self.uri.as_ref()?;
+        Some(match &self.host {
+            Some(host) => format!(
+                "{}://{}{}",
+                self.proto.as_deref().unwrap_or("http"),
+                host,
+                uri
+            ),
+            None => uri.to_string(),
+        })
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let uri = self.uri.as_ref()?;
+        let query = &uri[uri.find('?')? + 1..];
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.to_string())
+    }
+}
+
 impl AuthRequest {
     pub fn new(
         query: String,
         headers: HeaderMap,
         body: Option<HashMap<String, String>>,
         cookie: Option<String>,
+        remote: Option<SocketAddr>,
     ) -> Self {
-        let session = if let Some(ref c_string) = cookie {
-            SessionData::get_cookie(c_string)
-                .unwrap_or_else(|_| Some(SessionData::new(None).unwrap()))
-                .unwrap_or_else(|| SessionData::new(None).unwrap())
-        } else {
-            SessionData::new(None).unwrap()
-        };
+        let trusted = crate::CONFIG.general.trust_forwarded_headers;
+        let forwarded = ForwardedRequest::from_headers(&headers, trusted);
+        Self::with_forwarded(query, headers, body, cookie, remote, forwarded)
+    }
+
+    fn with_forwarded(
+        query: String,
+        headers: HeaderMap,
+        body: Option<HashMap<String, String>>,
+        cookie: Option<String>,
+        remote: Option<SocketAddr>,
+        forwarded: ForwardedRequest,
+    ) -> Self {
+        let client_ip = Self::client_ip(&headers, remote);
+        let host = headers
+            .get("Host")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+        let origin = headers
+            .get("Origin")
+            .or_else(|| headers.get("Referer"))
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+        let session = cookie
+            .as_ref()
+            .and_then(|c| {
+                let host = forwarded.host.as_deref().or(host.as_deref());
+                SessionData::get_cookie(c, host).unwrap_or_default()
+            })
+            .unwrap_or_else(|| SessionData::anonymous(client_ip));
         let res = AuthRequest(Arc::new(InnerAuthRequest {
             query: Self::parse_query(query),
+            client_ip,
+            host,
+            origin,
+            forwarded,
             authorization_header: Self::parse_headers(headers),
             urlbody: body,
             cookie,
@@ -44,6 +130,55 @@ impl AuthRequest {
         res
     }
 
+    /// Request a proxy asks about in its own name, as Envoy does, rather than
+    /// through the X-Forwarded-* headers
+    pub fn checking(
+        uri: String,
+        headers: HeaderMap,
+        cookie: Option<String>,
+        remote: Option<SocketAddr>,
+    ) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string())
+        };
+        let forwarded = ForwardedRequest {
+            proto: header("X-Forwarded-Proto"),
+            host: header("Host"),
+            uri: Some(uri),
+        };
+        Self::with_forwarded(String::default(), headers, None, cookie, remote, forwarded)
+    }
+
+    /// Host the browser sent the request to, as passed on by a trusted reverse proxy
+    pub fn host(&self) -> Option<&str> {
+        self.0.forwarded.host.as_deref().or(self.0.host.as_deref())
+    }
+
+    /// Whether the request was sent by one of our own pages, as a request from a
+    /// form on another site mustn't change anything
+    pub fn same_origin(&self) -> bool {
+        let origin = match self
+            .0
+            .origin
+            .as_ref()
+            .and_then(|o| o.parse::<url::Url>().ok())
+        {
+            Some(origin) => origin,
+            None => return false,
+        };
+        let authority = match (origin.host_str(), origin.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return false,
+        };
+        let issuer = crate::CONFIG.oauth.issuer.parse::<url::Url>().ok();
+        self.0.host.as_deref() == Some(authority.as_str())
+            || issuer.map(|i| i.origin()) == Some(origin.origin())
+    }
+
     fn parse_query(query: String) -> HashMap<String, String> {
         let mut result = HashMap::new();
         let res = form_urlencoded::parse(query.as_bytes());
@@ -53,6 +188,24 @@ impl AuthRequest {
         result
     }
 
+    /// Address of the client, taken from the proxy headers when they are trusted.
+    /// The last X-Forwarded-For entry is used as it was added by our own proxy,
+    /// anything before it is whatever the client sent.
+    fn client_ip(headers: &HeaderMap, remote: Option<SocketAddr>) -> Option<IpAddr> {
+        if crate::CONFIG.general.trust_forwarded_headers {
+            let forwarded = headers
+                .get("X-Forwarded-For")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.rsplit(',').next())
+                .or_else(|| headers.get("X-Real-IP").and_then(|h| h.to_str().ok()))
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        remote.map(|r| r.ip())
+    }
+
     fn parse_headers(headers: HeaderMap) -> Option<String> {
         let search_header = Some("Authorization".parse().unwrap());
         for h in headers.into_iter() {
@@ -68,11 +221,11 @@ impl WebRequest for AuthRequest {
     type Response = AuthResponse;
     type Error = OAuthError;
 
-    fn query(&mut self) -> Result<Cow<dyn QueryParameter + 'static>, OAuthError> {
+    fn query(&mut self) -> Result<Cow<'_, dyn QueryParameter + 'static>, OAuthError> {
         Ok(Cow::Borrowed(&self.0.query))
     }
 
-    fn urlbody(&mut self) -> Result<Cow<dyn QueryParameter + 'static>, OAuthError> {
+    fn urlbody(&mut self) -> Result<Cow<'_, dyn QueryParameter + 'static>, OAuthError> {
         self.0
             .urlbody
             .as_ref()
@@ -80,7 +233,7 @@ impl WebRequest for AuthRequest {
             .ok_or(OAuthError::PrimitiveError)
     }
 
-    fn authheader(&mut self) -> Result<Option<Cow<str>>, OAuthError> {
+    fn authheader(&mut self) -> Result<Option<Cow<'_, str>>, OAuthError> {
         Ok(self
             .0
             .authorization_header
@@ -88,3 +241,30 @@ impl WebRequest for AuthRequest {
             .map(|string| string.as_str().into()))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ignores_forwarded_host_unless_trusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", "auth.example.com".parse().unwrap());
+        headers.insert("X-Forwarded-Host", "app.example.com".parse().unwrap());
+        headers.insert("X-Forwarded-Uri", "/page".parse().unwrap());
+        assert_eq!(
+            ForwardedRequest::from_headers(&headers, true)
+                .host
+                .as_deref(),
+            Some("app.example.com")
+        );
+        let untrusted = ForwardedRequest::from_headers(&headers, false);
+        assert_eq!(untrusted.host, None);
+        assert_eq!(untrusted.uri.as_deref(), Some("/page"));
+
+        let req = AuthRequest::new(String::new(), headers.clone(), None, None, None);
+        assert_eq!(req.host(), Some("auth.example.com"));
+        let req = AuthRequest::checking("/page".into(), headers, None, None);
+        assert_eq!(req.host(), Some("auth.example.com"));
+    }
+}
@@ -4,9 +4,13 @@ use {super::*, oxide_auth::frontends::dev::*, parking_lot::Mutex, std::sync::Arc
 pub struct AuthResponse {
     pub status: u16,
     pub session: Option<Arc<Mutex<SessionData>>>,
+    /// Clears the session cookie, for sessions that were destroyed
+    pub expire_cookie: bool,
     pub content_type: Option<String>,
     pub www_authenticate: Option<String>,
     pub location: Option<String>,
+    /// Extra headers, such as the identity headers for forward auth
+    pub headers: Vec<(String, String)>,
     pub body: Option<String>,
 }
 
@@ -17,7 +21,11 @@ impl AuthResponse {
         if let Err(e) = sd.update() {
             warn!("Error updating session -> {}", e);
         }
-        self.session = Some(req.0.session.clone());
+        // Anonymous sessions that were never stored get no cookie, and the
+        // central cookie is only ever set on the auth domain
+        if sd.is_persisted() && handoff::is_auth_domain(req.host()) {
+            self.session = Some(req.0.session.clone());
+        }
         self
     }
 }
@@ -28,9 +36,11 @@ impl From<Grant> for AuthResponse {
         AuthResponse {
             status: 200,
             session: None,
+            expire_cookie: false,
             content_type: Some("application/json".into()),
             www_authenticate: None,
             location: None,
+            headers: vec![],
             body: Some(serde_json::to_string(&user_grant).unwrap_or_default()),
         }
     }
@@ -41,9 +51,11 @@ impl Default for AuthResponse {
         AuthResponse {
             status: 200,
             session: None,
+            expire_cookie: false,
             content_type: None,
             www_authenticate: None,
             location: None,
+            headers: vec![],
             body: None,
         }
     }
@@ -61,6 +73,9 @@ impl warp::Reply for AuthResponse {
         if let Some(auth) = self.www_authenticate {
             builder.header("WWW-Authenticate", auth);
         }
+        for (name, value) in self.headers {
+            builder.header(name.as_str(), value);
+        }
         if let Some(content) = self.content_type {
             builder.header("Content-Type", content);
         }
@@ -74,6 +89,9 @@ impl warp::Reply for AuthResponse {
                 session.lock().cookie_string().unwrap_or_default()
             );
         }
+        if self.expire_cookie {
+            builder.header("Set-Cookie", SessionData::removal_cookie_string());
+        }
         builder
             .body(match self.body {
                 Some(s) => hyper::Body::from(s),
@@ -0,0 +1,100 @@
+use {
+    super::jwt,
+    chrono::prelude::*,
+    serde::{Deserialize, Serialize},
+    url::Url,
+};
+
+/// Seconds a user has to log in before the return URL is forgotten
+const RETURN_TO_TTL: i64 = 3600;
+
+/// Page to send the user back to once logged in, signed into the OAuth state
+#[derive(Debug, Serialize, Deserialize)]
+struct ReturnTo {
+    rd: String,
+    exp: i64,
+}
+
+/// Whether the user may be sent to the URL. It is resolved the way browsers
+/// do against the issuer URL, so relative paths that turn out to name another
+/// host are caught, and the host has to be the issuer's or an allowed one.
+pub fn allowed(url: &str) -> bool {
+    let issuer = match Url::parse(&crate::CONFIG.oauth.issuer) {
+        Ok(issuer) => issuer,
+        Err(_) => return false,
+    };
+    let host = match issuer.join(url) {
+        Ok(ref u) if u.scheme() == "http" || u.scheme() == "https" => {
+            u.host_str().map(|h| h.to_lowercase())
+        }
+        _ => None,
+    };
+    host.map(|host| {
+        issuer.host_str().map(|h| h.eq_ignore_ascii_case(&host)) == Some(true)
+            || crate::CONFIG
+                .oauth
+                .return_to_domains
+                .iter()
+                .any(|d| domain_matches(&host, d))
+    })
+    .unwrap_or(false)
+}
+
+/// `example.com` only matches itself, `.example.com` also matches its subdomains
+pub fn domain_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.to_lowercase();
+    if domain.starts_with('.') {
+        host == &domain[1..] || host.ends_with(&domain)
+    } else {
+        host == domain
+    }
+}
+
+/// Signs the URL into a value to pass as OAuth state, `None` if it isn't allowed
+pub fn state(url: &str) -> Option<String> {
+    if !allowed(url) {
+        warn!("Not returning users to {}, it isn't an allowed domain", url);
+        return None;
+    }
+    let claims = ReturnTo {
+        rd: url.to_string(),
+        exp: Utc::now().timestamp() + RETURN_TO_TTL,
+    };
+    jwt::encode(&claims)
+        .map_err(|e| warn!("Failed to sign the return URL -> {}", e))
+        .ok()
+}
+
+/// The URL signed into the state, if it's genuine, current and still allowed
+pub fn from_state(state: &str) -> Option<String> {
+    let claims: ReturnTo = jwt::decode(state).ok()?;
+    if claims.exp < Utc::now().timestamp() || !allowed(&claims.rd) {
+        return None;
+    }
+    Some(claims.rd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_return_urls() {
+        assert!(allowed("/private.html?x=1"));
+        assert!(!allowed("//evil.example.com/"));
+        assert!(!allowed("/\\evil.example.com/"));
+        assert!(!allowed("/\t/evil.example.com/"));
+        assert!(!allowed(" //evil.example.com/"));
+        assert!(allowed("http://localhost:3030/private.html"));
+        assert!(!allowed("http://evil.example.com/"));
+        assert!(!allowed("javascript:alert(1)"));
+        assert!(domain_matches("app.example.com", ".example.com"));
+        assert!(domain_matches("example.com", ".Example.com"));
+        assert!(!domain_matches("badexample.com", ".example.com"));
+        assert!(!domain_matches("app.example.com", "example.com"));
+
+        let state = state("/private.html").unwrap();
+        assert_eq!(from_state(&state), Some("/private.html".into()));
+        assert_eq!(from_state("garbage"), None);
+    }
+}
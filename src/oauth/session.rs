@@ -1,14 +1,109 @@
 use {
-    crate::{data::*, login::User, *},
+    super::{handoff::domain, Tokens, UserGrant},
+    crate::{
+        args::{CookieOptions, SameSite, SessionOptions},
+        data::*,
+        login::User,
+        *,
+    },
     serde::{Deserialize, Serialize},
-    std::time::SystemTime,
+    sha2::{Digest, Sha256},
+    std::{net::IpAddr, time::SystemTime},
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SessionData {
     pub id: String,
     pub user: Option<User>,
+    /// User that passed the password check but still owes a second factor
+    #[serde(default)]
+    pub pending: Option<User>,
+    /// Whether the user passed a second factor or logged in with a passkey
+    #[serde(default)]
+    pub mfa: bool,
+    /// Clients the user signed into with this session
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// Milliseconds since the epoch at which the session was created
+    #[serde(default)]
+    pub created: u128,
+    /// Milliseconds since the epoch of the last request using this session
     pub ts: u128,
+    /// Address of the client that started the session
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    /// Place among the sessions without a user kept for the client address
+    #[serde(default)]
+    slot: Option<usize>,
+    /// Whether the session has been written to the database, anonymous
+    /// sessions only are once there is state worth keeping
+    #[serde(skip)]
+    persisted: bool,
+}
+
+/// Session cookie of a protected domain standing in for the central session,
+/// a cookie leaking from one domain is then of no use on the others
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DomainSession {
+    pub id: String,
+    pub session_id: String,
+    pub domain: String,
+}
+
+lazy_static! {
+    static ref COOKIE_NAME: String = prefixed_cookie_name(&crate::CONFIG.cookie);
+}
+
+/// Name of the session cookie
+pub fn cookie_name() -> &'static str {
+    &COOKIE_NAME
+}
+
+/// Adds the strictest prefix the cookie settings allow, `__Host-` requires a
+/// secure host-only cookie for the whole site and `__Secure-` just a secure one
+fn prefixed_cookie_name(config: &CookieOptions) -> String {
+    if config.insecure || config.name.starts_with("__") {
+        config.name.to_string()
+    } else if config.domain.is_none() && config.path == "/" {
+        format!("__Host-{}", config.name)
+    } else {
+        format!("__Secure-{}", config.name)
+    }
+}
+
+/// Cookies of protected domains are host-only, --cookie-domain is for the auth domain
+fn session_cookie(value: String, max_age: chrono::Duration, host_only: bool) -> String {
+    let config = &crate::CONFIG.cookie;
+    let mut builder = cookie::Cookie::build(cookie_name(), value)
+        .http_only(true)
+        .path(config.path.to_string())
+        .secure(!config.insecure)
+        .max_age(max_age);
+    if let (Some(domain), false) = (&config.domain, host_only) {
+        builder = builder.domain(domain.to_string());
+    }
+    builder = match config.same_site {
+        SameSite::Strict => builder.same_site(cookie::SameSite::Strict),
+        SameSite::Lax => builder.same_site(cookie::SameSite::Lax),
+        SameSite::None => builder,
+    };
+    let mut c = builder.finish().to_string();
+    // The cookie crate leaves out SameSite=None, which browsers now treat as Lax
+    if config.same_site == SameSite::None {
+        c.push_str("; SameSite=None");
+    }
+    c
+}
+
+fn slot_key(ip: IpAddr, slot: usize) -> String {
+    format!("{}/{}", ip, slot)
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
 }
 
 impl Default for SessionData {
@@ -16,10 +111,14 @@ impl Default for SessionData {
         SessionData {
             id: Self::gen_id(),
             user: Default::default(),
-            ts: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis(),
+            pending: Default::default(),
+            mfa: false,
+            clients: Default::default(),
+            created: now_millis(),
+            ts: now_millis(),
+            ip: None,
+            slot: None,
+            persisted: false,
         }
     }
 }
@@ -27,6 +126,8 @@ impl Default for SessionData {
 impl Persistable for SessionData {
     type ID = String;
 
+    const INDEXES: &'static [&'static str] = &["anonymous", "sid"];
+
     fn tree_name() -> &'static str {
         "sessions"
     }
@@ -34,6 +135,20 @@ impl Persistable for SessionData {
     fn id(&self) -> Self::ID {
         self.id.to_string()
     }
+
+    fn index_values(&self, index: &str) -> Vec<String> {
+        match (index, self.ip, self.slot, &self.user) {
+            ("anonymous", Some(ip), Some(slot), None) => vec![slot_key(ip, slot)],
+            ("sid", _, _, Some(_)) => vec![self.sid()],
+            _ => vec![],
+        }
+    }
+
+    /// Only the fields fixed for the life of the session id are signed, so
+    /// the cookie stays valid as the session is used
+    fn signed_data(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(&self.id, self.created))?)
+    }
 }
 
 impl SessionData {
@@ -41,7 +156,127 @@ impl SessionData {
         self.user.is_some()
     }
 
-    pub fn get_cookie(c: &'_ str) -> Result<Option<Self>> {
+    /// Session ID handed to clients in ID and logout tokens. It is derived from
+    /// the session id, which is also the cookie value, without giving it away.
+    pub fn sid(&self) -> String {
+        base64::encode_config(
+            &Sha256::digest(self.id.as_bytes())[..16],
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// The logged in session clients know by `sid`
+    pub fn by_sid(sid: &str) -> Result<Option<Self>> {
+        Self::find_by("sid", sid)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(&crate::CONFIG.session, now_millis())
+    }
+
+    /// Whether the session outlived its absolute lifetime or sat idle for too long
+    fn is_expired_at(&self, config: &SessionOptions, now: u128) -> bool {
+        now >= self.created + u128::from(config.lifetime) * 1000
+            || now >= self.ts + u128::from(config.idle_timeout) * 1000
+    }
+
+    /// Moves the session to a fresh id and signature and drops the old record.
+    /// Done whenever the authentication state changes, so a cookie planted or
+    /// seen before logging in is worthless afterwards. The tokens, grant and
+    /// domain cookies of the session move along with it.
+    pub fn rotate(&mut self) -> Result<()> {
+        let old = self.id.clone();
+        self.delete()?;
+        self.id = Self::gen_id();
+        self.created = now_millis();
+        self.store()?;
+        if let Some(mut tokens) = Tokens::get(old.to_string())? {
+            tokens.delete()?;
+            tokens.owner_id = self.id.to_string();
+            tokens.save()?;
+        }
+        if let Some(mut grant) = UserGrant::get(old.to_string())? {
+            grant.delete()?;
+            grant.owner_id = self.id.to_string();
+            grant.save()?;
+        }
+        for mut ds in DomainSession::find_all(|ds| ds.session_id == old)? {
+            ds.session_id = self.id.to_string();
+            ds.save()?;
+        }
+        Ok(())
+    }
+
+    /// Saves the session. Sessions without a user take one of the slots of
+    /// their client address and are refused once all of them are in use, logged
+    /// in sessions are never limited or evicted.
+    pub fn store(&mut self) -> Result<()> {
+        if !self.keep()? {
+            return Err(Error::Authentication(format!(
+                "Too many sessions for {}",
+                self.ip.map(|ip| ip.to_string()).unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Saves the session like `store`, but returns false instead of failing
+    /// when the client address has no free slot left, so handlers can answer
+    /// with a 429
+    pub fn keep(&mut self) -> Result<bool> {
+        let max = crate::CONFIG.session.max_per_ip;
+        if let (false, Some(ip), true, None) = (self.persisted, self.ip, max > 0, &self.user) {
+            match Self::free_slot(ip, max)? {
+                Some(slot) => self.slot = Some(slot),
+                None => {
+                    warn!("Client {} reached the session limit", ip);
+                    return Ok(false);
+                }
+            }
+        }
+        self.save()?;
+        self.persisted = true;
+        Ok(true)
+    }
+
+    /// First slot of the address not held by a live session
+    fn free_slot(ip: IpAddr, max: usize) -> Result<Option<usize>> {
+        for slot in 0..max {
+            match Self::find_by("anonymous", &slot_key(ip, slot))? {
+                Some(sd) if !sd.is_expired() => continue,
+                _ => return Ok(Some(slot)),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn is_persisted(&self) -> bool {
+        self.persisted
+    }
+
+    /// Destroys the session, revoking the tokens issued under it if asked to
+    pub fn end(&self, revoke_tokens: bool) -> Result<()> {
+        self.delete()?;
+        DomainSession::delete_all(|ds| ds.session_id == self.id)?;
+        if revoke_tokens {
+            if let Some(tokens) = Tokens::get(self.id.to_string())? {
+                tokens.delete()?;
+            }
+            if let Some(grant) = UserGrant::get(self.id.to_string())? {
+                grant.delete()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes every expired session, returning how many were removed
+    pub fn purge_expired() -> Result<u64> {
+        let now = now_millis();
+        Self::delete_all(|sd| sd.is_expired_at(&crate::CONFIG.session, now))
+    }
+
+    /// Session of a cookie, cookies of protected domains only count on their domain
+    pub fn get_cookie(c: &'_ str, host: Option<&str>) -> Result<Option<Self>> {
         debug!("Raw cookie: {}", c);
         let c = percent_encoding::percent_decode_str(c)
             .decode_utf8()
@@ -53,12 +288,37 @@ impl SessionData {
         }
 
         let (id, signature) = (parts[0], parts[1]);
-        let result = Self::get(id.to_string())?;
-        if let Some(ref r) = result {
+        let mut result = match Self::get(id.to_string())? {
+            Some(sd) => {
+                if let Err(e) = sd.verify(signature) {
+                    warn!("Cookie validation failed for {:?}", parts);
+                    return Err(e);
+                }
+                Some(sd)
+            }
+            // Cookies of protected domains lead to the central session
+            None => match DomainSession::get(id.to_string())? {
+                Some(ds) => {
+                    if let Err(e) = ds.verify(signature) {
+                        warn!("Domain cookie validation failed for {:?}", parts);
+                        return Err(e);
+                    }
+                    if host.map(domain).as_deref() != Some(ds.domain.as_str()) {
+                        warn!("Cookie of {} presented to {:?}", ds.domain, host);
+                        return Ok(None);
+                    }
+                    Self::get(ds.session_id)?
+                }
+                None => None,
+            },
+        };
+        if let Some(ref mut r) = result {
+            r.persisted = true;
             debug!("Using existing cookie: {:?}", r);
-            if let Err(e) = r.verify(signature) {
-                warn!("Cookie validation failed for {:?}", parts);
-                return Err(e);
+            if r.is_expired() {
+                debug!("Session {} has expired", r.id);
+                r.delete()?;
+                return Ok(None);
             }
         } else {
             debug!("No session found matching cookie!");
@@ -67,29 +327,90 @@ impl SessionData {
     }
 
     pub fn new(user: Option<User>) -> Result<Self> {
-        let mut res = SessionData::default();
-        res.user = user;
-        res.save()?;
+        let mut res = SessionData {
+            user,
+            ..Default::default()
+        };
+        res.store()?;
         Ok(res)
     }
 
+    /// Session for a client without a cookie, it isn't stored until it's needed
+    pub fn anonymous(ip: Option<IpAddr>) -> Self {
+        SessionData {
+            ip,
+            ..Default::default()
+        }
+    }
+
+    /// Marks the session as used, anonymous sessions are left unstored
     pub fn update(&mut self) -> Result<()> {
-        self.ts = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
+        if !self.persisted {
+            return Ok(());
+        }
+        self.ts = now_millis();
         self.save()?;
         Ok(())
     }
 
     pub fn cookie_string(&self) -> Result<String> {
         let val = self.signed_token()?;
-        let c = cookie::Cookie::build("SID", val)
-            .http_only(true)
-            .path("/")
-            .permanent()
-            .finish();
-        Ok(c.to_string())
+        Ok(session_cookie(val, self.cookie_max_age(), false))
+    }
+
+    /// By default the cookie goes away with the absolute lifetime, idle sessions are rejected server side
+    fn cookie_max_age(&self) -> chrono::Duration {
+        let max_age = match crate::CONFIG.cookie.max_age {
+            Some(max_age) => max_age as i64,
+            None => {
+                let lifetime = u128::from(crate::CONFIG.session.lifetime) * 1000;
+                ((self.created + lifetime).saturating_sub(now_millis()) / 1000) as i64
+            }
+        };
+        chrono::Duration::seconds(max_age)
+    }
+
+    /// Cookie telling the browser to drop the session cookie
+    pub fn removal_cookie_string() -> String {
+        session_cookie(String::new(), chrono::Duration::zero(), false)
+    }
+}
+
+impl Persistable for DomainSession {
+    type ID = String;
+
+    fn tree_name() -> &'static str {
+        "domain_sessions"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.id.to_string()
+    }
+}
+
+impl DomainSession {
+    pub fn create(sd: &SessionData, domain: &str) -> Result<Self> {
+        let ds = DomainSession {
+            id: Self::gen_id(),
+            session_id: sd.id.to_string(),
+            domain: domain.to_lowercase(),
+        };
+        ds.save()?;
+        Ok(ds)
+    }
+
+    /// Host-only cookie for the domain, living as long as the central session
+    pub fn cookie_string(&self, sd: &SessionData) -> Result<String> {
+        let val = self.signed_token()?;
+        Ok(session_cookie(val, sd.cookie_max_age(), true))
+    }
+
+    /// Deletes the cookies of sessions that ended or moved to a new id
+    pub fn purge_orphaned() -> Result<u64> {
+        Self::delete_all(|ds| match SessionData::get(ds.session_id.to_string()) {
+            Ok(sd) => sd.is_none(),
+            Err(_) => false,
+        })
     }
 }
 
@@ -105,9 +426,146 @@ mod test {
         println!("Signature: {}", signature);
         session.verify(&signature)?;
         let val = format!("{}.{}", session.id, session.sign()?);
-        println!("{:?}", SessionData::get_cookie(&val));
+        println!("{:?}", SessionData::get_cookie(&val, None));
         let f = SessionData::delete_all(|sd| sd.id == session.id);
         println!("Cleaned session: {:?}", f);
         Ok(())
     }
+
+    #[test]
+    fn rotates_session_ids() -> Result<()> {
+        let mut session = SessionData::new(None)?;
+        let old = session.signed_token()?;
+        session.rotate()?;
+        assert!(SessionData::get_cookie(&old, None)?.is_none());
+        assert!(SessionData::get_cookie(&session.signed_token()?, None)?.is_some());
+        session.delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn rotates_dependent_records() -> Result<()> {
+        let mut session = SessionData::new(Some(User::default()))?;
+        let mut tokens = Tokens::from(&session.id)?;
+        let until = chrono::Utc::now() + chrono::Duration::hours(1);
+        tokens.tokens.push((
+            hash_token("rotate-access")?,
+            hash_token("rotate-refresh")?,
+            until,
+        ));
+        tokens.save()?;
+        let ds = DomainSession::create(&session, "app.example.com")?;
+        let old = session.id.clone();
+        session.rotate()?;
+        assert!(Tokens::get(old.to_string())?.is_none());
+        let tokens = Tokens::access("rotate-access")?.unwrap();
+        assert_eq!(tokens.owner_id, session.id);
+        assert_eq!(
+            Tokens::refresh("rotate-refresh")?.unwrap().owner_id,
+            session.id
+        );
+        assert_eq!(
+            DomainSession::get(ds.id.to_string())?.unwrap().session_id,
+            session.id
+        );
+        session.end(true)?;
+        assert!(DomainSession::get(ds.id)?.is_none());
+        assert!(Tokens::access("rotate-access")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn finds_sessions_by_sid() -> Result<()> {
+        let session = SessionData::new(Some(User::default()))?;
+        assert!(!session.sid().contains(&session.id));
+        assert_eq!(SessionData::by_sid(&session.sid())?.unwrap().id, session.id);
+        session.delete()?;
+        assert!(SessionData::by_sid(&session.sid())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_cookies_valid_when_used() -> Result<()> {
+        let mut session = SessionData::new(None)?;
+        let cookie = session.signed_token()?;
+        session.ts = 0;
+        session.update()?;
+        assert!(SessionData::get_cookie(&cookie, None)?.is_some());
+        session.delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_across_key_rotation() -> Result<()> {
+        let session = SessionData::new(None)?;
+        let old = session.sign()?;
+        let legacy = old.split_once(':').unwrap().1.to_string();
+        let id = keyring::rotate(keyring::Ring::Signing, &crate::CONFIG.keys)?.unwrap();
+        session.verify(&old)?;
+        session.verify(&legacy)?;
+        let new = session.sign()?;
+        assert!(new.starts_with(&format!("{}:", id)));
+        session.verify(&new)?;
+        assert!(session.verify(&format!("unknown:{}", legacy)).is_err());
+        session.delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn caps_anonymous_sessions_per_ip() -> Result<()> {
+        let ip: Option<IpAddr> = Some("192.0.2.36".parse().unwrap());
+        let max = crate::CONFIG.session.max_per_ip;
+        let mut logged_in = SessionData::anonymous(ip);
+        logged_in.user = Some(User::default());
+        logged_in.store()?;
+        let mut first = SessionData::anonymous(ip);
+        first.store()?;
+        for _ in 1..max {
+            SessionData::anonymous(ip).store()?;
+        }
+        assert!(SessionData::anonymous(ip).store().is_err());
+        assert!(SessionData::get(logged_in.id.to_string())?.is_some());
+
+        // Slots are given back when their session goes away
+        first.delete()?;
+        SessionData::anonymous(ip).store()?;
+        logged_in.store()?;
+        assert_eq!(SessionData::find_all(|sd| sd.ip == ip)?.len(), max + 1);
+        SessionData::delete_all(|sd| sd.ip == ip)?;
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_cookie_names() {
+        let mut config = CookieOptions {
+            name: "SID".into(),
+            path: "/".into(),
+            ..Default::default()
+        };
+        assert_eq!(prefixed_cookie_name(&config), "__Host-SID");
+        config.domain = Some("example.com".into());
+        assert_eq!(prefixed_cookie_name(&config), "__Secure-SID");
+        config.insecure = true;
+        assert_eq!(prefixed_cookie_name(&config), "SID");
+    }
+
+    #[test]
+    fn expires_sessions() {
+        let config = SessionOptions {
+            lifetime: 100,
+            idle_timeout: 10,
+            sweep_interval: 300,
+            max_per_ip: 0,
+        };
+        let mut session = SessionData {
+            created: 1_000_000,
+            ts: 1_000_000,
+            ..Default::default()
+        };
+        assert!(!session.is_expired_at(&config, 1_009_999));
+        assert!(session.is_expired_at(&config, 1_010_000));
+        session.ts = 1_095_000;
+        assert!(!session.is_expired_at(&config, 1_099_999));
+        assert!(session.is_expired_at(&config, 1_100_000));
+    }
 }
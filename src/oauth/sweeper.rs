@@ -0,0 +1,36 @@
+use {
+    super::*,
+    crate::{args::SessionOptions, data::keyring, Result},
+    std::{thread, time::Duration},
+};
+
+/// Starts the background thread purging expired sessions, codes, tokens and
+/// login state, which also rotates the signing keys when they are due
+pub fn start(config: &SessionOptions) {
+    let interval = Duration::from_secs(config.sweep_interval.max(1));
+    thread::Builder::new()
+        .name("sweeper".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = sweep() {
+                warn!("Failed to purge expired sessions -> {}", e);
+            }
+            if let Err(e) = keyring::rotate_due(&crate::CONFIG.keys) {
+                warn!("Failed to rotate signing keys -> {}", e);
+            }
+        })
+        .expect("Failed to start session sweeper");
+}
+
+pub fn sweep() -> Result<()> {
+    let sessions = SessionData::purge_expired()? + DomainSession::purge_orphaned()?;
+    let tokens = Tokens::purge_expired()? + handoff::Handoff::purge_expired()?;
+    let logins = crate::login::purge_expired()?;
+    if sessions > 0 || tokens > 0 || logins > 0 {
+        info!(
+            "Purged {} expired sessions, {} expired codes or tokens and {} stale login records",
+            sessions, tokens, logins
+        );
+    }
+    Ok(())
+}
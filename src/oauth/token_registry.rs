@@ -1,6 +1,6 @@
 use {
-    super::UserGrant,
-    crate::data::Persistable,
+    super::{SessionData, UserGrant},
+    crate::data::{hash_token, Persistable},
     chrono::prelude::*,
     oxide_auth::{
         endpoint::*,
@@ -23,8 +23,8 @@ impl Issuer for TokenRegistry {
                 .unwrap(),
         };
         t.tokens.push((
-            new_token.token.clone(),
-            new_token.refresh.clone(),
+            hash_token(&new_token.token)?,
+            hash_token(&new_token.refresh)?,
             new_token.until,
         ));
         t.save()?;
@@ -43,15 +43,17 @@ impl Issuer for TokenRegistry {
     fn refresh(&mut self, refresh: &str, grant: Grant) -> Result<RefreshedToken, ()> {
         let mut t = Tokens::from(&grant.owner_id)?;
         let mut refreshed = Err(());
+        let hashed = hash_token(refresh)?;
         for t in t.tokens.iter_mut() {
-            if t.1 == refresh {
-                t.0 = Tokens::gen_id();
+            if t.1 == hashed {
+                let token = Tokens::gen_id();
+                t.0 = hash_token(&token)?;
                 t.2 = Utc::now()
                     .checked_add_signed(chrono::Duration::seconds(3600))
                     .unwrap();
                 refreshed = Ok(RefreshedToken {
-                    token: t.0.to_string(),
-                    refresh: Some(t.1.to_string()),
+                    token,
+                    refresh: Some(refresh.to_string()),
                     until: t.2,
                 });
             }
@@ -71,6 +73,7 @@ impl Issuer for TokenRegistry {
     }
 }
 
+/// Codes and tokens issued under a session, kept as their `hash_token`
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Tokens {
     pub owner_id: String,
@@ -81,6 +84,8 @@ pub struct Tokens {
 impl Persistable for Tokens {
     type ID = String;
 
+    const INDEXES: &'static [&'static str] = &["codes", "access", "refresh"];
+
     fn tree_name() -> &'static str {
         "tokens"
     }
@@ -88,6 +93,15 @@ impl Persistable for Tokens {
     fn id(&self) -> Self::ID {
         self.owner_id.to_string()
     }
+
+    fn index_values(&self, index: &str) -> Vec<String> {
+        match index {
+            "codes" => self.authorizations.iter().map(|a| a.0.clone()).collect(),
+            "access" => self.tokens.iter().map(|t| t.0.clone()).collect(),
+            "refresh" => self.tokens.iter().map(|t| t.1.clone()).collect(),
+            _ => vec![],
+        }
+    }
 }
 
 impl Tokens {
@@ -109,48 +123,137 @@ impl Tokens {
         self
     }
 
+    /// Drops expired authorization codes everywhere, and expired access tokens
+    /// once the session they were issued for is gone and can't refresh them.
+    /// Records left empty are deleted along with their grant.
+    pub fn purge_expired() -> crate::Result<u64> {
+        let now = Utc::now();
+        let mut purged = 0;
+        for mut t in Tokens::find_all(|_| true)? {
+            let (codes, tokens) = (t.authorizations.len(), t.tokens.len());
+            t.authorizations.retain(|a| now < a.1);
+            if SessionData::get(t.owner_id.to_string())?.is_none() {
+                t.tokens.retain(|t| now < t.2);
+            }
+            purged += (codes - t.authorizations.len() + tokens - t.tokens.len()) as u64;
+            if t.authorizations.is_empty() && t.tokens.is_empty() {
+                t.delete()?;
+                if let Some(grant) = UserGrant::get(t.owner_id.to_string())? {
+                    grant.delete()?;
+                }
+            } else if codes != t.authorizations.len() || tokens != t.tokens.len() {
+                t.save()?;
+            }
+        }
+        Ok(purged)
+    }
+
     pub fn grant(&self) -> crate::Result<Option<Grant>> {
         let ug = UserGrant::get(self.id())?;
         Ok(ug.map(|u| u.into()))
     }
 
-    pub fn authorize(token: &str) -> crate::Result<Option<Tokens>> {
-        let t = Tokens::find(|t| {
-            for t in t.authorizations.iter() {
-                if t.0 == token {
-                    return true;
-                }
+    /// Replaces the codes and tokens saved in the clear by their hashes
+    pub fn hash_stored() -> crate::Result<()> {
+        for mut t in Tokens::find_all(|_| true)? {
+            for a in t.authorizations.iter_mut() {
+                a.0 = hash_token(&a.0)?;
+            }
+            for token in t.tokens.iter_mut() {
+                token.0 = hash_token(&token.0)?;
+                token.1 = hash_token(&token.1)?;
             }
-            false
-        });
+            t.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn authorize(token: &str) -> crate::Result<Option<Tokens>> {
+        let t = Tokens::find_by("codes", &hash_token(token)?);
         t.map(|t| {
-            t.map(|t| {
+            t.inspect(|t| {
                 t.delete().unwrap_or_default();
-                t
             })
         })
     }
 
     pub fn access(token: &str) -> crate::Result<Option<Tokens>> {
         let now = Utc::now();
-        Tokens::find(|t| {
-            for t in t.tokens.iter() {
-                if t.0 == token && now < t.2 {
-                    return true;
-                }
-            }
-            false
-        })
+        let hashed = hash_token(token)?;
+        Ok(Tokens::find_by("access", &hashed)?
+            .filter(|t| t.tokens.iter().any(|t| t.0 == hashed && now < t.2)))
     }
 
     pub fn refresh(token: &str) -> crate::Result<Option<Tokens>> {
-        Tokens::find(|t| {
-            for t in t.tokens.iter() {
-                if t.1 == token {
-                    return true;
-                }
-            }
-            false
+        Tokens::find_by("refresh", &hash_token(token)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(owner: usize) -> crate::Result<Tokens> {
+        let until = Utc::now() + chrono::Duration::hours(1);
+        let hash = |kind| hash_token(&format!("{}-{}", kind, owner));
+        Ok(Tokens {
+            owner_id: format!("bench-{}", owner),
+            authorizations: vec![(hash("code")?, until)],
+            tokens: vec![(hash("access")?, hash("refresh")?, until)],
         })
     }
+
+    #[test]
+    fn finds_tokens_by_index() -> crate::Result<()> {
+        let mut t = tokens(0)?;
+        t.owner_id = "indexed".into();
+        t.save()?;
+        assert_eq!(Tokens::access("access-0")?.unwrap().owner_id, "indexed");
+        assert_eq!(Tokens::refresh("refresh-0")?.unwrap().owner_id, "indexed");
+
+        t.tokens[0].0 = hash_token("access-1")?;
+        t.save()?;
+        assert!(Tokens::access("access-0")?.is_none());
+        assert!(Tokens::access("access-1")?.is_some());
+
+        assert!(Tokens::authorize("code-0")?.is_some());
+        assert!(Tokens::access("access-1")?.is_none());
+        assert!(Tokens::authorize("code-0")?.is_none());
+        Ok(())
+    }
+
+    /// Run with `cargo +nightly bench --features bench`
+    #[cfg(feature = "bench")]
+    mod bench {
+        use {super::*, ::test::Bencher, std::sync::Once};
+
+        const RECORDS: usize = 10_000;
+        const WANTED: &str = "access-5000";
+
+        fn seed() {
+            static SEED: Once = Once::new();
+            SEED.call_once(|| {
+                for owner in 0..RECORDS {
+                    tokens(owner).unwrap().save().unwrap();
+                }
+            });
+        }
+
+        #[bench]
+        fn lookup_by_scan(b: &mut Bencher) {
+            seed();
+            let token = hash_token(WANTED).unwrap();
+            b.iter(|| {
+                Tokens::find(|t| t.tokens.iter().any(|t| t.0 == token))
+                    .unwrap()
+                    .unwrap()
+            });
+        }
+
+        #[bench]
+        fn lookup_by_index(b: &mut Bencher) {
+            seed();
+            b.iter(|| Tokens::access(WANTED).unwrap().unwrap());
+        }
+    }
 }
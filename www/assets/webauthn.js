@@ -0,0 +1,62 @@
+// Helpers for the WebAuthn ceremonies, the server speaks base64url
+function b64urlToBuffer(value) {
+  var b64 = value.replace(/-/g, '+').replace(/_/g, '/');
+  var raw = atob(b64 + '==='.slice((b64.length + 3) % 4));
+  return Uint8Array.from(raw, function(c) { return c.charCodeAt(0); }).buffer;
+}
+
+function bufferToB64url(buffer) {
+  var raw = String.fromCharCode.apply(null, new Uint8Array(buffer));
+  return btoa(raw).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+}
+
+function postJSON(url, body) {
+  return fetch(url, {
+    method: 'POST',
+    credentials: 'same-origin',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify(body)
+  }).then(function(resp) {
+    if (!resp.ok) { throw new Error('Request failed with ' + resp.status); }
+  });
+}
+
+function registerPasskey() {
+  return fetch('/webauthn/register', { credentials: 'same-origin' })
+    .then(function(resp) { return resp.json(); })
+    .then(function(options) {
+      options.challenge = b64urlToBuffer(options.challenge);
+      options.user.id = b64urlToBuffer(options.user.id);
+      options.excludeCredentials.forEach(function(c) { c.id = b64urlToBuffer(c.id); });
+      return navigator.credentials.create({ publicKey: options });
+    })
+    .then(function(credential) {
+      return postJSON('/webauthn/register', {
+        id: credential.id,
+        response: {
+          clientDataJSON: bufferToB64url(credential.response.clientDataJSON),
+          attestationObject: bufferToB64url(credential.response.attestationObject)
+        }
+      });
+    });
+}
+
+function loginWithPasskey() {
+  return fetch('/webauthn/login', { credentials: 'same-origin' })
+    .then(function(resp) { return resp.json(); })
+    .then(function(options) {
+      options.challenge = b64urlToBuffer(options.challenge);
+      options.allowCredentials.forEach(function(c) { c.id = b64urlToBuffer(c.id); });
+      return navigator.credentials.get({ publicKey: options });
+    })
+    .then(function(credential) {
+      return postJSON('/webauthn/login', {
+        id: credential.id,
+        response: {
+          clientDataJSON: bufferToB64url(credential.response.clientDataJSON),
+          authenticatorData: bufferToB64url(credential.response.authenticatorData),
+          signature: bufferToB64url(credential.response.signature)
+        }
+      });
+    });
+}